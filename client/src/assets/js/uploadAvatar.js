@@ -0,0 +1,21 @@
+// Upload an image to be used as a user or group picture. The callback is
+// called with the URL of the resized picture, or with null and an error
+// message.
+export default function uploadAvatar(file, callback) {
+  const form = new FormData();
+  form.append("file", file);
+
+  const req = new XMLHttpRequest();
+
+  req.onload = () => {
+    if (req.response && req.response.type === "success") {
+      callback(req.response.picture, null);
+    } else {
+      callback(null, req.response ? req.response.message : "file_too_large");
+    }
+  };
+
+  req.responseType = "json";
+  req.open("POST", "/api/avatar");
+  req.send(form);
+}
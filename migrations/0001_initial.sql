@@ -1,3 +1,7 @@
+-- The initial schema. This was previously applied on every startup from
+-- initialize.sql so it uses IF NOT EXISTS to allow databases created that way
+-- to be brought under version control.
+
 CREATE TABLE IF NOT EXISTS Usr (
     user_id SERIAL NOT NULL,
     name TEXT NOT NULL,
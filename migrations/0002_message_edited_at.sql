@@ -0,0 +1,4 @@
+-- The time that the message content was last edited. NULL if the message has
+-- never been edited.
+ALTER TABLE Message
+    ADD COLUMN edited_at TIMESTAMPTZ;
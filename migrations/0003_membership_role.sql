@@ -0,0 +1,30 @@
+-- The role of the user within the group and the permissions that the user has.
+-- The role values and permission bits are defined in src/database/membership.rs
+--
+-- Role
+--   0 = owner
+--   1 = admin
+--   2 = member
+ALTER TABLE Membership
+    ADD COLUMN role SMALLINT NOT NULL DEFAULT 2,
+    ADD COLUMN permissions INTEGER NOT NULL DEFAULT 1;
+
+-- Before roles existed, every member of a group could do anything to the group.
+-- A group can only have one owner so the member with the lowest ID becomes the
+-- owner (63 = all permissions) and everyone else becomes an admin
+-- (15 = create invite, manage channels, manage messages, manage group) so that
+-- nobody loses access to their groups.
+UPDATE Membership
+SET role = 1, permissions = 15;
+
+UPDATE Membership
+SET role = 0, permissions = 63
+WHERE (group_id, user_id) IN (
+    SELECT group_id, MIN(user_id)
+    FROM Membership
+    GROUP BY group_id
+);
+
+ALTER TABLE Membership
+    ALTER COLUMN role DROP DEFAULT,
+    ALTER COLUMN permissions DROP DEFAULT;
@@ -0,0 +1,28 @@
+CREATE TABLE Ban (
+    user_id INTEGER NOT NULL,
+    group_id INTEGER NOT NULL,
+    banned_by INTEGER,
+    creation_time TIMESTAMPTZ NOT NULL,
+
+    PRIMARY KEY (group_id, user_id),
+
+    FOREIGN KEY (user_id)
+        REFERENCES Usr (user_id)
+        ON UPDATE NO ACTION
+        ON DELETE CASCADE,
+
+    FOREIGN KEY (group_id)
+        REFERENCES Groop (group_id)
+        ON UPDATE NO ACTION
+        ON DELETE CASCADE,
+
+    FOREIGN KEY (banned_by)
+        REFERENCES Usr (user_id)
+        ON UPDATE NO ACTION
+        ON DELETE SET NULL
+);
+
+-- Owners and admins can kick (64) and ban (128) members.
+UPDATE Membership
+SET permissions = permissions | 64 | 128
+WHERE role IN (0, 1);
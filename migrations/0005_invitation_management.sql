@@ -0,0 +1,22 @@
+-- expiry_time is NULL if the invitation never expires.
+-- remaining_uses is NULL if the invitation can be used any number of times.
+-- creator is NULL if the user who created the invitation has been deleted.
+ALTER TABLE Invitation
+    ADD COLUMN expiry_time TIMESTAMPTZ,
+    ADD COLUMN remaining_uses INTEGER,
+    ADD COLUMN creator INTEGER
+        REFERENCES Usr (user_id)
+        ON UPDATE NO ACTION
+        ON DELETE SET NULL;
+
+-- Invitations used to expire 24 hours after they were created.
+UPDATE Invitation
+SET expiry_time = creation_time + INTERVAL '24 hours';
+
+CREATE INDEX invitation_group_idx
+    ON Invitation (group_id);
+
+-- Owners and admins can manage invitations created by other users (256).
+UPDATE Membership
+SET permissions = permissions | 256
+WHERE role IN (0, 1);
@@ -0,0 +1,5 @@
+-- Full-text search on message content. Queries must use the same expression
+-- for the index to be used.
+CREATE INDEX message_content_search_idx
+    ON Message
+    USING GIN (to_tsvector('english', content));
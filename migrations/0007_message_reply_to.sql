@@ -0,0 +1,10 @@
+-- The message that this message is a reply to. NULL if the message is not a
+-- reply or if the parent message has been deleted.
+ALTER TABLE Message
+    ADD COLUMN reply_to INTEGER
+        REFERENCES Message (message_id)
+        ON UPDATE NO ACTION
+        ON DELETE SET NULL;
+
+CREATE INDEX IF NOT EXISTS message_reply_to_idx
+    ON Message (reply_to);
@@ -0,0 +1,18 @@
+CREATE TABLE IF NOT EXISTS Reaction (
+    message_id INTEGER NOT NULL,
+    user_id INTEGER NOT NULL,
+    emoji TEXT NOT NULL,
+    creation_time TIMESTAMPTZ NOT NULL,
+
+    PRIMARY KEY (message_id, user_id, emoji),
+
+    FOREIGN KEY (message_id)
+        REFERENCES Message (message_id)
+        ON UPDATE NO ACTION
+        ON DELETE CASCADE,
+
+    FOREIGN KEY (user_id)
+        REFERENCES Usr (user_id)
+        ON UPDATE NO ACTION
+        ON DELETE CASCADE
+);
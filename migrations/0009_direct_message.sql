@@ -0,0 +1,41 @@
+-- A private conversation between two or more users that is not part of a
+-- group.
+CREATE TABLE IF NOT EXISTS Conversation (
+    conversation_id SERIAL NOT NULL,
+    creation_time TIMESTAMPTZ NOT NULL,
+
+    PRIMARY KEY (conversation_id)
+);
+
+CREATE TABLE IF NOT EXISTS ConversationMember (
+    conversation_id INTEGER NOT NULL,
+    user_id INTEGER NOT NULL,
+
+    PRIMARY KEY (conversation_id, user_id),
+
+    FOREIGN KEY (conversation_id)
+        REFERENCES Conversation (conversation_id)
+        ON UPDATE NO ACTION
+        ON DELETE CASCADE,
+
+    FOREIGN KEY (user_id)
+        REFERENCES Usr (user_id)
+        ON UPDATE NO ACTION
+        ON DELETE CASCADE
+);
+
+CREATE INDEX IF NOT EXISTS conversation_member_user_idx
+    ON ConversationMember (user_id);
+
+-- A message is either in a channel or in a conversation.
+ALTER TABLE Message
+    ALTER COLUMN channel_id DROP NOT NULL,
+    ADD COLUMN conversation_id INTEGER
+        REFERENCES Conversation (conversation_id)
+        ON UPDATE NO ACTION
+        ON DELETE CASCADE,
+    ADD CONSTRAINT message_container
+        CHECK ((channel_id IS NULL) <> (conversation_id IS NULL));
+
+CREATE INDEX IF NOT EXISTS message_conversation_idx
+    ON Message (conversation_id, message_id);
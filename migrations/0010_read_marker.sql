@@ -0,0 +1,19 @@
+-- The last message that a user has read in a channel. Messages after this one
+-- are unread. If there is no row, every message in the channel is unread.
+CREATE TABLE IF NOT EXISTS ReadMarker (
+    user_id INTEGER NOT NULL,
+    channel_id INTEGER NOT NULL,
+    last_read_message_id INTEGER NOT NULL,
+
+    PRIMARY KEY (user_id, channel_id),
+
+    FOREIGN KEY (user_id)
+        REFERENCES Usr (user_id)
+        ON UPDATE NO ACTION
+        ON DELETE CASCADE,
+
+    FOREIGN KEY (channel_id)
+        REFERENCES Channel (channel_id)
+        ON UPDATE NO ACTION
+        ON DELETE CASCADE
+);
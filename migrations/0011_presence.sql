@@ -0,0 +1,11 @@
+-- The presence that the user has chosen and their custom status text. The
+-- presence values are defined in src/database/user.rs
+--
+-- Presence
+--   0 = online
+--   1 = away
+--   2 = do not disturb
+--   3 = invisible
+ALTER TABLE Usr
+    ADD COLUMN presence SMALLINT NOT NULL DEFAULT 0,
+    ADD COLUMN status_text TEXT;
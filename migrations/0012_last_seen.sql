@@ -0,0 +1,4 @@
+-- The time that the user's last connection was closed. This is NULL if the
+-- user has never disconnected since this column was added.
+ALTER TABLE Usr
+    ADD COLUMN last_seen TIMESTAMPTZ;
@@ -0,0 +1,28 @@
+-- Messages that have been pinned to their channel. The channel_id is the same
+-- as the channel_id of the message. It's stored here so that the pins of a
+-- channel can be listed without scanning Message.
+CREATE TABLE IF NOT EXISTS Pin (
+    message_id INTEGER NOT NULL,
+    channel_id INTEGER NOT NULL,
+    pinned_by INTEGER,
+    pin_time TIMESTAMPTZ NOT NULL,
+
+    PRIMARY KEY (message_id),
+
+    FOREIGN KEY (message_id)
+        REFERENCES Message (message_id)
+        ON UPDATE NO ACTION
+        ON DELETE CASCADE,
+
+    FOREIGN KEY (channel_id)
+        REFERENCES Channel (channel_id)
+        ON UPDATE NO ACTION
+        ON DELETE CASCADE,
+
+    FOREIGN KEY (pinned_by)
+        REFERENCES Usr (user_id)
+        ON UPDATE NO ACTION
+        ON DELETE SET NULL
+);
+
+CREATE INDEX IF NOT EXISTS pin_channel_id_idx ON Pin (channel_id, pin_time);
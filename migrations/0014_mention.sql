@@ -0,0 +1,37 @@
+-- The users and channels that are mentioned in a message with @name or #name.
+CREATE TABLE IF NOT EXISTS UserMention (
+    message_id INTEGER NOT NULL,
+    user_id INTEGER NOT NULL,
+
+    PRIMARY KEY (message_id, user_id),
+
+    FOREIGN KEY (message_id)
+        REFERENCES Message (message_id)
+        ON UPDATE NO ACTION
+        ON DELETE CASCADE,
+
+    FOREIGN KEY (user_id)
+        REFERENCES Usr (user_id)
+        ON UPDATE NO ACTION
+        ON DELETE CASCADE
+);
+
+-- Used for the mentions inbox of a user.
+CREATE INDEX IF NOT EXISTS user_mention_user_id_idx ON UserMention (user_id, message_id);
+
+CREATE TABLE IF NOT EXISTS ChannelMention (
+    message_id INTEGER NOT NULL,
+    channel_id INTEGER NOT NULL,
+
+    PRIMARY KEY (message_id, channel_id),
+
+    FOREIGN KEY (message_id)
+        REFERENCES Message (message_id)
+        ON UPDATE NO ACTION
+        ON DELETE CASCADE,
+
+    FOREIGN KEY (channel_id)
+        REFERENCES Channel (channel_id)
+        ON UPDATE NO ACTION
+        ON DELETE CASCADE
+);
@@ -0,0 +1,29 @@
+-- Files that have been uploaded. An attachment is uploaded before the message
+-- that it belongs to is created so message_id is NULL until then. The contents
+-- of the file are kept in storage under storage_key.
+CREATE TABLE IF NOT EXISTS Attachment (
+    attachment_id SERIAL NOT NULL,
+    message_id INTEGER,
+    uploader INTEGER,
+    name TEXT NOT NULL,
+    content_type TEXT NOT NULL,
+    size INTEGER NOT NULL,
+    storage_key TEXT NOT NULL,
+    creation_time TIMESTAMPTZ NOT NULL,
+
+    PRIMARY KEY (attachment_id),
+
+    UNIQUE (storage_key),
+
+    FOREIGN KEY (message_id)
+        REFERENCES Message (message_id)
+        ON UPDATE NO ACTION
+        ON DELETE CASCADE,
+
+    FOREIGN KEY (uploader)
+        REFERENCES Usr (user_id)
+        ON UPDATE NO ACTION
+        ON DELETE SET NULL
+);
+
+CREATE INDEX IF NOT EXISTS attachment_message_id_idx ON Attachment (message_id);
@@ -0,0 +1,29 @@
+-- Storage keys of files that are waiting to be removed from storage. Deleting
+-- a message (or the channel, group or conversation that it's in) cascades to
+-- its attachments so the keys are queued by a trigger rather than by the code
+-- that deletes the message. The files are removed by a periodic sweep.
+CREATE TABLE IF NOT EXISTS DeletedFile (
+    storage_key TEXT NOT NULL,
+
+    PRIMARY KEY (storage_key)
+);
+
+CREATE OR REPLACE FUNCTION queue_deleted_attachment() RETURNS TRIGGER AS $$
+BEGIN
+    INSERT INTO DeletedFile (storage_key)
+    VALUES (OLD.storage_key)
+    ON CONFLICT DO NOTHING;
+    RETURN OLD;
+END;
+$$ LANGUAGE plpgsql;
+
+CREATE TRIGGER attachment_deleted
+    AFTER DELETE ON Attachment
+    FOR EACH ROW
+    EXECUTE PROCEDURE queue_deleted_attachment();
+
+-- Used to find the attachments that were never added to a message, both for
+-- the upload quota and for the sweep.
+CREATE INDEX IF NOT EXISTS attachment_unattached_idx
+    ON Attachment (uploader, creation_time)
+    WHERE message_id IS NULL;
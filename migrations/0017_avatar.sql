@@ -0,0 +1,18 @@
+-- Pictures that have been uploaded for a user or a group. The resized files are
+-- kept in storage under keys made from avatar_key. Pictures that aren't used
+-- by a user or a group are removed by the sweep after a while.
+CREATE TABLE IF NOT EXISTS Avatar (
+    avatar_key TEXT NOT NULL,
+    uploader INTEGER,
+    creation_time TIMESTAMPTZ NOT NULL,
+
+    PRIMARY KEY (avatar_key),
+
+    FOREIGN KEY (uploader)
+        REFERENCES Usr (user_id)
+        ON UPDATE NO ACTION
+        ON DELETE SET NULL
+);
+
+-- Used for the upload quota.
+CREATE INDEX IF NOT EXISTS avatar_uploader_idx ON Avatar (uploader, creation_time);
@@ -0,0 +1,4 @@
+-- Used by the picture proxy to check that a picture is in use. The proxy
+-- doesn't require a session so this check must not scan the tables.
+CREATE INDEX IF NOT EXISTS usr_picture_idx ON Usr (picture);
+CREATE INDEX IF NOT EXISTS groop_picture_idx ON Groop (picture);
@@ -0,0 +1,4 @@
+-- The content of the message rendered as HTML when it was created or last
+-- edited. NULL for messages created before this was stored.
+ALTER TABLE Message
+    ADD COLUMN html TEXT;
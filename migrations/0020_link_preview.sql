@@ -0,0 +1,20 @@
+-- A preview of the first link in a message. Previews are fetched after the
+-- message is created so a message may not have one yet (or ever).
+CREATE TABLE IF NOT EXISTS LinkPreview (
+    message_id INTEGER NOT NULL,
+    url TEXT NOT NULL,
+    title TEXT NOT NULL,
+    description TEXT,
+    image TEXT,
+    fetch_time TIMESTAMPTZ NOT NULL,
+
+    PRIMARY KEY (message_id),
+
+    FOREIGN KEY (message_id)
+        REFERENCES Message (message_id)
+        ON UPDATE NO ACTION
+        ON DELETE CASCADE
+);
+
+-- Used by the picture proxy to check that an image is in use.
+CREATE INDEX IF NOT EXISTS link_preview_image_idx ON LinkPreview (image);
@@ -0,0 +1,3 @@
+-- Used to reuse a recent preview of the same link instead of fetching the page
+-- again.
+CREATE INDEX IF NOT EXISTS link_preview_url_idx ON LinkPreview (url, fetch_time);
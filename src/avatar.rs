@@ -0,0 +1,97 @@
+use std::io::Cursor;
+use image::{DynamicImage, ImageOutputFormat, ImageResult};
+use image::imageops::FilterType;
+use crate::utils::generate_random_base64url;
+
+/// The sizes (in pixels) that each uploaded picture is resized to. Pictures
+/// are square.
+pub const AVATAR_SIZES: &[u32] = &[64, 128, 256];
+
+/// The size used in the URL returned after uploading a picture.
+pub const DEFAULT_AVATAR_SIZE: u32 = 128;
+
+/// The maximum size of an uploaded picture in bytes.
+pub const MAX_AVATAR_FILE_SIZE: usize = 4 * 1024 * 1024;
+
+/// The maximum width or height of an uploaded picture. This is checked before
+/// decoding so that a small file can't claim to be a huge image.
+pub const MAX_AVATAR_DIMENSION: u32 = 4096;
+
+const AVATAR_KEY_LENGTH: usize = 32;
+
+pub fn generate_avatar_key() -> String {
+    generate_random_base64url(AVATAR_KEY_LENGTH)
+}
+
+pub fn valid_avatar_key(key: &str) -> bool {
+    key.len() == AVATAR_KEY_LENGTH
+        && key.bytes().all(|b| b.is_ascii_alphanumeric() || b == b'-' || b == b'_')
+}
+
+/// The key that a resized picture is kept under in storage.
+pub fn storage_key(key: &str, size: u32) -> String {
+    format!("avatar/{}-{}.png", key, size)
+}
+
+/// The URL that a resized picture is served from.
+pub fn avatar_url(key: &str, size: u32) -> String {
+    format!("/avatar/{}/{}", size, key)
+}
+
+/// Determine whether a picture is an external URL or a picture that was
+/// uploaded to this server.
+pub fn valid_picture(picture: &String) -> bool {
+    if crate::database::valid_url(picture) {
+        return true;
+    }
+    let mut parts = picture.split('/');
+    parts.next() == Some("")
+        && parts.next() == Some("avatar")
+        && parts.next().and_then(|size| size.parse().ok()).is_some_and(|size| AVATAR_SIZES.contains(&size))
+        && parts.next().is_some_and(valid_avatar_key)
+        && parts.next().is_none()
+}
+
+pub enum AvatarError {
+    TooLarge,
+    Invalid,
+}
+
+impl From<image::ImageError> for AvatarError {
+    fn from(_: image::ImageError) -> Self {
+        AvatarError::Invalid
+    }
+}
+
+impl From<std::io::Error> for AvatarError {
+    fn from(_: std::io::Error) -> Self {
+        AvatarError::Invalid
+    }
+}
+
+fn encode_png(image: &DynamicImage) -> ImageResult<Vec<u8>> {
+    let mut data = Vec::new();
+    image.write_to(&mut data, ImageOutputFormat::Png)?;
+    Ok(data)
+}
+
+/// Decode an uploaded picture and create a PNG for each of the AVATAR_SIZES.
+/// The picture is cropped to a square around the centre. Re-encoding discards
+/// any metadata in the original file.
+///
+/// This is CPU bound so it should be called from a blocking task.
+pub fn resize_avatar(data: &[u8]) -> Result<Vec<(u32, Vec<u8>)>, AvatarError> {
+    let reader = image::io::Reader::new(Cursor::new(data)).with_guessed_format()?;
+    let (width, height) = reader.into_dimensions()?;
+    if width > MAX_AVATAR_DIMENSION || height > MAX_AVATAR_DIMENSION {
+        return Err(AvatarError::TooLarge);
+    }
+
+    let image = image::io::Reader::new(Cursor::new(data)).with_guessed_format()?.decode()?;
+    let mut resized = Vec::with_capacity(AVATAR_SIZES.len());
+    for &size in AVATAR_SIZES.iter() {
+        let image = image.resize_to_fill(size, size, FilterType::Lanczos3);
+        resized.push((size, encode_png(&image)?));
+    }
+    Ok(resized)
+}
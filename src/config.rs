@@ -0,0 +1,254 @@
+use serde::Deserialize;
+use std::net::SocketAddr;
+use std::str::FromStr;
+use std::env::VarError;
+
+/// Path of the config file that is used if CHAT_CONFIG is not set.
+const DEFAULT_CONFIG_PATH: &str = "config.toml";
+
+/*
+Configuration is loaded in three layers. The defaults are the values that were
+previously hardcoded and are suitable for running locally. These are overridden
+by the TOML file at CHAT_CONFIG (or config.toml). The file is optional. Those
+are then overridden by environment variables. See config.example.toml for all
+of the available settings.
+*/
+
+#[derive(Deserialize)]
+#[serde(default)]
+pub struct DatabaseConfig {
+    pub host: String,
+    pub port: u16,
+    pub user: String,
+    pub password: Option<String>,
+    pub dbname: String,
+    pub pool_size: usize,
+}
+
+impl Default for DatabaseConfig {
+    fn default() -> Self {
+        Self {
+            host: "localhost".to_owned(),
+            port: 5432,
+            user: "postgres".to_owned(),
+            password: None,
+            dbname: "chat".to_owned(),
+            pool_size: 16,
+        }
+    }
+}
+
+#[derive(Deserialize)]
+#[serde(default)]
+pub struct ServerConfig {
+    pub address: SocketAddr,
+}
+
+impl Default for ServerConfig {
+    fn default() -> Self {
+        Self {
+            address: ([0, 0, 0, 0], 443).into(),
+        }
+    }
+}
+
+#[derive(Deserialize)]
+#[serde(default)]
+pub struct TlsConfig {
+    pub cert_path: String,
+    pub key_path: String,
+}
+
+impl Default for TlsConfig {
+    fn default() -> Self {
+        Self {
+            cert_path: "tls/localhost.crt".to_owned(),
+            key_path: "tls/localhost.key".to_owned(),
+        }
+    }
+}
+
+#[derive(Deserialize)]
+#[serde(default)]
+pub struct GoogleConfig {
+    pub client_id: String,
+    pub client_secret: String,
+    pub redirect_uri: String,
+}
+
+impl Default for GoogleConfig {
+    fn default() -> Self {
+        Self {
+            client_id: String::new(),
+            client_secret: String::new(),
+            redirect_uri: "https://localhost/api/auth".to_owned(),
+        }
+    }
+}
+
+pub type GoogleConfigRef = std::sync::Arc<GoogleConfig>;
+
+#[derive(Deserialize)]
+#[serde(default)]
+pub struct StorageConfig {
+    /// The directory that uploaded files are stored in.
+    pub path: String,
+    /// The number of seconds that an uploaded file is kept if it isn't added
+    /// to a message, and that an uploaded picture is kept if it isn't used.
+    pub unattached_expiry: u64,
+    /// The number of seconds between sweeps that remove expired and deleted
+    /// files. This includes the picture cache.
+    pub sweep_interval: u64,
+}
+
+impl Default for StorageConfig {
+    fn default() -> Self {
+        Self {
+            path: "attachments".to_owned(),
+            unattached_expiry: 86400,
+            sweep_interval: 600,
+        }
+    }
+}
+
+#[derive(Deserialize)]
+#[serde(default)]
+pub struct FetchConfig {
+    /// Allow fetching URLs that resolve to loopback or private addresses. This
+    /// should only be enabled for testing.
+    pub allow_private_addresses: bool,
+    /// The maximum number of seconds that fetching a URL may take.
+    pub timeout: u64,
+}
+
+impl Default for FetchConfig {
+    fn default() -> Self {
+        Self {
+            allow_private_addresses: false,
+            timeout: 10,
+        }
+    }
+}
+
+#[derive(Deserialize)]
+#[serde(default)]
+pub struct PictureProxyConfig {
+    /// The directory that fetched pictures are cached in.
+    pub cache_path: String,
+    /// The number of seconds before a cached picture is fetched again.
+    pub cache_expiry: u64,
+}
+
+impl Default for PictureProxyConfig {
+    fn default() -> Self {
+        Self {
+            cache_path: "picture_cache".to_owned(),
+            cache_expiry: 86400,
+        }
+    }
+}
+
+#[derive(Deserialize, Default)]
+#[serde(default)]
+pub struct Config {
+    pub database: DatabaseConfig,
+    pub server: ServerConfig,
+    pub tls: TlsConfig,
+    pub google: GoogleConfig,
+    pub storage: StorageConfig,
+    pub fetch: FetchConfig,
+    pub picture_proxy: PictureProxyConfig,
+}
+
+#[derive(Debug)]
+pub enum ConfigError {
+    Io(String, std::io::Error),
+    Toml(String, toml::de::Error),
+    Env(&'static str),
+    Missing(&'static str),
+}
+
+impl std::fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            ConfigError::Io(path, e) => write!(f, "Failed to read {}: {}", path, e),
+            ConfigError::Toml(path, e) => write!(f, "Failed to parse {}: {}", path, e),
+            ConfigError::Env(name) => write!(f, "Environment variable {} is invalid", name),
+            ConfigError::Missing(name) => write!(f, "{} must be set", name),
+        }
+    }
+}
+
+impl std::error::Error for ConfigError {}
+
+/// Replace a value with the value of an environment variable if it is set.
+fn env_override<T: FromStr>(value: &mut T, name: &'static str) -> Result<(), ConfigError> {
+    match std::env::var(name) {
+        Ok(string) => {
+            *value = string.parse().map_err(|_| ConfigError::Env(name))?;
+            Ok(())
+        },
+        Err(VarError::NotPresent) => Ok(()),
+        Err(VarError::NotUnicode(_)) => Err(ConfigError::Env(name)),
+    }
+}
+
+impl Config {
+    /// Load the config file and apply the environment variable overrides.
+    pub fn load() -> Result<Self, ConfigError> {
+        let path = std::env::var("CHAT_CONFIG")
+            .unwrap_or_else(|_| DEFAULT_CONFIG_PATH.to_owned());
+
+        let mut config = match std::fs::read_to_string(&path) {
+            Ok(string) => toml::from_str::<Config>(string.as_str())
+                .map_err(|e| ConfigError::Toml(path, e))?,
+            // The file is optional if CHAT_CONFIG isn't set.
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound
+                && std::env::var_os("CHAT_CONFIG").is_none() => Config::default(),
+            Err(e) => return Err(ConfigError::Io(path, e)),
+        };
+
+        config.apply_env()?;
+
+        if config.google.client_id.is_empty() {
+            return Err(ConfigError::Missing("google.client_id"));
+        }
+        if config.google.client_secret.is_empty() {
+            return Err(ConfigError::Missing("google.client_secret"));
+        }
+
+        Ok(config)
+    }
+
+    fn apply_env(&mut self) -> Result<(), ConfigError> {
+        env_override(&mut self.database.host, "CHAT_DATABASE_HOST")?;
+        env_override(&mut self.database.port, "CHAT_DATABASE_PORT")?;
+        env_override(&mut self.database.user, "CHAT_DATABASE_USER")?;
+        env_override(&mut self.database.dbname, "CHAT_DATABASE_DBNAME")?;
+        env_override(&mut self.database.pool_size, "CHAT_DATABASE_POOL_SIZE")?;
+        if let Ok(password) = std::env::var("CHAT_DATABASE_PASSWORD") {
+            self.database.password = Some(password);
+        }
+
+        env_override(&mut self.server.address, "CHAT_SERVER_ADDRESS")?;
+
+        env_override(&mut self.tls.cert_path, "CHAT_TLS_CERT_PATH")?;
+        env_override(&mut self.tls.key_path, "CHAT_TLS_KEY_PATH")?;
+
+        env_override(&mut self.google.client_id, "CHAT_GOOGLE_CLIENT_ID")?;
+        env_override(&mut self.google.client_secret, "CHAT_GOOGLE_CLIENT_SECRET")?;
+        env_override(&mut self.google.redirect_uri, "CHAT_GOOGLE_REDIRECT_URI")?;
+
+        env_override(&mut self.storage.path, "CHAT_STORAGE_PATH")?;
+        env_override(&mut self.storage.unattached_expiry, "CHAT_STORAGE_UNATTACHED_EXPIRY")?;
+        env_override(&mut self.storage.sweep_interval, "CHAT_STORAGE_SWEEP_INTERVAL")?;
+
+        env_override(&mut self.fetch.allow_private_addresses, "CHAT_FETCH_ALLOW_PRIVATE_ADDRESSES")?;
+        env_override(&mut self.fetch.timeout, "CHAT_FETCH_TIMEOUT")?;
+
+        env_override(&mut self.picture_proxy.cache_path, "CHAT_PICTURE_PROXY_CACHE_PATH")?;
+        env_override(&mut self.picture_proxy.cache_expiry, "CHAT_PICTURE_PROXY_CACHE_EXPIRY")?;
+
+        Ok(())
+    }
+}
@@ -0,0 +1,178 @@
+use serde::Serialize;
+use std::time::SystemTime;
+use crate::error::Error;
+use super::{GroupID, MessageID, UserID};
+use deadpool_postgres::{Pool, PoolError};
+
+pub type AttachmentID = i32;
+
+/// The maximum size of an uploaded file in bytes.
+pub const MAX_ATTACHMENT_SIZE: usize = 8 * 1024 * 1024;
+
+/// The maximum number of attachments on a message.
+pub const MAX_MESSAGE_ATTACHMENTS: usize = 10;
+
+/// The maximum total size in bytes of the files that a user has uploaded but
+/// not added to a message yet. These are removed by the sweep after a while.
+pub const MAX_UNATTACHED_SIZE: usize = 64 * 1024 * 1024;
+
+/// The number of deleted files that are removed from storage at a time by the
+/// sweep.
+pub const DELETED_FILE_BATCH: usize = 100;
+
+// The number of base64url characters in a storage key
+const STORAGE_KEY_LENGTH: usize = 32;
+
+#[derive(Serialize)]
+pub struct Attachment {
+    pub attachment_id: AttachmentID,
+    pub name: String,
+    pub content_type: String,
+    pub size: i32,
+}
+
+/// The information needed to decide whether a user may download an
+/// attachment and then to send it.
+pub struct AttachmentFile {
+    pub name: String,
+    pub content_type: String,
+    pub storage_key: String,
+    pub uploader: Option<UserID>,
+    /// The message that the attachment belongs to. This is None if the
+    /// attachment hasn't been added to a message yet.
+    pub message_id: Option<MessageID>,
+    pub group_id: Option<GroupID>,
+}
+
+pub fn generate_storage_key() -> String {
+    crate::utils::generate_random_base64url(STORAGE_KEY_LENGTH)
+}
+
+/// Create an attachment that doesn't belong to a message yet. The file must
+/// already be in storage.
+///
+/// Returns None if this would take the user over MAX_UNATTACHED_SIZE.
+pub async fn create_attachment(
+    pool: Pool,
+    time: SystemTime,
+    uploader: UserID,
+    name: &str,
+    content_type: &str,
+    size: usize,
+    storage_key: &str
+) -> Result<Option<AttachmentID>, Error> {
+    let mut conn = pool.get().await?;
+    let txn = conn.transaction().await?;
+
+    // Locking the user means that concurrent uploads are counted one at a
+    // time so the quota can't be exceeded.
+    txn.execute("
+        SELECT 1
+        FROM Usr
+        WHERE user_id = $1
+        FOR UPDATE
+    ", &[&uploader]).await?;
+
+    let unattached: i64 = txn.query_one("
+        SELECT COALESCE(SUM(size), 0)
+        FROM Attachment
+        WHERE uploader = $1
+        AND message_id IS NULL
+    ", &[&uploader]).await?.get(0);
+
+    if unattached as usize + size > MAX_UNATTACHED_SIZE {
+        return Ok(None);
+    }
+
+    let size = size as i32;
+    let attachment_id = txn.query_one("
+        INSERT INTO Attachment (uploader, name, content_type, size, storage_key, creation_time)
+        VALUES ($1, $2, $3, $4, $5, $6)
+        RETURNING attachment_id
+    ", &[&uploader, &name, &content_type, &size, &storage_key, &time]).await?.get(0);
+
+    txn.commit().await?;
+    Ok(Some(attachment_id))
+}
+
+/// Get the attachments on a list of messages, ordered by message_id then by
+/// attachment_id.
+pub async fn message_attachments(pool: Pool, message_ids: &[MessageID])
+    -> Result<Vec<(MessageID, Attachment)>, PoolError>
+{
+    let conn = pool.get().await?;
+    let stmt = conn.prepare("
+        SELECT message_id, attachment_id, name, content_type, size
+        FROM Attachment
+        WHERE message_id = ANY($1)
+        ORDER BY message_id, attachment_id
+    ").await?;
+    Ok(conn.query(&stmt, &[&message_ids]).await?.iter().map(|row| (row.get(0), Attachment {
+        attachment_id: row.get(1),
+        name: row.get(2),
+        content_type: row.get(3),
+        size: row.get(4),
+    })).collect())
+}
+
+pub async fn attachment_file(pool: Pool, attachment_id: AttachmentID)
+    -> Result<Option<AttachmentFile>, Error>
+{
+    let conn = pool.get().await?;
+    let stmt = conn.prepare("
+        SELECT Attachment.name, content_type, storage_key, uploader, Attachment.message_id, Channel.group_id
+        FROM Attachment
+        LEFT JOIN Message ON Message.message_id = Attachment.message_id
+        LEFT JOIN Channel ON Channel.channel_id = Message.channel_id
+        WHERE attachment_id = $1
+    ").await?;
+    Ok(conn.query_opt(&stmt, &[&attachment_id]).await?.map(|row| AttachmentFile {
+        name: row.get(0),
+        content_type: row.get(1),
+        storage_key: row.get(2),
+        uploader: row.get(3),
+        message_id: row.get(4),
+        group_id: row.get(5),
+    }))
+}
+
+/// Delete the attachments that were uploaded before a time but never added to
+/// a message. Their files are queued for removal.
+pub async fn delete_unattached(pool: Pool, before: SystemTime) -> Result<u64, PoolError> {
+    let conn = pool.get().await?;
+    let stmt = conn.prepare("
+        DELETE FROM Attachment
+        WHERE message_id IS NULL
+        AND creation_time < $1
+    ").await?;
+    conn.execute(&stmt, &[&before]).await.map_err(|e| e.into())
+}
+
+/// Get up to DELETED_FILE_BATCH storage keys of files that are waiting to be
+/// removed from storage.
+///
+/// The keys are in order and only keys after the given key are returned so
+/// that the sweep can move past files that it failed to remove.
+pub async fn deleted_files(pool: Pool, after: &str) -> Result<Vec<String>, PoolError> {
+    let conn = pool.get().await?;
+    let stmt = conn.prepare("
+        SELECT storage_key
+        FROM DeletedFile
+        WHERE storage_key > $1
+        ORDER BY storage_key
+        LIMIT $2
+    ").await?;
+    let limit = DELETED_FILE_BATCH as i64;
+    Ok(conn.query(&stmt, &[&after, &limit]).await?.iter().map(|row| row.get(0)).collect())
+}
+
+/// Forget files that have been removed from storage.
+pub async fn remove_deleted_files(pool: Pool, storage_keys: &[String]) -> Result<(), PoolError> {
+    let conn = pool.get().await?;
+    let stmt = conn.prepare("
+        DELETE FROM DeletedFile
+        WHERE storage_key = ANY($1)
+    ").await?;
+    conn.execute(&stmt, &[&storage_keys]).await?;
+    Ok(())
+}
@@ -0,0 +1,104 @@
+use std::time::{Duration, SystemTime};
+use crate::error::Error;
+use super::UserID;
+use deadpool_postgres::{Pool, PoolError};
+
+/// The maximum number of pictures that a user may upload in
+/// AVATAR_UPLOAD_PERIOD.
+pub const MAX_AVATAR_UPLOADS: usize = 20;
+
+/// The period that uploads are counted over for MAX_AVATAR_UPLOADS.
+pub const AVATAR_UPLOAD_PERIOD: Duration = Duration::from_secs(24 * 60 * 60);
+
+/// The number of unused pictures that are removed at a time by the sweep.
+pub const UNUSED_AVATAR_BATCH: usize = 100;
+
+/// Record an uploaded picture. This must be done before the files are put in
+/// storage so that the sweep can find them.
+///
+/// Returns false if the user has already uploaded MAX_AVATAR_UPLOADS pictures
+/// in the last AVATAR_UPLOAD_PERIOD.
+pub async fn create_avatar(pool: Pool, time: SystemTime, uploader: UserID, avatar_key: &str)
+    -> Result<bool, Error>
+{
+    let mut conn = pool.get().await?;
+    let txn = conn.transaction().await?;
+
+    // Locking the user means that concurrent uploads are counted one at a
+    // time so the quota can't be exceeded.
+    txn.execute("
+        SELECT 1
+        FROM Usr
+        WHERE user_id = $1
+        FOR UPDATE
+    ", &[&uploader]).await?;
+
+    let uploads: i64 = txn.query_one("
+        SELECT COUNT(*)
+        FROM Avatar
+        WHERE uploader = $1
+        AND creation_time > $2
+    ", &[&uploader, &(time - AVATAR_UPLOAD_PERIOD)]).await?.get(0);
+
+    if uploads as usize >= MAX_AVATAR_UPLOADS {
+        return Ok(false);
+    }
+
+    txn.execute("
+        INSERT INTO Avatar (avatar_key, uploader, creation_time)
+        VALUES ($1, $2, $3)
+    ", &[&avatar_key, &uploader, &time]).await?;
+
+    txn.commit().await?;
+    Ok(true)
+}
+
+/// Get up to UNUSED_AVATAR_BATCH keys of pictures that were uploaded before a
+/// time and aren't the picture of any user or group. A user or group may use
+/// any of the sizes so the URL of each one is checked (see avatar::avatar_url).
+///
+/// The keys are in order and only keys after the given key are returned so
+/// that the sweep can move past pictures that it failed to remove.
+pub async fn unused_avatars(pool: Pool, before: SystemTime, sizes: &[u32], after: &str)
+    -> Result<Vec<String>, PoolError>
+{
+    let conn = pool.get().await?;
+    let stmt = conn.prepare("
+        SELECT avatar_key
+        FROM Avatar
+        WHERE creation_time < $1
+        AND avatar_key > $4
+        AND NOT EXISTS (
+            SELECT 1
+            FROM Usr
+            WHERE picture IN (
+                SELECT '/avatar/' || size || '/' || avatar_key
+                FROM UNNEST($2::INTEGER[]) AS size
+            )
+        )
+        AND NOT EXISTS (
+            SELECT 1
+            FROM Groop
+            WHERE picture IN (
+                SELECT '/avatar/' || size || '/' || avatar_key
+                FROM UNNEST($2::INTEGER[]) AS size
+            )
+        )
+        ORDER BY avatar_key
+        LIMIT $3
+    ").await?;
+    let sizes = sizes.iter().map(|&size| size as i32).collect::<Vec<_>>();
+    let limit = UNUSED_AVATAR_BATCH as i64;
+    Ok(conn.query(&stmt, &[&before, &sizes, &limit, &after]).await?.iter().map(|row| row.get(0)).collect())
+}
+
+/// Forget pictures that have been removed from storage.
+pub async fn remove_avatars(pool: Pool, avatar_keys: &[String]) -> Result<(), PoolError> {
+    let conn = pool.get().await?;
+    let stmt = conn.prepare("
+        DELETE FROM Avatar
+        WHERE avatar_key = ANY($1)
+    ").await?;
+    conn.execute(&stmt, &[&avatar_keys]).await?;
+    Ok(())
+}
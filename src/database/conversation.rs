@@ -0,0 +1,172 @@
+use serde::Serialize;
+use super::{MessageID, UserID, MESSAGE_PAGE_LIMIT};
+use crate::error::Error;
+use deadpool_postgres::Pool;
+use deadpool_postgres::tokio_postgres::Row;
+
+pub type ConversationID = i32;
+
+/// The maximum number of users in a conversation, including the creator.
+pub const MAX_CONVERSATION_USERS: usize = 10;
+
+/// Create a conversation between a set of users.
+///
+/// If the conversation is between two users and they already have a
+/// conversation, that conversation is returned instead.
+pub async fn create_conversation(pool: Pool, user_ids: &[UserID])
+    -> Result<ConversationID, Error>
+{
+    let mut conn = pool.get().await?;
+    let txn = conn.transaction().await?;
+
+    if let [first, second] = user_ids {
+        // Lock the pair of users so that if they both try to start a
+        // conversation with each other at the same time, they both get the
+        // same conversation. The two key form of pg_advisory_xact_lock doesn't
+        // overlap with the migration lock.
+        let (low, high) = (first.min(second), first.max(second));
+        txn.execute("SELECT pg_advisory_xact_lock($1, $2)", &[low, high]).await?;
+
+        let existing = txn.query_opt("
+            SELECT Mine.conversation_id
+            FROM ConversationMember Mine
+            JOIN ConversationMember Other ON Other.conversation_id = Mine.conversation_id
+            WHERE Mine.user_id = $1
+            AND Other.user_id = $2
+            AND (
+                SELECT COUNT(*)
+                FROM ConversationMember Member
+                WHERE Member.conversation_id = Mine.conversation_id
+            ) = 2
+            LIMIT 1
+        ", &[first, second]).await?;
+        if let Some(row) = existing {
+            return Ok(row.get(0));
+        }
+    }
+
+    let conversation_id: ConversationID = txn.query_one("
+        INSERT INTO Conversation (creation_time)
+        VALUES (NOW())
+        RETURNING conversation_id
+    ", &[]).await?.get(0);
+    txn.execute("
+        INSERT INTO ConversationMember (conversation_id, user_id)
+        SELECT $1, UNNEST($2::INTEGER[])
+    ", &[&conversation_id, &user_ids]).await?;
+    txn.commit().await?;
+
+    Ok(conversation_id)
+}
+
+/// Check whether a user shares at least one group with each of the other
+/// users. Users may only start conversations with people they know.
+pub async fn share_groups(pool: Pool, user_id: UserID, others: &[UserID])
+    -> Result<bool, Error>
+{
+    let conn = pool.get().await?;
+    let stmt = conn.prepare("
+        SELECT COUNT(DISTINCT Other.user_id)
+        FROM Membership Other
+        JOIN Membership Mine ON Mine.group_id = Other.group_id
+        WHERE Mine.user_id = $1
+        AND Other.user_id = ANY($2)
+    ").await?;
+    let count: i64 = conn.query_one(&stmt, &[&user_id, &others]).await?.get(0);
+    Ok(count as usize == others.len())
+}
+
+#[derive(Serialize)]
+pub struct Conversation {
+    pub conversation_id: ConversationID,
+    pub user_ids: Vec<UserID>,
+}
+
+/// Get the list of conversations that a user is part of, most recently
+/// active first.
+pub async fn user_conversations(pool: Pool, user_id: UserID)
+    -> Result<Vec<Conversation>, Error>
+{
+    let conn = pool.get().await?;
+    let stmt = conn.prepare("
+        SELECT Conversation.conversation_id, ARRAY_AGG(Member.user_id ORDER BY Member.user_id)
+        FROM Conversation
+        JOIN ConversationMember Mine ON Mine.conversation_id = Conversation.conversation_id
+        JOIN ConversationMember Member ON Member.conversation_id = Conversation.conversation_id
+        WHERE Mine.user_id = $1
+        GROUP BY Conversation.conversation_id
+        ORDER BY COALESCE((
+            SELECT MAX(message_id)
+            FROM Message
+            WHERE Message.conversation_id = Conversation.conversation_id
+        ), 0) DESC, Conversation.conversation_id DESC
+    ").await?;
+    Ok(conn.query(&stmt, &[&user_id])
+        .await?
+        .iter()
+        .map(|row| Conversation {
+            conversation_id: row.get(0),
+            user_ids: row.get(1),
+        })
+        .collect())
+}
+
+/// Get the users in a conversation.
+///
+/// Returns an empty vector if the conversation is invalid.
+pub async fn conversation_users(pool: Pool, conversation_id: ConversationID)
+    -> Result<Vec<UserID>, Error>
+{
+    let conn = pool.get().await?;
+    let stmt = conn.prepare("
+        SELECT user_id
+        FROM ConversationMember
+        WHERE conversation_id = $1
+    ").await?;
+    Ok(conn.query(&stmt, &[&conversation_id])
+        .await?
+        .iter()
+        .map(|row| row.get(0))
+        .collect())
+}
+
+pub async fn create_direct_message(
+    pool: Pool,
+    time: std::time::SystemTime,
+    user_id: UserID,
+    content: &str,
+    html: &str,
+    conversation_id: ConversationID
+) -> Result<MessageID, Error> {
+    let conn = pool.get().await?;
+    let stmt = conn.prepare("
+        INSERT INTO Message (timestamp, author, content, html, conversation_id)
+        VALUES ($1, $2, $3, $4, $5)
+        RETURNING message_id
+    ").await?;
+    Ok(conn.query_one(&stmt, &[&time, &user_id, &content, &html, &conversation_id]).await?.get(0))
+}
+
+/// Get the MESSAGE_PAGE_LIMIT most recent messages in a conversation. If
+/// before is Some then only messages before that message are returned.
+///
+/// The columns are the same as recent_messages.
+pub async fn direct_messages(pool: Pool, conversation_id: ConversationID, before: Option<MessageID>)
+    -> Result<Vec<Row>, Error>
+{
+    let conn = pool.get().await?;
+    let stmt = conn.prepare("
+        SELECT message_id, timestamp, COALESCE(author, 0), content, html, edited_at, reply_to
+        FROM (
+            SELECT *
+            FROM Message
+            WHERE conversation_id = $1
+            AND ($2::INTEGER IS NULL OR message_id < $2)
+            ORDER BY message_id DESC
+            LIMIT $3
+        ) Temp
+        ORDER BY message_id ASC
+    ").await?;
+    let limit = MESSAGE_PAGE_LIMIT as i64;
+    Ok(conn.query(&stmt, &[&conversation_id, &before, &limit]).await?)
+}
@@ -53,6 +53,7 @@ pub async fn group_channels(pool: Pool, group_id: GroupID)
 pub struct Group {
     pub group_id: GroupID,
     pub name: String,
+    #[serde(serialize_with = "crate::proxy::serialize_picture")]
     pub picture: String,
 }
 
@@ -86,20 +87,6 @@ pub async fn user_group_ids(pool: Pool, user_id: UserID) -> Result<Vec<GroupID>,
     Ok(conn.query(&stmt, &[&user_id]).await?.iter().map(|row| row.get(0)).collect())
 }
 
-/// Determine whether a user is a member of a group
-pub async fn group_member(pool: Pool, user_id: UserID, group_id: GroupID)
-    -> Result<bool, Error>
-{
-    let conn = pool.get().await?;
-    let stmt = conn.prepare("
-        SELECT 1
-        FROM Membership
-        WHERE user_id = $1
-        AND group_id = $2
-    ").await?;
-    Ok(conn.query_opt(&stmt, &[&user_id, &group_id]).await?.is_some())
-}
-
 pub async fn rename_group(pool: Pool, group_id: GroupID, name: &String, picture: &String)
     -> Result<bool, PoolError>
 {
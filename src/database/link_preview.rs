@@ -0,0 +1,80 @@
+use super::MessageID;
+use serde::Serialize;
+use std::time::SystemTime;
+use deadpool_postgres::{Pool, PoolError};
+
+#[derive(Serialize)]
+pub struct LinkPreview {
+    pub url: String,
+    pub title: String,
+    pub description: Option<String>,
+    #[serde(serialize_with = "crate::proxy::serialize_optional_picture")]
+    pub image: Option<String>,
+}
+
+/// Store the preview of a link in a message.
+///
+/// Returns false if the message has been deleted.
+pub async fn set_link_preview(pool: Pool, time: SystemTime, message_id: MessageID, preview: &LinkPreview)
+    -> Result<bool, PoolError>
+{
+    let conn = pool.get().await?;
+    let stmt = conn.prepare("
+        INSERT INTO LinkPreview (message_id, url, title, description, image, fetch_time)
+        SELECT $1, $2, $3, $4, $5, $6
+        WHERE EXISTS (
+            SELECT 1
+            FROM Message
+            WHERE message_id = $1
+        )
+        ON CONFLICT (message_id) DO UPDATE
+        SET url = EXCLUDED.url,
+            title = EXCLUDED.title,
+            description = EXCLUDED.description,
+            image = EXCLUDED.image,
+            fetch_time = EXCLUDED.fetch_time
+    ").await?;
+    Ok(conn.execute(&stmt, &[
+        &message_id, &preview.url, &preview.title, &preview.description, &preview.image, &time
+    ]).await? > 0)
+}
+
+/// Get the link previews of a list of messages.
+pub async fn message_link_previews(pool: Pool, message_ids: &[MessageID])
+    -> Result<Vec<(MessageID, LinkPreview)>, PoolError>
+{
+    let conn = pool.get().await?;
+    let stmt = conn.prepare("
+        SELECT message_id, url, title, description, image
+        FROM LinkPreview
+        WHERE message_id = ANY($1)
+    ").await?;
+    Ok(conn.query(&stmt, &[&message_ids]).await?.iter().map(|row| (row.get(0), LinkPreview {
+        url: row.get(1),
+        title: row.get(2),
+        description: row.get(3),
+        image: row.get(4),
+    })).collect())
+}
+
+/// Get the most recent preview of a URL that was fetched after a time, along
+/// with the time that it was fetched.
+pub async fn recent_link_preview(pool: Pool, url: &str, since: SystemTime)
+    -> Result<Option<(SystemTime, LinkPreview)>, PoolError>
+{
+    let conn = pool.get().await?;
+    let stmt = conn.prepare("
+        SELECT fetch_time, url, title, description, image
+        FROM LinkPreview
+        WHERE url = $1
+        AND fetch_time > $2
+        ORDER BY fetch_time DESC
+        LIMIT 1
+    ").await?;
+    Ok(conn.query_opt(&stmt, &[&url, &since]).await?.map(|row| (row.get(0), LinkPreview {
+        url: row.get(1),
+        title: row.get(2),
+        description: row.get(3),
+        image: row.get(4),
+    })))
+}
@@ -1,38 +1,61 @@
 use crate::error::Error;
+use std::time::SystemTime;
 use deadpool_postgres::Pool;
+use serde::{Serialize, Deserialize};
 use super::{UserID, GroupID};
-use crate::utils::generate_random_base64url;
+use crate::utils::{as_timestamp, generate_random_base64url};
 
 // This value is duplicated in the column type of Invitation.invite_id
 pub const INVITE_ID_LENGTH: usize = 16;
 
 pub type InviteID = String;
 
-macro_rules! creation_timeout {
-    () => { "INTERVAL '24 hours'" }
+// The condition that an invitation must satisfy to be usable
+macro_rules! invitation_valid {
+    () => { "
+        (expiry_time IS NULL OR expiry_time > NOW())
+        AND (remaining_uses IS NULL OR remaining_uses > 0)
+    " }
 }
 
-pub async fn create_invitation(pool: Pool, group_id: GroupID)
-    -> Result<InviteID, Error>
-{
+#[derive(Serialize)]
+pub struct Invitation {
+    pub invite_id: InviteID,
+    pub creator: UserID,
+    pub creation_time: u64,
+    pub expiry_time: Option<u64>,
+    pub remaining_uses: Option<i32>,
+}
+
+/// Create an invitation link for a group.
+///
+/// If expiry_time is None, the invitation never expires. If max_uses is None,
+/// the invitation can be used any number of times.
+pub async fn create_invitation(
+    pool: Pool,
+    group_id: GroupID,
+    creator: UserID,
+    expiry_time: Option<SystemTime>,
+    max_uses: Option<i32>
+) -> Result<InviteID, Error> {
     // This function is nearly identical to create_session
     let mut invite_id = generate_random_base64url(INVITE_ID_LENGTH);
 
     let conn = pool.get().await?;
     let stmt = conn.prepare("
-         INSERT INTO Invitation (invite_id, group_id, creation_time)
-         VALUES ($1, $2, NOW())
+         INSERT INTO Invitation (invite_id, group_id, creation_time, expiry_time, remaining_uses, creator)
+         VALUES ($1, $2, NOW(), $3, $4, $5)
          ON CONFLICT (invite_id) DO NOTHING
     ").await?;
 
-    while conn.execute(&stmt, &[&invite_id, &group_id]).await? == 0 {
+    while conn.execute(&stmt, &[&invite_id, &group_id, &expiry_time, &max_uses, &creator]).await? == 0 {
         invite_id = generate_random_base64url(INVITE_ID_LENGTH);
     }
 
     Ok(invite_id)
 }
 
-pub async fn invitation_group_id(pool: Pool, invite_id: InviteID)
+pub async fn invitation_group_id(pool: Pool, invite_id: &InviteID)
     -> Result<Option<GroupID>, Error>
 {
     // This function is nearly identical to session_user_id
@@ -45,31 +68,416 @@ pub async fn invitation_group_id(pool: Pool, invite_id: InviteID)
         SELECT group_id
         FROM Invitation
         WHERE invite_id = $1
-        AND creation_time > NOW() - ", creation_timeout!()
+        AND ", invitation_valid!()
     )).await?;
-    Ok(conn.query_opt(&stmt, &[&invite_id]).await?.map(|row| row.get(0)))
+    Ok(conn.query_opt(&stmt, &[invite_id]).await?.map(|row| row.get(0)))
+}
+
+/// Add a user to the group of an invitation as a member and use up one of the
+/// remaining uses of the invitation. The invitation isn't used up if the user
+/// is already a member of the group or has been banned from it.
+///
+/// Returns the group_id of the invitation if the invitation was valid.
+pub async fn accept_invitation(pool: Pool, invite_id: &InviteID, user_id: UserID)
+    -> Result<Option<GroupID>, Error>
+{
+    if invite_id.len() != INVITE_ID_LENGTH {
+        return Ok(None);
+    }
+
+    // The row lock taken by FOR UPDATE means that concurrent uses can't take
+    // the remaining uses below zero.
+    let conn = pool.get().await?;
+    let stmt = conn.prepare(concat!("
+        WITH Invite AS (
+            SELECT group_id
+            FROM Invitation
+            WHERE invite_id = $1
+            AND ", invitation_valid!(), "
+            FOR UPDATE
+        ), Joined AS (
+            INSERT INTO Membership (user_id, group_id, role, permissions)
+            SELECT $2, group_id, $3, $4
+            FROM Invite
+            WHERE NOT EXISTS (
+                SELECT 1
+                FROM Ban
+                WHERE user_id = $2
+                AND group_id = Invite.group_id
+            )
+            ON CONFLICT DO NOTHING
+            RETURNING group_id
+        ), Used AS (
+            UPDATE Invitation
+            SET remaining_uses = remaining_uses - 1
+            WHERE invite_id = $1
+            AND EXISTS (SELECT 1 FROM Joined)
+        )
+        SELECT group_id
+        FROM Invite
+    ")).await?;
+    let role = Role::Member;
+    Ok(conn.query_opt(&stmt, &[
+        invite_id, &user_id, &(role as i16), &role.permissions().0
+    ]).await?.map(|row| row.get(0)))
+}
+
+/// Get the usable invitations for a group.
+///
+/// If creator is Some then only the invitations created by that user are
+/// returned.
+pub async fn group_invitations(pool: Pool, group_id: GroupID, creator: Option<UserID>)
+    -> Result<Vec<Invitation>, Error>
+{
+    let conn = pool.get().await?;
+    let stmt = conn.prepare(concat!("
+        SELECT invite_id, COALESCE(creator, 0), creation_time, expiry_time, remaining_uses
+        FROM Invitation
+        WHERE group_id = $1
+        AND ($2::INTEGER IS NULL OR creator = $2)
+        AND ", invitation_valid!(), "
+        ORDER BY creation_time
+    ")).await?;
+    Ok(conn.query(&stmt, &[&group_id, &creator]).await?.iter().map(|row| Invitation {
+        invite_id: row.get(0),
+        creator: row.get(1),
+        creation_time: as_timestamp(row.get(2)),
+        expiry_time: row.get::<_, Option<SystemTime>>(3).map(as_timestamp),
+        remaining_uses: row.get(4),
+    }).collect())
+}
+
+/// Get the group and creator of an invitation, regardless of whether it is
+/// still usable.
+pub async fn invitation_info(pool: Pool, invite_id: &InviteID)
+    -> Result<Option<(GroupID, Option<UserID>)>, Error>
+{
+    if invite_id.len() != INVITE_ID_LENGTH {
+        return Ok(None);
+    }
+
+    let conn = pool.get().await?;
+    let stmt = conn.prepare("
+        SELECT group_id, creator
+        FROM Invitation
+        WHERE invite_id = $1
+    ").await?;
+    Ok(conn.query_opt(&stmt, &[invite_id]).await?.map(|row| (row.get(0), row.get(1))))
 }
 
-pub async fn join_group(pool: Pool, user_id: UserID, group_id: GroupID)
+/// Delete an invitation so that it can no longer be used.
+///
+/// Returns true if the invitation was actually deleted.
+pub async fn delete_invitation(pool: Pool, invite_id: &InviteID)
     -> Result<bool, Error>
 {
     let conn = pool.get().await?;
     let stmt = conn.prepare("
-        INSERT INTO Membership (user_id, group_id)
-        VALUES ($1, $2)
+        DELETE FROM Invitation
+        WHERE invite_id = $1
+    ").await?;
+    Ok(conn.execute(&stmt, &[invite_id]).await? > 0)
+}
+
+/// A set of actions that a member of a group is allowed to perform.
+///
+/// These bits are duplicated in the migrations that update
+/// Membership.permissions so they must never be renumbered. When a permission
+/// is added, add a migration that grants it to the roles that have it in
+/// Role::permissions.
+///
+/// | Bit | Value | Permission      | Roles         | Added in |
+/// |-----|-------|-----------------|---------------|----------|
+/// | 0   | 1     | CREATE_INVITE   | all           | 0003     |
+/// | 1   | 2     | MANAGE_CHANNELS | owner, admin  | 0003     |
+/// | 2   | 4     | MANAGE_MESSAGES | owner, admin  | 0003     |
+/// | 3   | 8     | MANAGE_GROUP    | owner, admin  | 0003     |
+/// | 4   | 16    | MANAGE_ROLES    | owner         | 0003     |
+/// | 5   | 32    | DELETE_GROUP    | owner         | 0003     |
+/// | 6   | 64    | KICK_MEMBERS    | owner, admin  | 0004     |
+/// | 7   | 128   | BAN_MEMBERS     | owner, admin  | 0004     |
+/// | 8   | 256   | MANAGE_INVITES  | owner, admin  | 0005     |
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub struct Permissions(i32);
+
+impl Permissions {
+    pub const CREATE_INVITE: Self = Self(1 << 0);
+    pub const MANAGE_CHANNELS: Self = Self(1 << 1);
+    pub const MANAGE_MESSAGES: Self = Self(1 << 2);
+    pub const MANAGE_GROUP: Self = Self(1 << 3);
+    pub const MANAGE_ROLES: Self = Self(1 << 4);
+    pub const DELETE_GROUP: Self = Self(1 << 5);
+    pub const KICK_MEMBERS: Self = Self(1 << 6);
+    pub const BAN_MEMBERS: Self = Self(1 << 7);
+    pub const MANAGE_INVITES: Self = Self(1 << 8);
+
+    pub fn contains(self, other: Self) -> bool {
+        self.0 & other.0 == other.0
+    }
+}
+
+impl std::ops::BitOr for Permissions {
+    type Output = Self;
+
+    fn bitor(self, other: Self) -> Self {
+        Self(self.0 | other.0)
+    }
+}
+
+// The discriminants are stored in Membership.role
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all="snake_case")]
+pub enum Role {
+    Owner = 0,
+    Admin = 1,
+    Member = 2,
+}
+
+impl Role {
+    pub fn from_i16(role: i16) -> Self {
+        match role {
+            0 => Role::Owner,
+            1 => Role::Admin,
+            _ => Role::Member,
+        }
+    }
+
+    /// Determine whether this role is higher than another role. A user can
+    /// only kick or ban users that they outrank.
+    pub fn outranks(self, other: Role) -> bool {
+        (self as i16) < (other as i16)
+    }
+
+    /// The permissions that are given to a user when they are assigned a role.
+    pub fn permissions(self) -> Permissions {
+        match self {
+            Role::Owner => Permissions::CREATE_INVITE
+                | Permissions::MANAGE_CHANNELS
+                | Permissions::MANAGE_MESSAGES
+                | Permissions::MANAGE_GROUP
+                | Permissions::MANAGE_ROLES
+                | Permissions::DELETE_GROUP
+                | Permissions::KICK_MEMBERS
+                | Permissions::BAN_MEMBERS
+                | Permissions::MANAGE_INVITES,
+            Role::Admin => Permissions::CREATE_INVITE
+                | Permissions::MANAGE_CHANNELS
+                | Permissions::MANAGE_MESSAGES
+                | Permissions::MANAGE_GROUP
+                | Permissions::KICK_MEMBERS
+                | Permissions::BAN_MEMBERS
+                | Permissions::MANAGE_INVITES,
+            Role::Member => Permissions::CREATE_INVITE,
+        }
+    }
+}
+
+/// Add a user to a group.
+///
+/// Returns false if the user is already a member or has been banned.
+pub async fn join_group(pool: Pool, user_id: UserID, group_id: GroupID, role: Role)
+    -> Result<bool, Error>
+{
+    let conn = pool.get().await?;
+    let stmt = conn.prepare("
+        INSERT INTO Membership (user_id, group_id, role, permissions)
+        SELECT $1, $2, $3, $4
+        WHERE NOT EXISTS (
+            SELECT 1
+            FROM Ban
+            WHERE user_id = $1
+            AND group_id = $2
+        )
         ON CONFLICT DO NOTHING;
     ").await?;
-    Ok(conn.execute(&stmt, &[&user_id, &group_id]).await? > 0)
+    Ok(conn.execute(&stmt, &[
+        &user_id, &group_id, &(role as i16), &role.permissions().0
+    ]).await? > 0)
+}
+
+pub struct Member {
+    pub role: Role,
+    pub permissions: Permissions,
+}
+
+pub async fn group_member(pool: Pool, user_id: UserID, group_id: GroupID)
+    -> Result<bool, Error>
+{
+    let conn = pool.get().await?;
+    let stmt = conn.prepare("
+        SELECT 1
+        FROM Membership
+        WHERE user_id = $1
+        AND group_id = $2
+    ").await?;
+    Ok(conn.query_opt(&stmt, &[&user_id, &group_id]).await?.is_some())
+}
+
+/// Get the role and permissions of a user within a group.
+///
+/// Returns Ok(None) if the user is not a member of the group.
+pub async fn group_membership(pool: Pool, user_id: UserID, group_id: GroupID)
+    -> Result<Option<Member>, Error>
+{
+    let conn = pool.get().await?;
+    let stmt = conn.prepare("
+        SELECT role, permissions
+        FROM Membership
+        WHERE user_id = $1
+        AND group_id = $2
+    ").await?;
+    Ok(conn.query_opt(&stmt, &[&user_id, &group_id]).await?.map(|row| Member {
+        role: Role::from_i16(row.get(0)),
+        permissions: Permissions(row.get(1)),
+    }))
+}
+
+/// Get the role of a user within a group.
+///
+/// Returns Ok(None) if the user is not a member of the group.
+pub async fn group_role(pool: Pool, user_id: UserID, group_id: GroupID)
+    -> Result<Option<Role>, Error>
+{
+    let conn = pool.get().await?;
+    let stmt = conn.prepare("
+        SELECT role
+        FROM Membership
+        WHERE user_id = $1
+        AND group_id = $2
+    ").await?;
+    Ok(conn.query_opt(&stmt, &[&user_id, &group_id]).await?.map(|row| {
+        Role::from_i16(row.get(0))
+    }))
+}
+
+/// Get the permissions that a user has within a group.
+///
+/// Returns Ok(None) if the user is not a member of the group.
+pub async fn group_permissions(pool: Pool, user_id: UserID, group_id: GroupID)
+    -> Result<Option<Permissions>, Error>
+{
+    let conn = pool.get().await?;
+    let stmt = conn.prepare("
+        SELECT permissions
+        FROM Membership
+        WHERE user_id = $1
+        AND group_id = $2
+    ").await?;
+    Ok(conn.query_opt(&stmt, &[&user_id, &group_id]).await?.map(|row| {
+        Permissions(row.get(0))
+    }))
 }
 
-pub async fn leave_group(pool: Pool, user_id: UserID, group_id: GroupID)
+/// Assign a role to a member of a group. This also resets their permissions
+/// to the permissions of the role.
+///
+/// Returns true if the user is a member of the group.
+pub async fn set_role(pool: Pool, user_id: UserID, group_id: GroupID, role: Role)
     -> Result<bool, Error>
 {
     let conn = pool.get().await?;
     let stmt = conn.prepare("
+        UPDATE Membership
+        SET role = $3, permissions = $4
+        WHERE user_id = $1
+        AND group_id = $2
+    ").await?;
+    Ok(conn.execute(&stmt, &[
+        &user_id, &group_id, &(role as i16), &role.permissions().0
+    ]).await? > 0)
+}
+
+/// Remove a user from a group and anonymize the messages that they wrote in
+/// it. If banned_by is Some then the user is also banned from the group so
+/// that they cannot rejoin.
+pub async fn remove_member(pool: Pool, user_id: UserID, group_id: GroupID, banned_by: Option<UserID>)
+    -> Result<(), Error>
+{
+    let mut conn = pool.get().await?;
+    let txn = conn.transaction().await?;
+
+    if let Some(banned_by) = banned_by {
+        txn.execute("
+            INSERT INTO Ban (user_id, group_id, banned_by, creation_time)
+            VALUES ($1, $2, $3, NOW())
+            ON CONFLICT DO NOTHING
+        ", &[&user_id, &group_id, &banned_by]).await?;
+    }
+
+    txn.execute("
         DELETE FROM Membership
         WHERE user_id = $1
         AND group_id = $2
+    ", &[&user_id, &group_id]).await?;
+
+    txn.execute("
+        UPDATE Message
+        SET author = NULL
+        WHERE author = $1
+        AND channel_id IN (
+            SELECT channel_id
+            FROM Channel
+            WHERE group_id = $2
+        )
+    ", &[&user_id, &group_id]).await?;
+
+    txn.commit().await?;
+    Ok(())
+}
+
+/// Determine whether a user is allowed to kick or ban another user from a
+/// group. The user must have the given permission and must outrank the target.
+///
+/// Returns Ok(None) if the target is not a member of the group.
+pub async fn can_remove_member(
+    pool: Pool,
+    user_id: UserID,
+    target_id: UserID,
+    group_id: GroupID,
+    permission: Permissions
+) -> Result<Option<bool>, Error> {
+    let (member, target) = futures::future::join(
+        group_membership(pool.clone(), user_id, group_id),
+        group_membership(pool, target_id, group_id)
+    ).await;
+
+    let target = match target? {
+        Some(target) => target,
+        None => return Ok(None)
+    };
+
+    Ok(Some(match member? {
+        Some(member) => member.permissions.contains(permission)
+            && member.role.outranks(target.role),
+        None => false
+    }))
+}
+
+/// Lift a ban so that the user can join the group again.
+///
+/// Returns true if the user was actually banned.
+pub async fn unban_user(pool: Pool, user_id: UserID, group_id: GroupID)
+    -> Result<bool, Error>
+{
+    let conn = pool.get().await?;
+    let stmt = conn.prepare("
+        DELETE FROM Ban
+        WHERE user_id = $1
+        AND group_id = $2
     ").await?;
     Ok(conn.execute(&stmt, &[&user_id, &group_id]).await? > 0)
 }
+
+/// Determine whether a user has been banned from a group.
+pub async fn banned(pool: Pool, user_id: UserID, group_id: GroupID)
+    -> Result<bool, Error>
+{
+    let conn = pool.get().await?;
+    let stmt = conn.prepare("
+        SELECT 1
+        FROM Ban
+        WHERE user_id = $1
+        AND group_id = $2
+    ").await?;
+    Ok(conn.query_opt(&stmt, &[&user_id, &group_id]).await?.is_some())
+}
@@ -0,0 +1,132 @@
+use serde::Serialize;
+use super::{Channel, ChannelID, GroupUser, MessageID, UserID};
+use deadpool_postgres::{Pool, PoolError, Transaction};
+use deadpool_postgres::tokio_postgres::Row;
+
+/// The maximum number of results returned by user_mentions.
+pub const MENTION_LIMIT: usize = 25;
+
+#[derive(Serialize, Default)]
+pub struct Mentions {
+    pub user_ids: Vec<UserID>,
+    pub channel_ids: Vec<ChannelID>,
+}
+
+fn is_word_char(ch: char) -> bool {
+    ch.is_alphanumeric() || ch == '_'
+}
+
+/// Find each occurrence of the sigil followed by one of the names. If more
+/// than one name matches, the longest one is used so that names containing
+/// spaces can be mentioned.
+fn find_mentions<T: Copy + PartialEq>(content: &str, sigil: char, names: &[(&str, T)], found: &mut Vec<T>) {
+    for (index, _) in content.match_indices(sigil) {
+        // The sigil must be at the start of a word so that something like an
+        // email address isn't treated as a mention.
+        if content[..index].chars().next_back().is_some_and(is_word_char) {
+            continue;
+        }
+
+        let rest = &content[index + sigil.len_utf8()..];
+        let longest = names.iter()
+            .filter(|(name, _)| {
+                rest.starts_with(name) && !rest[name.len()..].chars().next().is_some_and(is_word_char)
+            })
+            .max_by_key(|(name, _)| name.len());
+
+        if let Some((_, id)) = longest {
+            if !found.contains(id) {
+                found.push(*id);
+            }
+        }
+    }
+}
+
+/// Find the @user and #channel mentions in the content of a message.
+pub fn parse_mentions(content: &str, users: &[GroupUser], channels: &[Channel]) -> Mentions {
+    let mut mentions = Mentions::default();
+
+    let user_names = users.iter()
+        .map(|user| (user.name.as_str(), user.user_id))
+        .collect::<Vec<_>>();
+    find_mentions(content, '@', &user_names, &mut mentions.user_ids);
+
+    let channel_names = channels.iter()
+        .map(|channel| (channel.name.as_str(), channel.channel_id))
+        .collect::<Vec<_>>();
+    find_mentions(content, '#', &channel_names, &mut mentions.channel_ids);
+
+    mentions
+}
+
+/// Replace the mentions stored for a message. This is done within the
+/// transaction that creates or edits the message so that the two can't get out
+/// of sync.
+pub(super) async fn set_mentions(txn: &Transaction<'_>, message_id: MessageID, mentions: &Mentions)
+    -> Result<(), PoolError>
+{
+    txn.execute("DELETE FROM UserMention WHERE message_id = $1", &[&message_id]).await?;
+    txn.execute("DELETE FROM ChannelMention WHERE message_id = $1", &[&message_id]).await?;
+
+    if !mentions.user_ids.is_empty() {
+        txn.execute("
+            INSERT INTO UserMention (message_id, user_id)
+            SELECT $1, UNNEST($2::INTEGER[])
+        ", &[&message_id, &mentions.user_ids]).await?;
+    }
+
+    if !mentions.channel_ids.is_empty() {
+        txn.execute("
+            INSERT INTO ChannelMention (message_id, channel_id)
+            SELECT $1, UNNEST($2::INTEGER[])
+        ", &[&message_id, &mentions.channel_ids]).await?;
+    }
+
+    Ok(())
+}
+
+/// Get the mentions in a list of messages.
+///
+/// Each row is a message_id followed by either a user_id or a channel_id. The
+/// other column is NULL.
+pub async fn message_mentions(pool: Pool, message_ids: &[MessageID]) -> Result<Vec<Row>, PoolError> {
+    let conn = pool.get().await?;
+    let stmt = conn.prepare("
+        SELECT message_id, user_id, NULL::INTEGER
+        FROM UserMention
+        WHERE message_id = ANY($1)
+        UNION ALL
+        SELECT message_id, NULL::INTEGER, channel_id
+        FROM ChannelMention
+        WHERE message_id = ANY($1)
+    ").await?;
+    conn.query(&stmt, &[&message_ids]).await.map_err(|e| e.into())
+}
+
+/// Get the messages that mention a user across all of the groups that the
+/// user is a member of.
+///
+/// Results are ordered from newest to oldest. To get the next page of results,
+/// pass the message_id of the last result as before. Up to MENTION_LIMIT + 1
+/// rows are returned so that the caller can tell whether there are more. The
+/// last two columns are the channel_id and the group_id.
+pub async fn user_mentions(pool: Pool, user_id: UserID, before: Option<MessageID>)
+    -> Result<Vec<Row>, PoolError>
+{
+    let conn = pool.get().await?;
+    let stmt = conn.prepare("
+        SELECT Message.message_id, timestamp, COALESCE(author, 0), content, html, edited_at, reply_to,
+            Message.channel_id, Channel.group_id
+        FROM UserMention
+        JOIN Message ON Message.message_id = UserMention.message_id
+        JOIN Channel ON Channel.channel_id = Message.channel_id
+        JOIN Membership ON Membership.group_id = Channel.group_id
+            AND Membership.user_id = UserMention.user_id
+        WHERE UserMention.user_id = $1
+        AND ($2::INTEGER IS NULL OR Message.message_id < $2)
+        ORDER BY Message.message_id DESC
+        LIMIT $3
+    ").await?;
+    let limit = MENTION_LIMIT as i64 + 1;
+    conn.query(&stmt, &[&user_id, &before, &limit]).await.map_err(|e| e.into())
+}
@@ -1,23 +1,38 @@
-use super::{ChannelID, UserID};
+use super::{AttachmentID, ChannelID, GroupID, Mentions, UserID, set_mentions};
 use deadpool_postgres::{Pool, PoolError};
 use deadpool_postgres::tokio_postgres::Row;
 
 pub type MessageID = i32;
 
+/// The maximum number of messages returned by recent_messages, old_messages,
+/// new_messages and direct_messages.
+pub const MESSAGE_PAGE_LIMIT: usize = 50;
+
+/// The maximum number of results returned by search_messages.
+pub const SEARCH_LIMIT: usize = 25;
+
+/// The maximum number of messages returned by thread_messages.
+pub const THREAD_LIMIT: usize = 100;
+
+/// The number of messages on either side of the target message returned by
+/// messages_around.
+pub const AROUND_LIMIT: usize = 25;
+
 pub async fn recent_messages(pool: Pool, channel_id: ChannelID) -> Result<Vec<Row>, PoolError> {
     let conn = pool.get().await?;
     let stmt = conn.prepare("
-        SELECT message_id, timestamp, COALESCE(author, 0), content
+        SELECT message_id, timestamp, COALESCE(author, 0), content, html, edited_at, reply_to
         FROM (
             SELECT *
             FROM Message
             WHERE channel_id = $1
             ORDER BY message_id DESC
-            LIMIT 50
+            LIMIT $2
         ) Temp
         ORDER BY message_id ASC
     ").await?;
-    conn.query(&stmt, &[&channel_id]).await.map_err(|e| e.into())
+    let limit = MESSAGE_PAGE_LIMIT as i64;
+    conn.query(&stmt, &[&channel_id, &limit]).await.map_err(|e| e.into())
 }
 
 pub async fn old_messages(pool: Pool, channel_id: ChannelID, message_id: MessageID)
@@ -25,32 +40,338 @@ pub async fn old_messages(pool: Pool, channel_id: ChannelID, message_id: Message
 {
     let conn = pool.get().await?;
     let stmt = conn.prepare("
-        SELECT message_id, timestamp, COALESCE(author, 0), content
+        SELECT message_id, timestamp, COALESCE(author, 0), content, html, edited_at, reply_to
         FROM (
             SELECT *
             FROM Message
             WHERE channel_id = $1
             AND message_id < $2
             ORDER BY message_id DESC
-            LIMIT 50
+            LIMIT $3
         ) Temp
         ORDER BY message_id ASC
     ").await?;
-    conn.query(&stmt, &[&channel_id, &message_id]).await.map_err(|e| e.into())
+    let limit = MESSAGE_PAGE_LIMIT as i64;
+    conn.query(&stmt, &[&channel_id, &message_id, &limit]).await.map_err(|e| e.into())
 }
 
-pub async fn create_message(
-    pool: Pool,
-    time: std::time::SystemTime,
-    user_id: UserID,
-    content: &String,
-    channel_id: ChannelID
-) -> Result<MessageID, PoolError> {
+/// The opposite of old_messages. Get the messages that were sent after a
+/// particular message.
+pub async fn new_messages(pool: Pool, channel_id: ChannelID, message_id: MessageID)
+    -> Result<Vec<Row>, PoolError>
+{
     let conn = pool.get().await?;
     let stmt = conn.prepare("
-        INSERT INTO Message (timestamp, author, content, channel_id)
-        VALUES ($1, $2, $3, $4)
+        SELECT message_id, timestamp, COALESCE(author, 0), content, html, edited_at, reply_to
+        FROM Message
+        WHERE channel_id = $1
+        AND message_id > $2
+        ORDER BY message_id ASC
+        LIMIT $3
+    ").await?;
+    let limit = MESSAGE_PAGE_LIMIT as i64;
+    conn.query(&stmt, &[&channel_id, &message_id, &limit]).await.map_err(|e| e.into())
+}
+
+pub struct MessageWindow {
+    /// The messages in ascending order, including the target message.
+    pub messages: Vec<Row>,
+    /// Whether there are messages before the first message in the window.
+    pub more_before: bool,
+    /// Whether there are messages after the last message in the window.
+    pub more_after: bool,
+}
+
+/// Get up to n messages on either side of a message, along with the message
+/// itself.
+///
+/// Returns None if the message is not in the channel.
+pub async fn messages_around(pool: Pool, channel_id: ChannelID, message_id: MessageID, n: usize)
+    -> Result<Option<MessageWindow>, PoolError>
+{
+    let conn = pool.get().await?;
+    let before_stmt = conn.prepare("
+        SELECT message_id, timestamp, COALESCE(author, 0), content, html, edited_at, reply_to
+        FROM Message
+        WHERE channel_id = $1
+        AND message_id < $2
+        ORDER BY message_id DESC
+        LIMIT $3
+    ").await?;
+    let after_stmt = conn.prepare("
+        SELECT message_id, timestamp, COALESCE(author, 0), content, html, edited_at, reply_to
+        FROM Message
+        WHERE channel_id = $1
+        AND message_id >= $2
+        ORDER BY message_id ASC
+        LIMIT $3
+    ").await?;
+
+    // One extra row is fetched in each direction to determine whether there
+    // are more. The target message is the first row of after.
+    let limit = n as i64 + 1;
+    let mut after = conn.query(&after_stmt, &[&channel_id, &message_id, &(limit + 1)]).await?;
+    match after.first() {
+        Some(row) if row.get::<_, MessageID>(0) == message_id => {},
+        _ => return Ok(None),
+    }
+    let mut before = conn.query(&before_stmt, &[&channel_id, &message_id, &limit]).await?;
+
+    let more_before = before.len() > n;
+    let more_after = after.len() > n + 1;
+    before.truncate(n);
+    after.truncate(n + 1);
+    before.reverse();
+    before.append(&mut after);
+
+    Ok(Some(MessageWindow { messages: before, more_before, more_after }))
+}
+
+pub struct NewMessage<'a> {
+    pub time: std::time::SystemTime,
+    pub author: UserID,
+    pub content: &'a str,
+    /// The content rendered by markdown::render.
+    pub html: &'a str,
+    pub channel_id: ChannelID,
+    pub reply_to: Option<MessageID>,
+    pub mentions: &'a Mentions,
+    pub attachment_ids: &'a [AttachmentID],
+}
+
+pub enum CreateMessageResult {
+    Created(MessageID),
+    /// The message being replied to isn't in the same channel.
+    ReplyToInvalid,
+    /// One of the attachments wasn't uploaded by the author or already belongs
+    /// to a message.
+    AttachmentIdInvalid,
+}
+
+/// Create a message, optionally as a reply to another message, along with its
+/// mentions and attachments. Nothing is stored unless the whole message is
+/// valid.
+pub async fn create_message(pool: Pool, message: &NewMessage<'_>) -> Result<CreateMessageResult, PoolError> {
+    let mut conn = pool.get().await?;
+    let txn = conn.transaction().await?;
+    let stmt = txn.prepare("
+        INSERT INTO Message (timestamp, author, content, html, channel_id, reply_to)
+        SELECT $1, $2, $3, $4, $5, $6
+        WHERE $6::INTEGER IS NULL OR EXISTS (
+            SELECT 1
+            FROM Message
+            WHERE message_id = $6
+            AND channel_id = $5
+        )
         RETURNING message_id
     ").await?;
-    Ok(conn.query_one(&stmt, &[&time, &user_id, content, &channel_id]).await?.get(0))
+    let message_id: MessageID = match txn.query_opt(&stmt, &[
+        &message.time, &message.author, &message.content, &message.html, &message.channel_id, &message.reply_to
+    ]).await? {
+        Some(row) => row.get(0),
+        None => return Ok(CreateMessageResult::ReplyToInvalid)
+    };
+
+    set_mentions(&txn, message_id, message.mentions).await?;
+
+    // If another message claimed one of the attachments first then the row
+    // won't match so it isn't counted.
+    if !message.attachment_ids.is_empty() {
+        let attached = txn.execute("
+            UPDATE Attachment
+            SET message_id = $2
+            WHERE attachment_id = ANY($3)
+            AND uploader = $1
+            AND message_id IS NULL
+        ", &[&message.author, &message_id, &message.attachment_ids]).await?;
+        if attached as usize != message.attachment_ids.len() {
+            return Ok(CreateMessageResult::AttachmentIdInvalid);
+        }
+    }
+
+    txn.commit().await?;
+    Ok(CreateMessageResult::Created(message_id))
+}
+
+/// Get the thread that a message is part of.
+///
+/// The thread is the root message (the message that isn't a reply) and all of
+/// the replies to it, direct or indirect. The message must be in a channel
+/// within the given group. Up to THREAD_LIMIT + 1 rows are returned in
+/// ascending order so that the caller can tell whether there are more. The
+/// last column is the channel_id.
+pub async fn thread_messages(pool: Pool, group_id: GroupID, message_id: MessageID)
+    -> Result<Vec<Row>, PoolError>
+{
+    let conn = pool.get().await?;
+    let stmt = conn.prepare("
+        WITH RECURSIVE Ancestor AS (
+            SELECT message_id, reply_to
+            FROM Message
+            WHERE message_id = $1
+            AND channel_id IN (
+                SELECT channel_id
+                FROM Channel
+                WHERE group_id = $2
+            )
+        UNION ALL
+            SELECT Message.message_id, Message.reply_to
+            FROM Message
+            JOIN Ancestor ON Ancestor.reply_to = Message.message_id
+        ), Thread AS (
+            SELECT *
+            FROM Message
+            WHERE message_id IN (
+                SELECT message_id
+                FROM Ancestor
+                WHERE reply_to IS NULL
+            )
+        UNION ALL
+            SELECT Message.*
+            FROM Message
+            JOIN Thread ON Message.reply_to = Thread.message_id
+        )
+        SELECT message_id, timestamp, COALESCE(author, 0), content, html, edited_at, reply_to, channel_id
+        FROM Thread
+        ORDER BY message_id ASC
+        LIMIT $3
+    ").await?;
+    let limit = THREAD_LIMIT as i64 + 1;
+    conn.query(&stmt, &[&message_id, &group_id, &limit]).await.map_err(|e| e.into())
+}
+
+pub struct MessageEdit<'a> {
+    pub time: std::time::SystemTime,
+    pub author: UserID,
+    pub message_id: MessageID,
+    pub content: &'a str,
+    /// The content rendered by markdown::render.
+    pub html: &'a str,
+    pub mentions: &'a Mentions,
+    /// The link in the content found by unfurl::find_url.
+    pub url: Option<&'a str>,
+}
+
+/// Edit the content of a message.
+///
+/// Only the author of a message may edit it. The message must also be in a
+/// channel within the given group. The link preview is removed if it isn't a
+/// preview of the link in the new content.
+///
+/// Returns the channel_id of the message if it was actually edited, along with
+/// whether the message still has a link preview.
+pub async fn edit_message(pool: Pool, group_id: GroupID, edit: &MessageEdit<'_>)
+    -> Result<Option<(ChannelID, bool)>, PoolError>
+{
+    let mut conn = pool.get().await?;
+    let txn = conn.transaction().await?;
+    let stmt = txn.prepare("
+        UPDATE Message
+        SET content = $4, html = $5, edited_at = $6
+        WHERE message_id = $1
+        AND author = $2
+        AND channel_id IN (
+            SELECT channel_id
+            FROM Channel
+            WHERE group_id = $3
+        )
+        RETURNING channel_id
+    ").await?;
+    let channel_id: ChannelID = match txn.query_opt(&stmt, &[
+        &edit.message_id, &edit.author, &group_id, &edit.content, &edit.html, &edit.time
+    ]).await? {
+        Some(row) => row.get(0),
+        None => return Ok(None)
+    };
+
+    set_mentions(&txn, edit.message_id, edit.mentions).await?;
+
+    txn.execute("
+        DELETE FROM LinkPreview
+        WHERE message_id = $1
+        AND url IS DISTINCT FROM $2
+    ", &[&edit.message_id, &edit.url]).await?;
+    let has_preview: bool = txn.query_one("
+        SELECT EXISTS (
+            SELECT 1
+            FROM LinkPreview
+            WHERE message_id = $1
+        )
+    ", &[&edit.message_id]).await?.get(0);
+
+    txn.commit().await?;
+    Ok(Some((channel_id, has_preview)))
+}
+
+/// Get the channel that a message is in.
+///
+/// Returns None if the message is not in a channel within the given group.
+pub async fn message_channel(pool: Pool, group_id: GroupID, message_id: MessageID)
+    -> Result<Option<ChannelID>, PoolError>
+{
+    let conn = pool.get().await?;
+    let stmt = conn.prepare("
+        SELECT Message.channel_id
+        FROM Message
+        JOIN Channel ON Channel.channel_id = Message.channel_id
+        WHERE message_id = $1
+        AND group_id = $2
+    ").await?;
+    Ok(conn.query_opt(&stmt, &[&message_id, &group_id])
+        .await?
+        .map(|row| row.get(0)))
+}
+
+/// Delete a message.
+///
+/// If author is Some then the message will only be deleted if it was written
+/// by that user. The message must also be in a channel within the given group.
+///
+/// Returns the channel_id of the message if it was actually deleted.
+pub async fn delete_message(pool: Pool, author: Option<UserID>, group_id: GroupID, message_id: MessageID)
+    -> Result<Option<ChannelID>, PoolError>
+{
+    let conn = pool.get().await?;
+    let stmt = conn.prepare("
+        DELETE FROM Message
+        WHERE message_id = $1
+        AND ($2::INTEGER IS NULL OR author = $2)
+        AND channel_id IN (
+            SELECT channel_id
+            FROM Channel
+            WHERE group_id = $3
+        )
+        RETURNING channel_id
+    ").await?;
+    Ok(conn.query_opt(&stmt, &[&message_id, &author, &group_id])
+        .await?
+        .map(|row| row.get(0)))
+}
+
+/// Search for messages within a group, optionally restricted to one channel.
+///
+/// Results are ordered from newest to oldest. To get the next page of results,
+/// pass the message_id of the last result as before. Up to SEARCH_LIMIT + 1
+/// rows are returned so that the caller can tell whether there are more.
+pub async fn search_messages(
+    pool: Pool,
+    group_id: GroupID,
+    channel_id: Option<ChannelID>,
+    query: &str,
+    before: Option<MessageID>
+) -> Result<Vec<Row>, PoolError> {
+    let conn = pool.get().await?;
+    let stmt = conn.prepare("
+        SELECT message_id, timestamp, COALESCE(author, 0), content, html, edited_at, reply_to, Message.channel_id
+        FROM Message
+        JOIN Channel ON Channel.channel_id = Message.channel_id
+        WHERE Channel.group_id = $1
+        AND ($2::INTEGER IS NULL OR Message.channel_id = $2)
+        AND ($4::INTEGER IS NULL OR message_id < $4)
+        AND to_tsvector('english', content) @@ websearch_to_tsquery('english', $3)
+        ORDER BY message_id DESC
+        LIMIT $5
+    ").await?;
+    let limit = SEARCH_LIMIT as i64 + 1;
+    conn.query(&stmt, &[&group_id, &channel_id, &query, &before, &limit]).await.map_err(|e| e.into())
 }
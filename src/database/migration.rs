@@ -0,0 +1,198 @@
+use crate::error::Error;
+use deadpool_postgres::Pool;
+
+pub struct Migration {
+    pub version: i32,
+    pub name: &'static str,
+    sql: &'static str,
+}
+
+/// The list of migrations in the order that they must be applied.
+///
+/// A migration must never be modified after it has been deployed. To change
+/// the schema, append a new migration to the end of this list. The version
+/// number must match the number in the file name.
+const MIGRATIONS: &[Migration] = &[
+    Migration {
+        version: 1,
+        name: "initial",
+        sql: include_str!("../../migrations/0001_initial.sql"),
+    },
+    Migration {
+        version: 2,
+        name: "message_edited_at",
+        sql: include_str!("../../migrations/0002_message_edited_at.sql"),
+    },
+    Migration {
+        version: 3,
+        name: "membership_role",
+        sql: include_str!("../../migrations/0003_membership_role.sql"),
+    },
+    Migration {
+        version: 4,
+        name: "ban",
+        sql: include_str!("../../migrations/0004_ban.sql"),
+    },
+    Migration {
+        version: 5,
+        name: "invitation_management",
+        sql: include_str!("../../migrations/0005_invitation_management.sql"),
+    },
+    Migration {
+        version: 6,
+        name: "message_search",
+        sql: include_str!("../../migrations/0006_message_search.sql"),
+    },
+    Migration {
+        version: 7,
+        name: "message_reply_to",
+        sql: include_str!("../../migrations/0007_message_reply_to.sql"),
+    },
+    Migration {
+        version: 8,
+        name: "reaction",
+        sql: include_str!("../../migrations/0008_reaction.sql"),
+    },
+    Migration {
+        version: 9,
+        name: "direct_message",
+        sql: include_str!("../../migrations/0009_direct_message.sql"),
+    },
+    Migration {
+        version: 10,
+        name: "read_marker",
+        sql: include_str!("../../migrations/0010_read_marker.sql"),
+    },
+    Migration {
+        version: 11,
+        name: "presence",
+        sql: include_str!("../../migrations/0011_presence.sql"),
+    },
+    Migration {
+        version: 12,
+        name: "last_seen",
+        sql: include_str!("../../migrations/0012_last_seen.sql"),
+    },
+    Migration {
+        version: 13,
+        name: "pin",
+        sql: include_str!("../../migrations/0013_pin.sql"),
+    },
+    Migration {
+        version: 14,
+        name: "mention",
+        sql: include_str!("../../migrations/0014_mention.sql"),
+    },
+    Migration {
+        version: 15,
+        name: "attachment",
+        sql: include_str!("../../migrations/0015_attachment.sql"),
+    },
+    Migration {
+        version: 16,
+        name: "attachment_cleanup",
+        sql: include_str!("../../migrations/0016_attachment_cleanup.sql"),
+    },
+    Migration {
+        version: 17,
+        name: "avatar",
+        sql: include_str!("../../migrations/0017_avatar.sql"),
+    },
+    Migration {
+        version: 18,
+        name: "picture_index",
+        sql: include_str!("../../migrations/0018_picture_index.sql"),
+    },
+    Migration {
+        version: 19,
+        name: "message_html",
+        sql: include_str!("../../migrations/0019_message_html.sql"),
+    },
+    Migration {
+        version: 20,
+        name: "link_preview",
+        sql: include_str!("../../migrations/0020_link_preview.sql"),
+    },
+    Migration {
+        version: 21,
+        name: "link_preview_url",
+        sql: include_str!("../../migrations/0021_link_preview_url.sql"),
+    },
+];
+
+// Arbitrary key used with pg_advisory_xact_lock so that two servers starting at
+// the same time don't both try to apply the same migrations.
+const MIGRATION_LOCK: i64 = 0x43686174;
+
+/// The database has been migrated by a newer version of the server.
+#[derive(Debug)]
+pub struct SchemaTooNew {
+    pub database: i32,
+    pub latest: i32,
+}
+
+impl std::fmt::Display for SchemaTooNew {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(
+            f,
+            "Database schema version {} is newer than the latest known version {}",
+            self.database,
+            self.latest
+        )
+    }
+}
+
+/// Apply all pending migrations within a single transaction.
+///
+/// If dry_run is true, the migrations are applied and then rolled back so that
+/// errors in the SQL are still reported.
+///
+/// Returns the list of migrations that were (or would have been) applied.
+pub async fn migrate(pool: Pool, dry_run: bool) -> Result<Vec<&'static Migration>, Error> {
+    let mut conn = pool.get().await?;
+    let txn = conn.transaction().await?;
+
+    txn.execute("SELECT pg_advisory_xact_lock($1)", &[&MIGRATION_LOCK]).await?;
+
+    txn.batch_execute("
+        CREATE TABLE IF NOT EXISTS schema_version (
+            version INTEGER NOT NULL,
+            name TEXT NOT NULL,
+            applied_time TIMESTAMPTZ NOT NULL,
+
+            PRIMARY KEY (version)
+        )
+    ").await?;
+
+    let current: i32 = txn.query_one("
+        SELECT COALESCE(MAX(version), 0)
+        FROM schema_version
+    ", &[]).await?.get(0);
+
+    let latest = MIGRATIONS.last().map_or(0, |m| m.version);
+    if current > latest {
+        return Err(Error::Migration(SchemaTooNew { database: current, latest }));
+    }
+
+    let pending = MIGRATIONS.iter()
+        .filter(|m| m.version > current)
+        .collect::<Vec<_>>();
+
+    let insert = txn.prepare("
+        INSERT INTO schema_version (version, name, applied_time)
+        VALUES ($1, $2, NOW())
+    ").await?;
+
+    for migration in pending.iter() {
+        txn.batch_execute(migration.sql).await?;
+        txn.execute(&insert, &[&migration.version, &migration.name]).await?;
+    }
+
+    if dry_run {
+        txn.rollback().await?;
+    } else {
+        txn.commit().await?;
+    }
+
+    Ok(pending)
+}
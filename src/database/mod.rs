@@ -5,6 +5,15 @@ mod message;
 mod group;
 mod strings;
 mod membership;
+mod migration;
+mod reaction;
+mod conversation;
+mod read_marker;
+mod pin;
+mod mention;
+mod attachment;
+mod link_preview;
+mod avatar;
 
 pub use channel::*;
 pub use user::*;
@@ -13,3 +22,12 @@ pub use session::*;
 pub use group::*;
 pub use strings::*;
 pub use membership::*;
+pub use migration::*;
+pub use reaction::*;
+pub use conversation::*;
+pub use read_marker::*;
+pub use pin::*;
+pub use mention::*;
+pub use attachment::*;
+pub use link_preview::*;
+pub use avatar::*;
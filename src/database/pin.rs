@@ -0,0 +1,92 @@
+use super::{ChannelID, GroupID, MessageID, UserID};
+use deadpool_postgres::{Pool, PoolError};
+use deadpool_postgres::tokio_postgres::Row;
+
+/// The maximum number of messages that can be pinned in a channel.
+pub const MAX_PINS: usize = 50;
+
+pub enum PinResult {
+    Pinned,
+    AlreadyPinned,
+    LimitReached,
+}
+
+/// Pin a message to a channel unless MAX_PINS messages are already pinned.
+///
+/// Assumes that the message is in the channel.
+pub async fn pin_message(
+    pool: Pool,
+    time: std::time::SystemTime,
+    user_id: UserID,
+    channel_id: ChannelID,
+    message_id: MessageID
+) -> Result<PinResult, PoolError> {
+    let mut conn = pool.get().await?;
+    let txn = conn.transaction().await?;
+
+    // Locking the channel means that concurrent pins are counted one at a
+    // time so the limit can't be exceeded.
+    txn.execute("
+        SELECT 1
+        FROM Channel
+        WHERE channel_id = $1
+        FOR UPDATE
+    ", &[&channel_id]).await?;
+
+    let count: i64 = txn.query_one("
+        SELECT COUNT(*)
+        FROM Pin
+        WHERE channel_id = $1
+    ", &[&channel_id]).await?.get(0);
+
+    if count as usize >= MAX_PINS {
+        return Ok(PinResult::LimitReached);
+    }
+
+    let inserted = txn.execute("
+        INSERT INTO Pin (message_id, channel_id, pinned_by, pin_time)
+        VALUES ($1, $2, $3, $4)
+        ON CONFLICT DO NOTHING
+    ", &[&message_id, &channel_id, &user_id, &time]).await? > 0;
+
+    txn.commit().await?;
+
+    Ok(if inserted { PinResult::Pinned } else { PinResult::AlreadyPinned })
+}
+
+/// Unpin a message. The message must be in a channel within the given group.
+///
+/// Returns the channel_id of the message if it was actually unpinned.
+pub async fn unpin_message(pool: Pool, group_id: GroupID, message_id: MessageID)
+    -> Result<Option<ChannelID>, PoolError>
+{
+    let conn = pool.get().await?;
+    let stmt = conn.prepare("
+        DELETE FROM Pin
+        WHERE message_id = $1
+        AND channel_id IN (
+            SELECT channel_id
+            FROM Channel
+            WHERE group_id = $2
+        )
+        RETURNING channel_id
+    ").await?;
+    Ok(conn.query_opt(&stmt, &[&message_id, &group_id])
+        .await?
+        .map(|row| row.get(0)))
+}
+
+/// Get all of the pinned messages in a channel, most recently pinned first.
+///
+/// The columns are the same as recent_messages.
+pub async fn pinned_messages(pool: Pool, channel_id: ChannelID) -> Result<Vec<Row>, PoolError> {
+    let conn = pool.get().await?;
+    let stmt = conn.prepare("
+        SELECT Message.message_id, timestamp, COALESCE(author, 0), content, html, edited_at, reply_to
+        FROM Pin
+        JOIN Message ON Message.message_id = Pin.message_id
+        WHERE Pin.channel_id = $1
+        ORDER BY pin_time DESC
+    ").await?;
+    conn.query(&stmt, &[&channel_id]).await.map_err(|e| e.into())
+}
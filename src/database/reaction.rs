@@ -0,0 +1,90 @@
+use super::{MessageID, UserID};
+use deadpool_postgres::{Pool, PoolError};
+use deadpool_postgres::tokio_postgres::Row;
+
+/// The maximum number of different emoji that a user may react to a message
+/// with.
+pub const MAX_REACTIONS: usize = 20;
+
+pub enum ReactionResult {
+    Added,
+    AlreadyAdded,
+    LimitReached,
+}
+
+/// Add a reaction to a message unless the user has already reacted to it with
+/// MAX_REACTIONS different emoji.
+pub async fn add_reaction(pool: Pool, message_id: MessageID, user_id: UserID, emoji: &str)
+    -> Result<ReactionResult, PoolError>
+{
+    let conn = pool.get().await?;
+    // The outer query sees the table as it was before the insert so the second
+    // column tells whether the reaction already existed.
+    let stmt = conn.prepare("
+        WITH Inserted AS (
+            INSERT INTO Reaction (message_id, user_id, emoji, creation_time)
+            SELECT $1, $2, $3, NOW()
+            WHERE (
+                SELECT COUNT(*)
+                FROM Reaction
+                WHERE message_id = $1
+                AND user_id = $2
+            ) < $4
+            ON CONFLICT DO NOTHING
+            RETURNING 1
+        )
+        SELECT
+            EXISTS (SELECT 1 FROM Inserted),
+            EXISTS (
+                SELECT 1
+                FROM Reaction
+                WHERE message_id = $1
+                AND user_id = $2
+                AND emoji = $3
+            )
+    ").await?;
+    let limit = MAX_REACTIONS as i64;
+    let row = conn.query_one(&stmt, &[&message_id, &user_id, &emoji, &limit]).await?;
+    Ok(if row.get(0) {
+        ReactionResult::Added
+    } else if row.get(1) {
+        ReactionResult::AlreadyAdded
+    } else {
+        ReactionResult::LimitReached
+    })
+}
+
+/// Remove a reaction from a message.
+///
+/// Returns true if the reaction was removed.
+pub async fn remove_reaction(pool: Pool, message_id: MessageID, user_id: UserID, emoji: &str)
+    -> Result<bool, PoolError>
+{
+    let conn = pool.get().await?;
+    let stmt = conn.prepare("
+        DELETE FROM Reaction
+        WHERE message_id = $1
+        AND user_id = $2
+        AND emoji = $3
+    ").await?;
+    Ok(conn.execute(&stmt, &[&message_id, &user_id, &emoji]).await? > 0)
+}
+
+/// Get the reactions on a list of messages.
+///
+/// For each message, each emoji is listed once along with the number of users
+/// who reacted with it and whether the given user is one of them. Rows are
+/// ordered by message_id then by the time that the emoji was first used.
+pub async fn message_reactions(pool: Pool, user_id: UserID, message_ids: &[MessageID])
+    -> Result<Vec<Row>, PoolError>
+{
+    let conn = pool.get().await?;
+    let stmt = conn.prepare("
+        SELECT message_id, emoji, COUNT(*), BOOL_OR(user_id = $2)
+        FROM Reaction
+        WHERE message_id = ANY($1)
+        GROUP BY message_id, emoji
+        ORDER BY message_id, MIN(creation_time)
+    ").await?;
+    conn.query(&stmt, &[&message_ids, &user_id]).await.map_err(|e| e.into())
+}
@@ -0,0 +1,62 @@
+use serde::Serialize;
+use super::{ChannelID, GroupID, MessageID, UserID};
+use deadpool_postgres::{Pool, PoolError};
+
+/// Move a user's read marker in a channel forward to the given message.
+///
+/// Assumes that the message is in the channel.
+///
+/// Returns true if the marker moved. The marker never moves backwards.
+pub async fn mark_read(pool: Pool, user_id: UserID, channel_id: ChannelID, message_id: MessageID)
+    -> Result<bool, PoolError>
+{
+    let conn = pool.get().await?;
+    let stmt = conn.prepare("
+        INSERT INTO ReadMarker (user_id, channel_id, last_read_message_id)
+        VALUES ($1, $2, $3)
+        ON CONFLICT (user_id, channel_id) DO UPDATE
+        SET last_read_message_id = EXCLUDED.last_read_message_id
+        WHERE ReadMarker.last_read_message_id < EXCLUDED.last_read_message_id
+    ").await?;
+    Ok(conn.execute(&stmt, &[&user_id, &channel_id, &message_id]).await? > 0)
+}
+
+#[derive(Serialize)]
+pub struct ReadState {
+    pub channel_id: ChannelID,
+    /// 0 if the user hasn't read any messages in the channel.
+    pub last_read_message_id: MessageID,
+    /// The number of messages after the last read message. The user's own
+    /// messages are not counted.
+    pub unread_count: i64,
+}
+
+/// Get the read state of each channel in a group for a user.
+pub async fn read_states(pool: Pool, user_id: UserID, group_id: GroupID)
+    -> Result<Vec<ReadState>, PoolError>
+{
+    let conn = pool.get().await?;
+    let stmt = conn.prepare("
+        SELECT Channel.channel_id, COALESCE(ReadMarker.last_read_message_id, 0), (
+            SELECT COUNT(*)
+            FROM Message
+            WHERE Message.channel_id = Channel.channel_id
+            AND message_id > COALESCE(ReadMarker.last_read_message_id, 0)
+            AND author IS DISTINCT FROM $1
+        )
+        FROM Channel
+        LEFT JOIN ReadMarker ON ReadMarker.channel_id = Channel.channel_id
+            AND ReadMarker.user_id = $1
+        WHERE group_id = $2
+        ORDER BY Channel.channel_id
+    ").await?;
+    Ok(conn.query(&stmt, &[&user_id, &group_id])
+        .await?
+        .iter()
+        .map(|row| ReadState {
+            channel_id: row.get(0),
+            last_read_message_id: row.get(1),
+            unread_count: row.get(2),
+        })
+        .collect())
+}
@@ -3,6 +3,10 @@ pub const MAX_GROUP_NAME_LENGTH: usize = 32;
 pub const MAX_URL_LENGTH: usize = 2048;
 pub const MAX_USER_NAME_LENGTH: usize = 64;
 pub const MAX_MESSAGE_LENGTH: usize = 1024;
+pub const MAX_EMOJI_LENGTH: usize = 32;
+pub const MAX_STATUS_TEXT_LENGTH: usize = 128;
+pub const MAX_ATTACHMENT_NAME_LENGTH: usize = 255;
+pub const MAX_CONTENT_TYPE_LENGTH: usize = 255;
 
 pub fn valid_channel_name(name: &String) -> bool {
     // A byte limit instead of a character limit is tempting...
@@ -45,3 +49,28 @@ pub fn valid_user_name(name: &String) -> bool {
 pub fn valid_message(message: &String) -> bool {
     !message.is_empty() && within_char_limit(message, MAX_MESSAGE_LENGTH)
 }
+
+pub fn valid_status_text(text: &String) -> bool {
+    !text.is_empty() && within_char_limit(text, MAX_STATUS_TEXT_LENGTH)
+}
+
+pub fn valid_attachment_name(name: &String) -> bool {
+    !name.is_empty()
+        && within_char_limit(name, MAX_ATTACHMENT_NAME_LENGTH)
+        && !name.chars().any(|ch| ch == '/' || ch == '\\' || ch.is_control())
+}
+
+/// The content type is sent back in a header when the file is downloaded.
+pub fn valid_content_type(content_type: &str) -> bool {
+    !content_type.is_empty()
+        && content_type.len() <= MAX_CONTENT_TYPE_LENGTH
+        && content_type.bytes().all(|b| b.is_ascii_graphic() || b == b' ')
+}
+
+/// An emoji is either a sequence of unicode characters (which may be joined
+/// with ZWJ and modifiers) or the name of a custom emoji like :party:.
+pub fn valid_emoji(emoji: &String) -> bool {
+    !emoji.is_empty()
+        && within_char_limit(emoji, MAX_EMOJI_LENGTH)
+        && !emoji.chars().any(|ch| ch.is_whitespace() || ch.is_control())
+}
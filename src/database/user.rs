@@ -1,6 +1,8 @@
-use super::GroupID;
-use serde::Serialize;
+use super::{GroupID, Role};
+use serde::{Serialize, Deserialize};
 use crate::error::Error;
+use std::time::SystemTime;
+use crate::utils::as_timestamp;
 use deadpool_postgres::{Pool, PoolError};
 
 pub type UserID = i32;
@@ -9,15 +11,57 @@ pub type UserID = i32;
 pub struct User {
     pub user_id: UserID,
     pub name: String,
+    #[serde(serialize_with = "crate::proxy::serialize_picture")]
     pub picture: String,
 }
 
 #[derive(Serialize)]
 pub struct AnonUser {
     pub name: String,
+    #[serde(serialize_with = "crate::proxy::serialize_picture")]
     pub picture: String,
 }
 
+#[derive(Serialize)]
+pub struct GroupUser {
+    pub user_id: UserID,
+    pub name: String,
+    #[serde(serialize_with = "crate::proxy::serialize_picture")]
+    pub picture: String,
+    pub role: Role,
+    /// The time that the user was last connected. This is None if the user
+    /// hasn't been seen yet. It isn't serialized because it must only be sent
+    /// for users that are offline (see SocketContext::request_users).
+    #[serde(skip)]
+    pub last_seen: Option<u64>,
+}
+
+// The discriminants are stored in Usr.presence
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all="snake_case")]
+pub enum Presence {
+    Online = 0,
+    Away = 1,
+    DoNotDisturb = 2,
+    Invisible = 3,
+}
+
+impl Presence {
+    pub fn from_i16(presence: i16) -> Self {
+        match presence {
+            1 => Presence::Away,
+            2 => Presence::DoNotDisturb,
+            3 => Presence::Invisible,
+            _ => Presence::Online,
+        }
+    }
+}
+
+pub struct UserPresence {
+    pub presence: Presence,
+    pub status_text: Option<String>,
+}
+
 pub struct GoogleUser {
     pub google_id: String,
     pub name: String,
@@ -57,32 +101,88 @@ pub async fn user_id_from_google(pool: Pool, user: &GoogleUser) -> Result<UserID
     Ok(conn.query_one(&stmt, &[&user.google_id, &user.name, &user.picture]).await?.get(0))
 }
 
-pub async fn group_users(pool: Pool, group_id: GroupID) -> Result<Vec<User>, PoolError> {
+/// Get the presence that a user has chosen.
+///
+/// Returns the default presence if the user doesn't exist.
+pub async fn user_presence(pool: Pool, user_id: UserID) -> Result<UserPresence, Error> {
     let conn = pool.get().await?;
     let stmt = conn.prepare("
-        SELECT Usr.user_id, name, picture
+        SELECT presence, status_text
+        FROM Usr
+        WHERE user_id = $1
+    ").await?;
+    Ok(match conn.query_opt(&stmt, &[&user_id]).await? {
+        Some(row) => UserPresence {
+            presence: Presence::from_i16(row.get(0)),
+            status_text: row.get(1),
+        },
+        None => UserPresence {
+            presence: Presence::Online,
+            status_text: None,
+        }
+    })
+}
+
+pub async fn set_presence(pool: Pool, user_id: UserID, presence: Presence, status_text: &Option<String>)
+    -> Result<bool, Error>
+{
+    let conn = pool.get().await?;
+    let stmt = conn.prepare("
+        UPDATE Usr
+        SET presence = $2, status_text = $3
+        WHERE user_id = $1
+    ").await?;
+    Ok(conn.execute(&stmt, &[&user_id, &(presence as i16), status_text]).await? > 0)
+}
+
+pub async fn set_last_seen(pool: Pool, user_id: UserID, time: SystemTime) -> Result<bool, Error> {
+    let conn = pool.get().await?;
+    let stmt = conn.prepare("
+        UPDATE Usr
+        SET last_seen = $2
+        WHERE user_id = $1
+    ").await?;
+    Ok(conn.execute(&stmt, &[&user_id, &time]).await? > 0)
+}
+
+pub async fn group_users(pool: Pool, group_id: GroupID) -> Result<Vec<GroupUser>, PoolError> {
+    let conn = pool.get().await?;
+    let stmt = conn.prepare("
+        SELECT Usr.user_id, name, picture, role, last_seen
         FROM Usr
         JOIN Membership ON Membership.user_id = Usr.user_id
         WHERE Membership.group_id = $1
         ORDER BY Usr.user_id
     ").await?;
-    Ok(conn.query(&stmt, &[&group_id]).await?.iter().map(|row| User {
+    Ok(conn.query(&stmt, &[&group_id]).await?.iter().map(|row| GroupUser {
         user_id: row.get(0),
         name: row.get(1),
         picture: row.get(2),
+        role: Role::from_i16(row.get(3)),
+        last_seen: row.get::<_, Option<SystemTime>>(4).map(as_timestamp),
     }).collect())
 }
 
-pub async fn group_user_ids(pool: Pool, group_id: GroupID) -> Result<Vec<UserID>, PoolError> {
+/// Determine whether a URL is the picture of a user or a group, or the image of
+/// a link preview.
+pub async fn picture_in_use(pool: Pool, picture: &str) -> Result<bool, Error> {
     let conn = pool.get().await?;
     let stmt = conn.prepare("
-        SELECT Usr.user_id
-        FROM Usr
-        JOIN Membership ON Membership.user_id = Usr.user_id
-        WHERE Membership.group_id = $1
-        ORDER BY Usr.user_id
+        SELECT EXISTS (
+            SELECT 1
+            FROM Usr
+            WHERE picture = $1
+        ) OR EXISTS (
+            SELECT 1
+            FROM Groop
+            WHERE picture = $1
+        ) OR EXISTS (
+            SELECT 1
+            FROM LinkPreview
+            WHERE image = $1
+        )
     ").await?;
-    Ok(conn.query(&stmt, &[&group_id]).await?.iter().map(|row| row.get(0)).collect())
+    Ok(conn.query_one(&stmt, &[&picture]).await?.get(0))
 }
 
 pub async fn rename_user(pool: Pool, user_id: UserID, name: &String, picture: &String) -> Result<bool, Error> {
@@ -101,26 +201,19 @@ pub async fn rename_user(pool: Pool, user_id: UserID, name: &String, picture: &S
     Ok(conn.execute(&stmt, &[&user_id, name, picture]).await? > 0)
 }
 
+/// Delete a user. Returns false if the user owns a group because the group
+/// would be left without an owner.
 pub async fn delete_user(pool: Pool, user_id: UserID) -> Result<bool, Error> {
     let conn = pool.get().await?;
     let stmt = conn.prepare("
         DELETE FROM Usr
         WHERE user_id = $1
-    ").await?;
-    Ok(conn.execute(&stmt, &[&user_id]).await? > 0)
-}
-
-pub async fn anonymize_messages(pool: Pool, user_id: UserID, group_id: GroupID) -> Result<bool, Error> {
-    let conn = pool.get().await?;
-    let stmt = conn.prepare("
-        UPDATE Message
-        SET author = NULL
-        WHERE author = $1
-        AND channel_id IN (
-            SELECT channel_id
-            FROM Channel
-            WHERE group_id = $2
+        AND NOT EXISTS (
+            SELECT 1
+            FROM Membership
+            WHERE user_id = $1
+            AND role = $2
         )
     ").await?;
-    Ok(conn.execute(&stmt, &[&user_id, &group_id]).await? > 0)
+    Ok(conn.execute(&stmt, &[&user_id, &(Role::Owner as i16)]).await? > 0)
 }
@@ -3,6 +3,8 @@ pub type RequestError = reqwest::Error;
 pub type JWTError = jsonwebtoken::errors::Error;
 pub type HeaderError = headers::Error;
 pub type JSONError = serde_json::error::Error;
+pub type MigrationError = crate::database::SchemaTooNew;
+pub type StorageError = std::io::Error;
 
 #[derive(Debug)]
 pub enum Error {
@@ -10,7 +12,9 @@ pub enum Error {
     Request(RequestError),
     JWT(JWTError),
     Header(HeaderError),
-    JSON(JSONError)
+    JSON(JSONError),
+    Migration(MigrationError),
+    Storage(StorageError)
 }
 
 impl std::fmt::Display for Error {
@@ -20,7 +24,9 @@ impl std::fmt::Display for Error {
             Error::Request(e) => e.fmt(f),
             Error::JWT(e) => e.fmt(f),
             Error::Header(e) => e.fmt(f),
-            Error::JSON(e) => e.fmt(f)
+            Error::JSON(e) => e.fmt(f),
+            Error::Migration(e) => e.fmt(f),
+            Error::Storage(e) => e.fmt(f)
         }
     }
 }
@@ -74,3 +80,9 @@ impl From<JSONError> for Error {
         Error::JSON(e)
     }
 }
+
+impl From<StorageError> for Error {
+    fn from(e: StorageError) -> Error {
+        Error::Storage(e)
+    }
+}
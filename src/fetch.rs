@@ -0,0 +1,420 @@
+use std::future::Future;
+use std::net::IpAddr;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use std::time::Duration;
+use hyper::body::HttpBody;
+use hyper::client::HttpConnector;
+use hyper::client::connect::dns::Name;
+use hyper::header::{CONTENT_LENGTH, CONTENT_TYPE, LOCATION};
+use hyper_tls::HttpsConnector;
+use crate::config::FetchConfig;
+
+/// The maximum number of redirects that are followed.
+const MAX_REDIRECTS: usize = 5;
+
+#[derive(Debug, PartialEq)]
+pub enum FetchError {
+    /// The URL is invalid or it refers to a private address.
+    Refused,
+    /// The server couldn't be reached or it responded with an error.
+    Failed,
+    /// The response doesn't have an acceptable content type.
+    ContentType,
+    /// The response is larger than the limit.
+    TooLarge,
+}
+
+pub struct Fetched {
+    pub content_type: String,
+    pub data: Vec<u8>,
+}
+
+/// Determine whether an address is on the public internet. URLs supplied by
+/// users must not be able to reach the server itself or its local network.
+pub fn public_address(ip: IpAddr) -> bool {
+    match ip {
+        IpAddr::V4(ip) => {
+            let octets = ip.octets();
+            !(ip.is_private()
+                || ip.is_loopback()
+                || ip.is_link_local()
+                || ip.is_broadcast()
+                || ip.is_documentation()
+                || ip.is_unspecified()
+                || octets[0] == 0
+                // Shared address space (100.64.0.0/10)
+                || (octets[0] == 100 && (octets[1] & 0xC0) == 64)
+                // Multicast and reserved
+                || octets[0] >= 224)
+        }
+        IpAddr::V6(ip) => {
+            if let Some(ip) = ip.to_ipv4_mapped() {
+                return public_address(IpAddr::V4(ip));
+            }
+            let segments = ip.segments();
+            let first = segments[0];
+            !(ip.is_loopback()
+                || ip.is_unspecified()
+                || ip.is_multicast()
+                // IPv4-compatible (::/96)
+                || segments[..6] == [0; 6]
+                // NAT64 (64:ff9b::/96)
+                || segments[..6] == [0x64, 0xFF9B, 0, 0, 0, 0]
+                // 6to4 (2002::/16)
+                || first == 0x2002
+                // Unique local (fc00::/7)
+                || (first & 0xFE00) == 0xFC00
+                // Link local (fe80::/10)
+                || (first & 0xFFC0) == 0xFE80
+                // Site local (fec0::/10)
+                || (first & 0xFFC0) == 0xFEC0)
+        }
+    }
+}
+
+fn any_address(_: IpAddr) -> bool {
+    true
+}
+
+/// The host of a URL if it's an IP address.
+fn host_address(url: &reqwest::Url) -> Option<IpAddr> {
+    // IPv6 addresses are in brackets.
+    url.host_str()?.trim_start_matches('[').trim_end_matches(']').parse().ok()
+}
+
+#[derive(Debug)]
+enum ResolveError {
+    Lookup(std::io::Error),
+    /// None of the addresses are allowed.
+    Refused,
+}
+
+impl std::fmt::Display for ResolveError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            ResolveError::Lookup(e) => e.fmt(f),
+            ResolveError::Refused => write!(f, "Host only resolves to addresses that are not allowed"),
+        }
+    }
+}
+
+impl std::error::Error for ResolveError {}
+
+/// Resolves host names for the connector and leaves out the addresses that
+/// aren't allowed. The connector only connects to the addresses returned here
+/// so a name can't resolve to a different address after it's been checked.
+#[derive(Clone)]
+struct CheckedResolver {
+    allowed: fn(IpAddr) -> bool,
+}
+
+impl hyper::service::Service<Name> for CheckedResolver {
+    type Response = std::vec::IntoIter<IpAddr>;
+    type Error = ResolveError;
+    type Future = Pin<Box<dyn Future<Output = Result<Self::Response, Self::Error>> + Send>>;
+
+    fn poll_ready(&mut self, _: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn call(&mut self, name: Name) -> Self::Future {
+        let allowed = self.allowed;
+        Box::pin(async move {
+            let addrs = tokio::net::lookup_host((name.as_str(), 0)).await.map_err(ResolveError::Lookup)?;
+            let addrs = addrs.map(|addr| addr.ip()).filter(|&ip| allowed(ip)).collect::<Vec<_>>();
+            if addrs.is_empty() {
+                Err(ResolveError::Refused)
+            } else {
+                Ok(addrs.into_iter())
+            }
+        })
+    }
+}
+
+/// Determine whether a request failed because the host resolved to addresses
+/// that aren't allowed.
+fn refused_by_resolver(error: &hyper::Error) -> bool {
+    let mut source = std::error::Error::source(error);
+    while let Some(error) = source {
+        if let Some(ResolveError::Refused) = error.downcast_ref::<ResolveError>() {
+            return true;
+        }
+        source = error.source();
+    }
+    false
+}
+
+type Client = hyper::Client<HttpsConnector<HttpConnector<CheckedResolver>>>;
+
+/// Fetches URLs supplied by users. Unless private addresses are allowed, only
+/// public addresses are connected to. This applies to each redirect too.
+#[derive(Clone)]
+pub struct Fetcher {
+    client: Client,
+    allowed: fn(IpAddr) -> bool,
+    timeout: Duration,
+}
+
+impl Fetcher {
+    pub fn new(config: &FetchConfig) -> Self {
+        let allowed = if config.allow_private_addresses { any_address } else { public_address };
+        Self::with_filter(allowed, Duration::from_secs(config.timeout))
+    }
+
+    /// allowed decides whether an address may be connected to.
+    fn with_filter(allowed: fn(IpAddr) -> bool, timeout: Duration) -> Self {
+        let mut http = HttpConnector::new_with_resolver(CheckedResolver { allowed });
+        http.enforce_http(false);
+        let client = hyper::Client::builder().build(HttpsConnector::new_with_connector(http));
+        Self { client, allowed, timeout }
+    }
+
+    /// Fetch a URL with a GET request. accept is called with the media type
+    /// of the response (without any parameters) to decide whether to read the
+    /// body. Responses larger than max_size bytes are rejected.
+    pub async fn get<F>(&self, url: &str, accept: F, max_size: usize) -> Result<Fetched, FetchError>
+        where F: Fn(&str) -> bool
+    {
+        self.fetch(url, accept, max_size, false).await
+    }
+
+    /// Like get, but only the first max_size bytes of a larger response are
+    /// read instead of rejecting it.
+    pub async fn get_prefix<F>(&self, url: &str, accept: F, max_size: usize) -> Result<Fetched, FetchError>
+        where F: Fn(&str) -> bool
+    {
+        self.fetch(url, accept, max_size, true).await
+    }
+
+    async fn fetch<F>(&self, url: &str, accept: F, max_size: usize, truncate: bool) -> Result<Fetched, FetchError>
+        where F: Fn(&str) -> bool
+    {
+        let url = reqwest::Url::parse(url).map_err(|_| FetchError::Refused)?;
+        let fetch = self.fetch_url(url, accept, max_size, truncate);
+        tokio::time::timeout(self.timeout, fetch).await.unwrap_or(Err(FetchError::Failed))
+    }
+
+    /// Send a request, following any redirects.
+    async fn request(&self, mut url: reqwest::Url) -> Result<hyper::Response<hyper::Body>, FetchError> {
+        let mut redirects = 0;
+        loop {
+            if url.scheme() != "http" && url.scheme() != "https" {
+                return Err(FetchError::Refused);
+            }
+            // Addresses in the URL don't go through the resolver.
+            if host_address(&url).is_some_and(|ip| !(self.allowed)(ip)) {
+                return Err(FetchError::Refused);
+            }
+
+            let uri = url.as_str().parse::<hyper::Uri>().map_err(|_| FetchError::Refused)?;
+            let response = self.client.get(uri).await.map_err(|e| {
+                if refused_by_resolver(&e) {
+                    FetchError::Refused
+                } else {
+                    FetchError::Failed
+                }
+            })?;
+
+            if !response.status().is_redirection() {
+                return Ok(response);
+            }
+
+            redirects += 1;
+            if redirects > MAX_REDIRECTS {
+                return Err(FetchError::Failed);
+            }
+            let location = response.headers()
+                .get(LOCATION)
+                .and_then(|value| value.to_str().ok())
+                .ok_or(FetchError::Failed)?;
+            url = url.join(location).map_err(|_| FetchError::Refused)?;
+        }
+    }
+
+    async fn fetch_url<F>(&self, url: reqwest::Url, accept: F, max_size: usize, truncate: bool)
+        -> Result<Fetched, FetchError>
+        where F: Fn(&str) -> bool
+    {
+        let mut response = self.request(url).await?;
+
+        if !response.status().is_success() {
+            return Err(FetchError::Failed);
+        }
+
+        let content_type = response.headers()
+            .get(CONTENT_TYPE)
+            .and_then(|value| value.to_str().ok())
+            .and_then(|value| value.split(';').next())
+            .map(|value| value.trim().to_ascii_lowercase())
+            .unwrap_or_default();
+        if !accept(&content_type) {
+            return Err(FetchError::ContentType);
+        }
+
+        let content_length = response.headers()
+            .get(CONTENT_LENGTH)
+            .and_then(|value| value.to_str().ok())
+            .and_then(|value| value.parse::<u64>().ok());
+        if !truncate && content_length.is_some_and(|length| length > max_size as u64) {
+            return Err(FetchError::TooLarge);
+        }
+
+        let mut data = Vec::new();
+        while let Some(chunk) = response.body_mut().data().await {
+            let chunk = chunk.map_err(|_| FetchError::Failed)?;
+            if data.len() + chunk.len() > max_size {
+                if !truncate {
+                    return Err(FetchError::TooLarge);
+                }
+                data.extend_from_slice(&chunk[..max_size - data.len()]);
+                break;
+            }
+            data.extend_from_slice(&chunk);
+        }
+
+        Ok(Fetched { content_type, data })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::SocketAddr;
+    use std::time::Instant;
+    use warp::Filter;
+    use crate::testing::{self, Hits};
+
+    /// Only 127.0.0.2 may be connected to. The other loopback addresses stand
+    /// in for private addresses.
+    fn fetcher() -> Fetcher {
+        Fetcher::with_filter(|ip| ip == IpAddr::from([127, 0, 0, 2]), Duration::from_secs(1))
+    }
+
+    fn any(_: &str) -> bool {
+        true
+    }
+
+    /// A server on 127.0.0.1 that counts the requests it gets.
+    fn private_server() -> (SocketAddr, Hits) {
+        let hits = Hits::default();
+        let addr = testing::serve(hits.filter().map(|| "private"));
+        (addr, hits)
+    }
+
+    fn redirect(location: String) -> impl warp::Reply {
+        warp::reply::with_header(warp::http::StatusCode::FOUND, "Location", location)
+    }
+
+    #[test]
+    fn public_addresses() {
+        let public = ["1.1.1.1", "93.184.216.34", "2606:4700:4700::1111", "::ffff:1.1.1.1"];
+        for ip in public.iter() {
+            assert!(public_address(ip.parse().unwrap()), "{}", ip);
+        }
+
+        let private = [
+            "127.0.0.1", "10.0.0.1", "172.16.0.1", "192.168.1.1", "169.254.169.254", "100.64.0.1",
+            "0.0.0.0", "255.255.255.255", "224.0.0.1", "::1", "::", "::ffff:127.0.0.1",
+            "::127.0.0.1", "64:ff9b::7f00:1", "2002:7f00:1::", "fc00::1", "fe80::1", "fec0::1",
+        ];
+        for ip in private.iter() {
+            assert!(!public_address(ip.parse().unwrap()), "{}", ip);
+        }
+    }
+
+    #[tokio::test]
+    async fn allowed_address() {
+        let addr = testing::serve_at([127, 0, 0, 2], warp::path("page").map(|| "page"));
+        let fetched = fetcher().get(&format!("http://{}/page", addr), any, 1024).await.unwrap();
+        assert_eq!(fetched.data, b"page");
+        assert_eq!(fetched.content_type, "text/plain");
+    }
+
+    #[tokio::test]
+    async fn refuses_private_addresses() {
+        let (addr, hits) = private_server();
+        let fetcher = fetcher();
+
+        for url in [
+            format!("http://{}/", addr),
+            format!("http://localhost:{}/", addr.port()),
+            format!("http://[::1]:{}/", addr.port()),
+            format!("ftp://{}/", addr),
+        ].iter() {
+            assert_eq!(fetcher.get(url, any, 1024).await.err(), Some(FetchError::Refused), "{}", url);
+        }
+        assert_eq!(hits.count(), 0);
+    }
+
+    #[tokio::test]
+    async fn refuses_redirects_to_private_addresses() {
+        let (private, hits) = private_server();
+        let to_ip = warp::path("ip").map(move || redirect(format!("http://{}/", private)));
+        let to_name = warp::path("name").map(move || redirect(format!("http://localhost:{}/", private.port())));
+        let addr = testing::serve_at([127, 0, 0, 2], to_ip.or(to_name));
+        let fetcher = fetcher();
+
+        for path in ["ip", "name"].iter() {
+            let url = format!("http://{}/{}", addr, path);
+            assert_eq!(fetcher.get(&url, any, 1024).await.err(), Some(FetchError::Refused), "{}", url);
+        }
+        assert_eq!(hits.count(), 0);
+    }
+
+    #[tokio::test]
+    async fn follows_redirects() {
+        let page = warp::path("page").map(|| "page");
+        let relative = warp::path("relative").map(|| redirect("/page".to_owned()));
+        let loop_ = warp::path("loop").map(|| redirect("/loop".to_owned()));
+        let addr = testing::serve_at([127, 0, 0, 2], page.or(relative).or(loop_));
+        let fetcher = fetcher();
+
+        let fetched = fetcher.get(&format!("http://{}/relative", addr), any, 1024).await.unwrap();
+        assert_eq!(fetched.data, b"page");
+        let result = fetcher.get(&format!("http://{}/loop", addr), any, 1024).await;
+        assert_eq!(result.err(), Some(FetchError::Failed));
+    }
+
+    #[tokio::test]
+    async fn allow_private_addresses() {
+        let (addr, hits) = private_server();
+        let url = format!("http://localhost:{}/", addr.port());
+
+        let config = FetchConfig { allow_private_addresses: true, ..FetchConfig::default() };
+        let fetched = Fetcher::new(&config).get(&url, any, 1024).await.unwrap();
+        assert_eq!(fetched.data, b"private");
+        assert_eq!(hits.count(), 1);
+
+        let result = Fetcher::new(&FetchConfig::default()).get(&url, any, 1024).await;
+        assert_eq!(result.err(), Some(FetchError::Refused));
+        assert_eq!(hits.count(), 1);
+    }
+
+    #[tokio::test]
+    async fn timeout() {
+        let slow = warp::any().and_then(|| async {
+            tokio::time::delay_for(Duration::from_secs(5)).await;
+            Ok::<_, warp::Rejection>("slow")
+        });
+        let addr = testing::serve_at([127, 0, 0, 2], slow);
+
+        let start = Instant::now();
+        let result = fetcher().get(&format!("http://{}/", addr), any, 1024).await;
+        assert_eq!(result.err(), Some(FetchError::Failed));
+        assert!(start.elapsed() < Duration::from_secs(3));
+    }
+
+    #[tokio::test]
+    async fn size_limit() {
+        let addr = testing::serve_at([127, 0, 0, 2], warp::any().map(|| "0123456789"));
+        let url = format!("http://{}/", addr);
+        let fetcher = fetcher();
+
+        assert_eq!(fetcher.get(&url, any, 5).await.err(), Some(FetchError::TooLarge));
+        assert_eq!(fetcher.get_prefix(&url, any, 5).await.unwrap().data, b"01234");
+        assert_eq!(fetcher.get(&url, any, 10).await.unwrap().data, b"0123456789");
+        let html = |content_type: &str| content_type == "text/html";
+        assert_eq!(fetcher.get(&url, html, 10).await.err(), Some(FetchError::ContentType));
+    }
+}
@@ -5,7 +5,11 @@ use deadpool_postgres::Pool;
 use std::convert::Infallible;
 use crate::utils::cache_long;
 use super::{handlers, socket};
-use crate::database::{ChannelID, UserID, GroupID, InviteID, SessionID};
+use crate::fetch::Fetcher;
+use crate::storage::StorageRef;
+use crate::proxy::PictureCacheRef;
+use crate::config::GoogleConfigRef;
+use crate::database::{AttachmentID, ChannelID, UserID, GroupID, InviteID, SessionID};
 
 fn with_state<S: Clone + Send>(state: S) -> impl Filter<Extract = (S,), Error = Infallible> + Clone {
     warp::any().map(move || state.clone())
@@ -28,21 +32,23 @@ pub fn root(pool: Pool) -> impl Filter<Extract = impl warp::Reply, Error = warp:
         .recover(rejection)
 }
 
-pub fn login() -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
+pub fn login(google: GoogleConfigRef) -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
     warp::path!("login")
         .and(warp::get())
         .and(warp::query::<handlers::LoginQuery>())
+        .and(with_state(google))
         .and_then(handlers::login)
         .recover(rejection)
 }
 
-pub fn logout(pool: Pool, socket_ctx: socket::Context)
+pub fn logout(pool: Pool, socket_ctx: socket::Context, google: GoogleConfigRef)
     -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone
 {
     warp::path!("logout")
         .and(warp::get())
         .and(with_state(pool))
         .and(with_state(socket_ctx))
+        .and(with_state(google))
         .and(with_session_id())
         .and_then(handlers::logout)
         .recover(rejection)
@@ -57,22 +63,24 @@ pub fn channel(pool: Pool) -> impl Filter<Extract = impl warp::Reply, Error = wa
         .recover(rejection)
 }
 
-pub fn invite(pool: Pool) -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
+pub fn invite(pool: Pool, socket_ctx: socket::Context) -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
     warp::path!("invite" / InviteID)
         .and(warp::get())
         .and(with_session_id())
         .and(with_state(pool))
+        .and(with_state(socket_ctx))
         .and_then(handlers::accept_invite)
         .recover(rejection)
 }
 
-pub fn create_group(pool: Pool) -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
+pub fn create_group(pool: Pool, socket_ctx: socket::Context) -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
     warp::path!("api" / "group")
         .and(warp::post())
         .and(warp::cookie("session_id"))
         .and(warp::body::content_length_limit(handlers::CREATE_GROUP_LIMIT))
         .and(warp::body::json())
         .and(with_state(pool))
+        .and(with_state(socket_ctx))
         .and_then(handlers::create_group)
         .recover(rejection)
 }
@@ -98,6 +106,44 @@ pub fn create_invite(pool: Pool) -> impl Filter<Extract = impl warp::Reply, Erro
         .recover(rejection)
 }
 
+pub fn list_invites(pool: Pool) -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
+    warp::path!("api" / "invite" / GroupID)
+        .and(warp::get())
+        .and(warp::cookie("session_id"))
+        .and(with_state(pool))
+        .and_then(handlers::list_invites)
+        .recover(rejection)
+}
+
+pub fn delete_invite(pool: Pool) -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
+    warp::path!("api" / "invite" / InviteID)
+        .and(warp::delete())
+        .and(warp::cookie("session_id"))
+        .and(with_state(pool))
+        .and_then(handlers::delete_invite)
+        .recover(rejection)
+}
+
+pub fn create_conversation(pool: Pool) -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
+    warp::path!("api" / "conversation")
+        .and(warp::post())
+        .and(warp::cookie("session_id"))
+        .and(warp::body::content_length_limit(handlers::CREATE_CONVERSATION_LIMIT))
+        .and(warp::body::json())
+        .and(with_state(pool))
+        .and_then(handlers::create_conversation)
+        .recover(rejection)
+}
+
+pub fn list_conversations(pool: Pool) -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
+    warp::path!("api" / "conversation")
+        .and(warp::get())
+        .and(warp::cookie("session_id"))
+        .and(with_state(pool))
+        .and_then(handlers::list_conversations)
+        .recover(rejection)
+}
+
 pub fn leave_group(pool: Pool, socket_ctx: socket::Context) -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
     warp::path!("api" / "leave" / GroupID)
         .and(warp::post())
@@ -108,6 +154,35 @@ pub fn leave_group(pool: Pool, socket_ctx: socket::Context) -> impl Filter<Extra
         .recover(rejection)
 }
 
+pub fn kick_user(pool: Pool, socket_ctx: socket::Context) -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
+    warp::path!("api" / "kick" / GroupID / UserID)
+        .and(warp::post())
+        .and(warp::cookie("session_id"))
+        .and(with_state(pool))
+        .and(with_state(socket_ctx))
+        .and_then(handlers::kick_user)
+        .recover(rejection)
+}
+
+pub fn ban_user(pool: Pool, socket_ctx: socket::Context) -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
+    warp::path!("api" / "ban" / GroupID / UserID)
+        .and(warp::post())
+        .and(warp::cookie("session_id"))
+        .and(with_state(pool))
+        .and(with_state(socket_ctx))
+        .and_then(handlers::ban_user)
+        .recover(rejection)
+}
+
+pub fn unban_user(pool: Pool) -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
+    warp::path!("api" / "ban" / GroupID / UserID)
+        .and(warp::delete())
+        .and(warp::cookie("session_id"))
+        .and(with_state(pool))
+        .and_then(handlers::unban_user)
+        .recover(rejection)
+}
+
 pub fn user(pool: Pool) -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
     warp::path!("api" / "user" / UserID)
         .and(warp::get())
@@ -138,8 +213,59 @@ pub fn delete_user(pool: Pool, socket_ctx: socket::Context) -> impl Filter<Extra
         .recover(rejection)
 }
 
+pub fn upload_attachment(pool: Pool, storage: StorageRef) -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
+    warp::path!("api" / "attachment")
+        .and(warp::post())
+        .and(warp::cookie("session_id"))
+        .and(warp::multipart::form().max_length(handlers::UPLOAD_ATTACHMENT_LIMIT))
+        .and(with_state(pool))
+        .and(with_state(storage))
+        .and_then(handlers::upload_attachment)
+        .recover(rejection)
+}
+
+pub fn download_attachment(pool: Pool, storage: StorageRef) -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
+    warp::path!("api" / "attachment" / AttachmentID)
+        .and(warp::get())
+        .and(warp::cookie("session_id"))
+        .and(with_state(pool))
+        .and(with_state(storage))
+        .and_then(handlers::download_attachment)
+        .recover(rejection)
+}
+
+pub fn upload_avatar(pool: Pool, storage: StorageRef) -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
+    warp::path!("api" / "avatar")
+        .and(warp::post())
+        .and(warp::cookie("session_id"))
+        .and(warp::multipart::form().max_length(handlers::UPLOAD_AVATAR_LIMIT))
+        .and(with_state(pool))
+        .and(with_state(storage))
+        .and_then(handlers::upload_avatar)
+        .recover(rejection)
+}
+
+pub fn avatar(storage: StorageRef) -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
+    warp::path!("avatar" / u32 / String)
+        .and(warp::get())
+        .and(with_state(storage))
+        .and_then(handlers::avatar)
+        .recover(rejection)
+}
+
+pub fn picture(pool: Pool, fetcher: Fetcher, cache: PictureCacheRef) -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
+    warp::path!("picture")
+        .and(warp::get())
+        .and(warp::query::<handlers::PictureQuery>())
+        .and(with_state(pool))
+        .and(with_state(fetcher))
+        .and(with_state(cache))
+        .and_then(handlers::picture)
+        .recover(rejection)
+}
+
 pub fn socket(socket_ctx: socket::Context) -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
-    warp::path!("api" / "socket" / GroupID)
+    warp::path!("api" / "socket")
         .and(warp::ws())
         .and(warp::cookie("session_id"))
         .and(with_state(socket_ctx))
@@ -147,7 +273,7 @@ pub fn socket(socket_ctx: socket::Context) -> impl Filter<Extract = impl warp::R
         .recover(rejection)
 }
 
-pub fn auth_success(pool: Pool, client: reqwest::Client, cert_cache: handlers::CertificateCache)
+pub fn auth_success(pool: Pool, client: reqwest::Client, cert_cache: handlers::CertificateCache, google: GoogleConfigRef)
     -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone
 {
     warp::path!("api" / "auth")
@@ -156,6 +282,7 @@ pub fn auth_success(pool: Pool, client: reqwest::Client, cert_cache: handlers::C
         .and(with_state(pool))
         .and(with_state(client))
         .and(with_state(cert_cache))
+        .and(with_state(google))
         .and_then(handlers::auth_success)
         .recover(rejection)
 }
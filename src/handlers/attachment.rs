@@ -0,0 +1,197 @@
+use warp::Buf;
+use std::time::SystemTime;
+use futures::StreamExt;
+use crate::error::Error;
+use crate::database as db;
+use deadpool_postgres::Pool;
+use crate::storage::StorageRef;
+use serde::Serialize;
+
+#[derive(Serialize)]
+#[serde(tag="type")]
+#[serde(rename_all="snake_case")]
+enum Response {
+    Error { message: &'static str },
+    Success { attachment: db::Attachment },
+}
+
+// Leave some room for the multipart boundary and headers.
+pub const UPLOAD_ATTACHMENT_LIMIT: u64 = db::MAX_ATTACHMENT_SIZE as u64 + 4096;
+
+/// Content types that the browser is allowed to display instead of
+/// downloading. Anything else (HTML and SVG in particular) could run scripts
+/// on this origin.
+const INLINE_CONTENT_TYPES: &[&str] = &["image/png", "image/jpeg", "image/gif", "image/webp"];
+
+fn error_response(message: &'static str) -> Box<dyn warp::Reply> {
+    Box::new(warp::reply::json(
+        &Response::Error { message }
+    ))
+}
+
+/// Find the part of a form named "file".
+pub(super) async fn file_part(form: &mut warp::multipart::FormData) -> Option<warp::multipart::Part> {
+    while let Some(part) = form.next().await {
+        match part {
+            Ok(part) if part.name() == "file" => return Some(part),
+            Ok(_) => continue,
+            Err(_) => return None
+        }
+    }
+    None
+}
+
+/// Read the contents of a part of a form.
+pub(super) async fn part_data(part: &mut warp::multipart::Part) -> Option<Vec<u8>> {
+    let mut data = Vec::new();
+    while let Some(chunk) = part.data().await {
+        data.extend_from_slice(chunk.ok()?.bytes());
+    }
+    Some(data)
+}
+
+/// Some browsers send the full path of the file.
+fn base_name(path: &str) -> &str {
+    path.rsplit(['/', '\\']).next().unwrap_or("")
+}
+
+/// Create a Content-Disposition header value. The plain filename parameter is
+/// an ASCII fallback for the RFC 5987 encoded filename* parameter.
+fn content_disposition(disposition: &str, name: &str) -> String {
+    let mut ascii = String::new();
+    let mut encoded = String::new();
+    for ch in name.chars() {
+        if ch.is_ascii_graphic() && ch != '"' && ch != '\\' {
+            ascii.push(ch);
+        } else {
+            ascii.push('_');
+        }
+    }
+    for byte in name.bytes() {
+        if byte.is_ascii_alphanumeric() || b"!#$&+-.^_`|~".contains(&byte) {
+            encoded.push(byte as char);
+        } else {
+            encoded.push_str(&format!("%{:02X}", byte));
+        }
+    }
+    format!("{}; filename=\"{}\"; filename*=UTF-8''{}", disposition, ascii, encoded)
+}
+
+/// Upload a file that can be attached to a message. The file is the part of
+/// the form named "file".
+pub async fn upload_attachment(session_id: db::SessionID, mut form: warp::multipart::FormData, pool: Pool, storage: StorageRef)
+    -> Result<Box<dyn warp::Reply>, warp::Rejection>
+{
+    let user_id = match db::session_user_id(pool.clone(), &session_id).await? {
+        Some(id) => id,
+        None => return Ok(Box::new(warp::http::StatusCode::UNAUTHORIZED))
+    };
+
+    let mut part = match file_part(&mut form).await {
+        Some(part) => part,
+        None => return Ok(error_response("file_missing"))
+    };
+
+    let name = base_name(part.filename().unwrap_or("")).to_owned();
+    if !db::valid_attachment_name(&name) {
+        return Ok(error_response("name_invalid"));
+    }
+
+    let content_type = part.content_type().unwrap_or("application/octet-stream").to_owned();
+    if !db::valid_content_type(&content_type) {
+        return Ok(error_response("content_type_invalid"));
+    }
+
+    let data = match part_data(&mut part).await {
+        Some(data) => data,
+        None => return Ok(error_response("file_missing"))
+    };
+
+    if data.is_empty() {
+        return Ok(error_response("file_empty"));
+    }
+    if data.len() > db::MAX_ATTACHMENT_SIZE {
+        return Ok(error_response("file_too_large"));
+    }
+
+    let size = data.len();
+    let storage_key = db::generate_storage_key();
+    storage.put(&storage_key, data).await.map_err(Error::from)?;
+
+    let result = db::create_attachment(
+        pool, SystemTime::now(), user_id, &name, &content_type, size, &storage_key
+    ).await;
+
+    // Don't leave behind a file that nothing refers to.
+    let attachment_id = match result {
+        Ok(Some(id)) => id,
+        Ok(None) => {
+            storage.delete(&storage_key).await.map_err(Error::from)?;
+            return Ok(error_response("quota_exceeded"));
+        },
+        Err(e) => {
+            storage.delete(&storage_key).await.map_err(Error::from)?;
+            return Err(e.into());
+        }
+    };
+
+    Ok(Box::new(warp::reply::json(&Response::Success {
+        attachment: db::Attachment {
+            attachment_id,
+            name,
+            content_type,
+            size: size as i32,
+        }
+    })))
+}
+
+/// Download an attachment. Attachments on a message can be downloaded by the
+/// members of the group. Attachments that haven't been added to a message yet
+/// can only be downloaded by the uploader.
+pub async fn download_attachment(attachment_id: db::AttachmentID, session_id: db::SessionID, pool: Pool, storage: StorageRef)
+    -> Result<Box<dyn warp::Reply>, warp::Rejection>
+{
+    let user_id = match db::session_user_id(pool.clone(), &session_id).await? {
+        Some(id) => id,
+        None => return Ok(Box::new(warp::http::StatusCode::UNAUTHORIZED))
+    };
+
+    let file = match db::attachment_file(pool.clone(), attachment_id).await? {
+        Some(file) => file,
+        None => return Ok(Box::new(warp::http::StatusCode::NOT_FOUND))
+    };
+
+    let allowed = match (file.message_id, file.group_id) {
+        (None, _) => file.uploader == Some(user_id),
+        (Some(_), Some(group_id)) => db::group_member(pool, user_id, group_id).await?,
+        (Some(_), None) => false,
+    };
+
+    // Not revealing whether the attachment exists.
+    if !allowed {
+        return Ok(Box::new(warp::http::StatusCode::NOT_FOUND));
+    }
+
+    let data = match storage.get(&file.storage_key).await.map_err(Error::from)? {
+        Some(data) => data,
+        None => return Ok(Box::new(warp::http::StatusCode::NOT_FOUND))
+    };
+
+    let disposition = if INLINE_CONTENT_TYPES.contains(&file.content_type.as_str()) {
+        "inline"
+    } else {
+        "attachment"
+    };
+
+    let response = warp::http::Response::builder()
+        .header("Content-Type", file.content_type.as_str())
+        .header("Content-Disposition", content_disposition(disposition, &file.name))
+        .header("X-Content-Type-Options", "nosniff")
+        .header("Cache-Control", "private,max-age=604800,immutable")
+        .body(data);
+
+    match response {
+        Ok(response) => Ok(Box::new(response)),
+        Err(_) => Ok(Box::new(warp::http::StatusCode::INTERNAL_SERVER_ERROR))
+    }
+}
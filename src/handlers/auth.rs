@@ -2,6 +2,7 @@ use log::error;
 use crate::error::Error;
 use crate::database as db;
 use deadpool_postgres::Pool;
+use crate::config::{GoogleConfig, GoogleConfigRef};
 use jsonwebtoken::errors::Error as JWTError;
 use jsonwebtoken::errors::ErrorKind as JWTErrorKind;
 
@@ -16,7 +17,8 @@ use jsonwebtoken::{decode, decode_header, Algorithm, Validation, DecodingKey};
 Full explanation
 https://developers.google.com/identity/protocols/oauth2/web-server#httprest_5
 
-The authentication flow starts when the user clicks a link:
+The authentication flow starts when the user clicks a link (the client_id and
+redirect_uri come from the google section of the config):
 https://accounts.google.com/o/oauth2/v2/auth?
   client_id=xxx.apps.googleusercontent.com&
   redirect_uri=https://localhost/api/auth&
@@ -57,12 +59,12 @@ pub struct AuthFail {
 }
 
 #[derive(Serialize)]
-struct TokenRequest {
-    client_id: &'static str,
-    client_secret: &'static str,
+struct TokenRequest<'a> {
+    client_id: &'a str,
+    client_secret: &'a str,
     code: String,
     grant_type: &'static str,
-    redirect_uri: &'static str,
+    redirect_uri: &'a str,
 }
 
 #[derive(Deserialize)]
@@ -75,15 +77,15 @@ struct TokenResponse {
     // refresh_token: String,
 }
 
-async fn request_id_token(client: &reqwest::Client, authorization_code: String)
+async fn request_id_token(client: &reqwest::Client, google: &GoogleConfig, authorization_code: String)
     -> Result<TokenResponse, Error>
 {
     let request = TokenRequest {
-        client_id: include_str!("../../api/client_id.txt"),
-        client_secret: include_str!("../../api/client_secret.txt"),
+        client_id: google.client_id.as_str(),
+        client_secret: google.client_secret.as_str(),
         code: authorization_code,
         grant_type: "authorization_code",
-        redirect_uri: "https://localhost/api/auth"
+        redirect_uri: google.redirect_uri.as_str()
     };
     Ok(client.post("https://oauth2.googleapis.com/token")
         .form(&request)
@@ -160,7 +162,7 @@ pub struct Claims {
     pub family_name: String,
 }
 
-fn decode_id_token(certs: &Certs, id_token: &str) -> Result<Claims, Error> {
+fn decode_id_token(certs: &Certs, client_id: &str, id_token: &str) -> Result<Claims, Error> {
     let header = decode_header(id_token)?;
 
     // The header contains a kid (key ID) field that identifies the key to use
@@ -179,7 +181,7 @@ fn decode_id_token(certs: &Certs, id_token: &str) -> Result<Claims, Error> {
     for cert in certs.keys.iter() {
         if cert.kid == header_kid {
             let mut validation = Validation::new(Algorithm::RS256);
-            validation.set_audience(&[include_str!("../../api/client_id.txt")]);
+            validation.set_audience(&[client_id]);
             let key = DecodingKey::from_rsa_components(&cert.n, &cert.e);
             let token_data = decode::<Claims>(id_token, &key, &validation)?;
 
@@ -197,16 +199,16 @@ fn decode_id_token(certs: &Certs, id_token: &str) -> Result<Claims, Error> {
     Err(JWTError::from(JWTErrorKind::InvalidAlgorithmName).into())
 }
 
-pub async fn auth_success(res: AuthSuccess, pool: Pool, client: reqwest::Client, cache: CertificateCache)
+pub async fn auth_success(res: AuthSuccess, pool: Pool, client: reqwest::Client, cache: CertificateCache, google: GoogleConfigRef)
     -> Result<impl warp::Reply, warp::Rejection>
 {
     if res.scope != "profile https://www.googleapis.com/auth/userinfo.profile" {
         return Err(warp::reject::not_found());
     }
-    let token = request_id_token(&client, res.code).await?;
+    let token = request_id_token(&client, &google, res.code).await?;
     let mut certs = cache.lock().await;
     update_cert_cache(&client, &mut *certs).await?;
-    let claims = decode_id_token(&certs, token.id_token.as_str())?;
+    let claims = decode_id_token(&certs, google.client_id.as_str(), token.id_token.as_str())?;
 
     let user = db::GoogleUser {
         google_id: claims.sub,
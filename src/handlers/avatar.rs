@@ -0,0 +1,89 @@
+use crate::avatar;
+use crate::error::Error;
+use crate::database as db;
+use deadpool_postgres::Pool;
+use crate::storage::StorageRef;
+use crate::utils::cache_long;
+use super::{file_part, part_data};
+use serde::Serialize;
+use std::time::SystemTime;
+
+#[derive(Serialize)]
+#[serde(tag="type")]
+#[serde(rename_all="snake_case")]
+enum Response {
+    Error { message: &'static str },
+    Success { picture: String },
+}
+
+// Leave some room for the multipart boundary and headers.
+pub const UPLOAD_AVATAR_LIMIT: u64 = avatar::MAX_AVATAR_FILE_SIZE as u64 + 4096;
+
+fn error_response(message: &'static str) -> Box<dyn warp::Reply> {
+    Box::new(warp::reply::json(
+        &Response::Error { message }
+    ))
+}
+
+/// Upload a picture for a user or a group. The picture is the part of the form
+/// named "file". The response contains a URL that can be used as the picture
+/// when renaming a user or when creating or renaming a group.
+pub async fn upload_avatar(session_id: db::SessionID, mut form: warp::multipart::FormData, pool: Pool, storage: StorageRef)
+    -> Result<Box<dyn warp::Reply>, warp::Rejection>
+{
+    let user_id = match db::session_user_id(pool.clone(), &session_id).await? {
+        Some(id) => id,
+        None => return Ok(Box::new(warp::http::StatusCode::UNAUTHORIZED))
+    };
+
+    let data = match file_part(&mut form).await {
+        Some(mut part) => part_data(&mut part).await,
+        None => None
+    };
+    let data = match data {
+        Some(data) if !data.is_empty() => data,
+        _ => return Ok(error_response("file_missing"))
+    };
+
+    if data.len() > avatar::MAX_AVATAR_FILE_SIZE {
+        return Ok(error_response("file_too_large"));
+    }
+
+    let resized = match tokio::task::spawn_blocking(move || avatar::resize_avatar(&data)).await {
+        Ok(Ok(resized)) => resized,
+        Ok(Err(avatar::AvatarError::TooLarge)) => return Ok(error_response("image_too_large")),
+        Ok(Err(avatar::AvatarError::Invalid)) => return Ok(error_response("image_invalid")),
+        Err(_) => return Ok(Box::new(warp::http::StatusCode::INTERNAL_SERVER_ERROR))
+    };
+
+    let key = avatar::generate_avatar_key();
+    if !db::create_avatar(pool, SystemTime::now(), user_id, &key).await? {
+        return Ok(error_response("quota_exceeded"));
+    }
+    for (size, data) in resized {
+        storage.put(&avatar::storage_key(&key, size), data).await.map_err(Error::from)?;
+    }
+
+    Ok(Box::new(warp::reply::json(&Response::Success {
+        picture: avatar::avatar_url(&key, avatar::DEFAULT_AVATAR_SIZE)
+    })))
+}
+
+/// Pictures are never modified after they're uploaded so they can be cached
+/// for a long time.
+pub async fn avatar(size: u32, key: String, storage: StorageRef)
+    -> Result<Box<dyn warp::Reply>, warp::Rejection>
+{
+    if !avatar::AVATAR_SIZES.contains(&size) || !avatar::valid_avatar_key(&key) {
+        return Ok(Box::new(warp::http::StatusCode::NOT_FOUND));
+    }
+
+    let data = match storage.get(&avatar::storage_key(&key, size)).await.map_err(Error::from)? {
+        Some(data) => data,
+        None => return Ok(Box::new(warp::http::StatusCode::NOT_FOUND))
+    };
+
+    let reply = warp::reply::with_header(data, "Content-Type", "image/png");
+    let reply = warp::reply::with_header(reply, "X-Content-Type-Options", "nosniff");
+    Ok(Box::new(cache_long(reply)))
+}
@@ -2,6 +2,7 @@ use askama::Template;
 use serde::Serialize;
 use crate::database as db;
 use deadpool_postgres::Pool;
+use crate::proxy::proxy_url;
 
 #[derive(Template)]
 #[template(path = "channel.html")]
@@ -14,6 +15,7 @@ struct ChannelTemplate {
     user_list: String,
     group_list: String,
     channel_list: String,
+    read_states: String,
     url: String,
 }
 
@@ -35,7 +37,7 @@ pub async fn channel(mut group_id: db::GroupID, mut channel_id: db::ChannelID, s
     let group_list = db::user_groups(pool.clone(), user.user_id).await?;
 
     if group_list.is_empty() {
-        let preload_images = vec![user.picture.clone()];
+        let preload_images = vec![proxy_url(&user.picture)];
         let user_id = user.user_id;
         let user_list = vec![user];
         return Ok(Box::new(ChannelTemplate {
@@ -47,6 +49,7 @@ pub async fn channel(mut group_id: db::GroupID, mut channel_id: db::ChannelID, s
             user_list: ser_json(&user_list),
             group_list: "[]".to_owned(),
             channel_list: "[]".to_owned(),
+            read_states: "[]".to_owned(),
             url: "/channel/0/0".to_owned(),
         }))
     }
@@ -60,14 +63,16 @@ pub async fn channel(mut group_id: db::GroupID, mut channel_id: db::ChannelID, s
         }
     };
 
-    let (channel_list, user_list) = futures::future::join(
+    let (channel_list, user_list, read_states) = futures::future::join3(
         db::group_channels(pool.clone(), group_id),
-        db::group_users(pool.clone(), group_id)
+        db::group_users(pool.clone(), group_id),
+        db::read_states(pool.clone(), user.user_id, group_id)
     ).await;
 
     // The channel list cannot be empty
     let channel_list = channel_list?;
     let user_list = user_list.map_err(|e| crate::error::Error::Database(e))?;
+    let read_states = read_states.map_err(crate::error::Error::Database)?;
 
     let channel_name = match channel_list.iter().find(|c| c.channel_id == channel_id) {
         Some(channel) => channel.name.as_str(),
@@ -79,10 +84,10 @@ pub async fn channel(mut group_id: db::GroupID, mut channel_id: db::ChannelID, s
 
     let mut preload_images = Vec::new();
     for group in group_list.iter() {
-        preload_images.push(group.picture.clone());
+        preload_images.push(proxy_url(&group.picture));
     }
     for other_user in user_list.iter() {
-        preload_images.push(other_user.picture.clone());
+        preload_images.push(proxy_url(&other_user.picture));
     }
 
     Ok(Box::new(ChannelTemplate {
@@ -94,6 +99,7 @@ pub async fn channel(mut group_id: db::GroupID, mut channel_id: db::ChannelID, s
         user_list: ser_json(&user_list),
         group_list: ser_json(&group_list),
         channel_list: ser_json(&channel_list),
+        read_states: ser_json(&read_states),
         url: format!("/channel/{}/{}", group_id, channel_id),
     }))
 }
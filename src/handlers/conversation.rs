@@ -0,0 +1,68 @@
+use crate::database as db;
+use deadpool_postgres::Pool;
+use serde::{Serialize, Deserialize};
+
+#[derive(Serialize)]
+#[serde(tag="type")]
+#[serde(rename_all="snake_case")]
+enum Response {
+    Error { message: &'static str },
+    Success { conversation_id: db::ConversationID },
+}
+
+#[derive(Deserialize)]
+pub struct CreateConversationRequest {
+    user_ids: Vec<db::UserID>,
+}
+
+// Each user ID is at most 11 characters plus a comma.
+pub const CREATE_CONVERSATION_LIMIT: u64 =
+    ("{'user_ids':[]}".len() + 12 * db::MAX_CONVERSATION_USERS) as u64;
+
+fn error_response(message: &'static str) -> Box<dyn warp::Reply> {
+    Box::new(warp::reply::json(
+        &Response::Error { message }
+    ))
+}
+
+pub async fn create_conversation(session_id: db::SessionID, request: CreateConversationRequest, pool: Pool)
+    -> Result<Box<dyn warp::Reply>, warp::Rejection>
+{
+    let user_id = match db::session_user_id(pool.clone(), &session_id).await? {
+        Some(id) => id,
+        None => return Ok(Box::new(warp::http::StatusCode::UNAUTHORIZED))
+    };
+
+    let mut others = request.user_ids;
+    others.sort_unstable();
+    others.dedup();
+    others.retain(|&id| id != user_id);
+
+    if others.is_empty() || others.len() >= db::MAX_CONVERSATION_USERS {
+        return Ok(error_response("user_count_invalid"));
+    }
+
+    if !db::share_groups(pool.clone(), user_id, &others).await? {
+        return Ok(error_response("user_id_invalid"));
+    }
+
+    others.push(user_id);
+    let conversation_id = db::create_conversation(pool, &others).await?;
+
+    Ok(Box::new(warp::reply::json(
+        &Response::Success { conversation_id }
+    )))
+}
+
+pub async fn list_conversations(session_id: db::SessionID, pool: Pool)
+    -> Result<Box<dyn warp::Reply>, warp::Rejection>
+{
+    let user_id = match db::session_user_id(pool.clone(), &session_id).await? {
+        Some(id) => id,
+        None => return Ok(Box::new(warp::http::StatusCode::UNAUTHORIZED))
+    };
+
+    Ok(Box::new(warp::reply::json(
+        &db::user_conversations(pool, user_id).await?
+    )))
+}
@@ -30,14 +30,16 @@ fn error_response(message: &'static str) -> Box<dyn warp::Reply> {
     ))
 }
 
-pub async fn create_group(session_id: String, request: CreateGroupRequest, pool: Pool)
+pub async fn create_group(session_id: String, mut request: CreateGroupRequest, pool: Pool, socket_ctx: socket::Context)
     -> Result<Box<dyn warp::Reply>, warp::Rejection>
 {
+    request.picture = crate::proxy::original_url(request.picture);
+
     if !db::valid_group_name(&request.name) {
         return Ok(error_response("name_invalid"));
     }
 
-    if !db::valid_url(&request.picture) {
+    if !crate::avatar::valid_picture(&request.picture) {
         return Ok(error_response("picture_invalid"));
     }
 
@@ -55,7 +57,7 @@ pub async fn create_group(session_id: String, request: CreateGroupRequest, pool:
 
     let (channel_id, joined) = futures::future::join(
         db::create_channel(pool.clone(), group_id, &"general".to_owned()),
-        db::join_group(pool.clone(), user_id, group_id)
+        db::join_group(pool.clone(), user_id, group_id, db::Role::Owner)
     ).await;
 
     // Unwrapping the Option returned by create_channel because it is None if
@@ -64,6 +66,9 @@ pub async fn create_group(session_id: String, request: CreateGroupRequest, pool:
     channel_id.map_err(|e| crate::error::Error::Database(e))?.unwrap();
     joined?;
 
+    // The user's open sockets need to start receiving events for the group.
+    socket_ctx.join_group(user_id, group_id).await?;
+
     Ok(Box::new(warp::reply::json(
         &Response::Success { group_id }
     )))
@@ -77,12 +82,80 @@ pub async fn delete_group(group_id: db::GroupID, session_id: db::SessionID, pool
         None => return Ok(warp::http::StatusCode::UNAUTHORIZED)
     };
 
-    if !db::group_member(pool.clone(), user_id, group_id).await? {
-        return Ok(warp::http::StatusCode::FORBIDDEN);
+    match db::group_permissions(pool.clone(), user_id, group_id).await? {
+        Some(perms) if perms.contains(db::Permissions::DELETE_GROUP) => {},
+        _ => return Ok(warp::http::StatusCode::FORBIDDEN)
     }
 
-    let users = db::group_user_ids(pool.clone(), group_id).await.map_err(|e| crate::error::Error::Database(e))?;
     db::delete_group(pool.clone(), group_id).await?;
-    socket_ctx.delete_group(users, group_id).await;
+    socket_ctx.delete_group(group_id).await;
+    Ok(warp::http::StatusCode::NO_CONTENT)
+}
+
+/// Remove a user from a group and optionally ban them so that they cannot
+/// rejoin.
+async fn remove_member(
+    group_id: db::GroupID,
+    target_id: db::UserID,
+    session_id: db::SessionID,
+    pool: Pool,
+    socket_ctx: socket::Context,
+    ban: bool
+) -> Result<warp::http::StatusCode, warp::Rejection> {
+    let user_id = match db::session_user_id(pool.clone(), &session_id).await? {
+        Some(id) => id,
+        None => return Ok(warp::http::StatusCode::UNAUTHORIZED)
+    };
+
+    let permission = if ban {
+        db::Permissions::BAN_MEMBERS
+    } else {
+        db::Permissions::KICK_MEMBERS
+    };
+
+    match db::can_remove_member(pool.clone(), user_id, target_id, group_id, permission).await? {
+        Some(true) => {},
+        Some(false) => return Ok(warp::http::StatusCode::FORBIDDEN),
+        None => return Ok(warp::http::StatusCode::NOT_FOUND)
+    }
+
+    // This is the same as the user leaving the group
+    let banned_by = if ban { Some(user_id) } else { None };
+    db::remove_member(pool, target_id, group_id, banned_by).await?;
+    socket_ctx.kick_user_from_group(target_id, group_id).await;
+    socket_ctx.delete_user(vec![group_id], target_id).await;
+
     Ok(warp::http::StatusCode::NO_CONTENT)
 }
+
+pub async fn kick_user(group_id: db::GroupID, target_id: db::UserID, session_id: db::SessionID, pool: Pool, socket_ctx: socket::Context)
+    -> Result<impl warp::Reply, warp::Rejection>
+{
+    remove_member(group_id, target_id, session_id, pool, socket_ctx, false).await
+}
+
+pub async fn ban_user(group_id: db::GroupID, target_id: db::UserID, session_id: db::SessionID, pool: Pool, socket_ctx: socket::Context)
+    -> Result<impl warp::Reply, warp::Rejection>
+{
+    remove_member(group_id, target_id, session_id, pool, socket_ctx, true).await
+}
+
+pub async fn unban_user(group_id: db::GroupID, target_id: db::UserID, session_id: db::SessionID, pool: Pool)
+    -> Result<impl warp::Reply, warp::Rejection>
+{
+    let user_id = match db::session_user_id(pool.clone(), &session_id).await? {
+        Some(id) => id,
+        None => return Ok(warp::http::StatusCode::UNAUTHORIZED)
+    };
+
+    match db::group_permissions(pool.clone(), user_id, group_id).await? {
+        Some(perms) if perms.contains(db::Permissions::BAN_MEMBERS) => {},
+        _ => return Ok(warp::http::StatusCode::FORBIDDEN)
+    }
+
+    if db::unban_user(pool, target_id, group_id).await? {
+        Ok(warp::http::StatusCode::NO_CONTENT)
+    } else {
+        Ok(warp::http::StatusCode::NOT_FOUND)
+    }
+}
@@ -1,9 +1,11 @@
 use lexical_core::Number;
+use crate::socket;
 use crate::database as db;
 use deadpool_postgres::Pool;
 use serde::{Serialize, Deserialize};
+use std::time::{Duration, SystemTime};
 
-pub async fn accept_invite(invite_id: db::InviteID, session_id: db::SessionID, pool: Pool)
+pub async fn accept_invite(invite_id: db::InviteID, session_id: db::SessionID, pool: Pool, socket_ctx: socket::Context)
     -> Result<Box<dyn warp::Reply>, warp::Rejection>
 {
     let user_id = match db::session_user_id(pool.clone(), &session_id).await? {
@@ -14,14 +16,23 @@ pub async fn accept_invite(invite_id: db::InviteID, session_id: db::SessionID, p
         )))
     };
 
-    let group_id = match db::invitation_group_id(pool.clone(), invite_id).await? {
+    let group_id = match db::invitation_group_id(pool.clone(), &invite_id).await? {
         Some(id) => id,
         None => return Ok(Box::new(warp::http::StatusCode::NOT_FOUND))
     };
 
-    // This returns false if the user is already a member of the group but that
-    // doesn't matter because either way, we should take the user to the group.
-    db::join_group(pool.clone(), user_id, group_id).await?;
+    if db::banned(pool.clone(), user_id, group_id).await? {
+        return Ok(Box::new(warp::http::StatusCode::FORBIDDEN));
+    }
+
+    // The invitation might have been used up since we checked it above. If the
+    // user is already a member of the group then this doesn't use up the
+    // invitation but either way, we should take the user to the group.
+    if db::accept_invitation(pool.clone(), &invite_id, user_id).await?.is_none() {
+        return Ok(Box::new(warp::http::StatusCode::NOT_FOUND));
+    }
+
+    socket_ctx.join_group(user_id, group_id).await?;
 
     super::channel(group_id, 0, session_id, pool).await
 }
@@ -31,13 +42,31 @@ struct Response {
     invite_id: db::InviteID
 }
 
+// Invitations created without specifying an expiry last for 24 hours.
+const DEFAULT_EXPIRY: u32 = 24 * 60 * 60;
+
+fn default_expiry() -> Option<u32> {
+    Some(DEFAULT_EXPIRY)
+}
+
 #[derive(Deserialize)]
 pub struct CreateInviteRequest {
-    group_id: db::GroupID
+    group_id: db::GroupID,
+    // The number of seconds until the invitation expires. If this is null then
+    // the invitation never expires.
+    #[serde(default = "default_expiry")]
+    expires_in: Option<u32>,
+    // If this is null then the invitation can be used any number of times.
+    #[serde(default)]
+    max_uses: Option<i32>,
 }
 
-pub const CREATE_INVITE_LIMIT: u64 =
-    ("{'group_id':}".len() + db::GroupID::FORMATTED_SIZE_DECIMAL) as u64;
+pub const CREATE_INVITE_LIMIT: u64 = (
+    "{'group_id':,'expires_in':,'max_uses':}".len()
+    + db::GroupID::FORMATTED_SIZE_DECIMAL
+    + u32::FORMATTED_SIZE_DECIMAL
+    + i32::FORMATTED_SIZE_DECIMAL
+) as u64;
 
 pub async fn create_invite(session_id: db::SessionID, request: CreateInviteRequest, pool: Pool)
     -> Result<Box<dyn warp::Reply>, warp::Rejection>
@@ -47,11 +76,69 @@ pub async fn create_invite(session_id: db::SessionID, request: CreateInviteReque
         None => return Ok(Box::new(warp::http::StatusCode::UNAUTHORIZED))
     };
 
-    if !db::group_member(pool.clone(), user_id, request.group_id).await? {
-        return Ok(Box::new(warp::http::StatusCode::NOT_FOUND));
+    match db::group_permissions(pool.clone(), user_id, request.group_id).await? {
+        Some(perms) if perms.contains(db::Permissions::CREATE_INVITE) => {},
+        Some(_) => return Ok(Box::new(warp::http::StatusCode::FORBIDDEN)),
+        None => return Ok(Box::new(warp::http::StatusCode::NOT_FOUND))
     }
 
+    if request.expires_in == Some(0) || matches!(request.max_uses, Some(uses) if uses <= 0) {
+        return Ok(Box::new(warp::http::StatusCode::BAD_REQUEST));
+    }
+
+    let expiry_time = request.expires_in.map(|secs| {
+        SystemTime::now() + Duration::from_secs(secs as u64)
+    });
+
     Ok(Box::new(warp::reply::json(&Response {
-        invite_id: db::create_invitation(pool.clone(), request.group_id).await?
+        invite_id: db::create_invitation(
+            pool.clone(), request.group_id, user_id, expiry_time, request.max_uses
+        ).await?
     })))
 }
+
+pub async fn list_invites(group_id: db::GroupID, session_id: db::SessionID, pool: Pool)
+    -> Result<Box<dyn warp::Reply>, warp::Rejection>
+{
+    let user_id = match db::session_user_id(pool.clone(), &session_id).await? {
+        Some(id) => id,
+        None => return Ok(Box::new(warp::http::StatusCode::UNAUTHORIZED))
+    };
+
+    // Users that can't manage invitations can only see their own.
+    let creator = match db::group_permissions(pool.clone(), user_id, group_id).await? {
+        Some(perms) if perms.contains(db::Permissions::MANAGE_INVITES) => None,
+        Some(_) => Some(user_id),
+        None => return Ok(Box::new(warp::http::StatusCode::NOT_FOUND))
+    };
+
+    Ok(Box::new(warp::reply::json(
+        &db::group_invitations(pool, group_id, creator).await?
+    )))
+}
+
+pub async fn delete_invite(invite_id: db::InviteID, session_id: db::SessionID, pool: Pool)
+    -> Result<impl warp::Reply, warp::Rejection>
+{
+    let user_id = match db::session_user_id(pool.clone(), &session_id).await? {
+        Some(id) => id,
+        None => return Ok(warp::http::StatusCode::UNAUTHORIZED)
+    };
+
+    let (group_id, creator) = match db::invitation_info(pool.clone(), &invite_id).await? {
+        Some(info) => info,
+        None => return Ok(warp::http::StatusCode::NOT_FOUND)
+    };
+
+    // Users can revoke their own invitations as long as they're still members.
+    match db::group_permissions(pool.clone(), user_id, group_id).await? {
+        Some(perms) if perms.contains(db::Permissions::MANAGE_INVITES) => {},
+        Some(_) if creator == Some(user_id) => {},
+        Some(_) => return Ok(warp::http::StatusCode::FORBIDDEN),
+        None => return Ok(warp::http::StatusCode::NOT_FOUND)
+    }
+
+    db::delete_invitation(pool, &invite_id).await?;
+
+    Ok(warp::http::StatusCode::NO_CONTENT)
+}
@@ -2,6 +2,7 @@ use askama::Template;
 use serde::Deserialize;
 use crate::database as db;
 use deadpool_postgres::Pool;
+use crate::config::GoogleConfigRef;
 use crate::{utils::cache_long, socket};
 
 #[derive(Template)]
@@ -16,11 +17,12 @@ pub struct LoginQuery {
     redirect: String,
 }
 
-pub async fn login(query: LoginQuery) -> Result<impl warp::Reply, warp::Rejection> {
-    let mut google_auth_url = format!(
-        "https://accounts.google.com/o/oauth2/v2/auth?redirect_uri=https://localhost/api/auth&response_type=code&scope=profile&client_id={}&state=",
-        include_str!("../../api/client_id.txt")
-    );
+pub async fn login(query: LoginQuery, google: GoogleConfigRef) -> Result<impl warp::Reply, warp::Rejection> {
+    let mut google_auth_url = "https://accounts.google.com/o/oauth2/v2/auth?redirect_uri=".to_owned();
+    google_auth_url.extend(form_urlencoded::byte_serialize(google.redirect_uri.as_bytes()));
+    google_auth_url.push_str("&response_type=code&scope=profile&client_id=");
+    google_auth_url.extend(form_urlencoded::byte_serialize(google.client_id.as_bytes()));
+    google_auth_url.push_str("&state=");
     google_auth_url.extend(form_urlencoded::byte_serialize(query.redirect.as_bytes()));
     Ok(cache_long(LoginTemplate {
         redirect_url: query.redirect,
@@ -28,12 +30,12 @@ pub async fn login(query: LoginQuery) -> Result<impl warp::Reply, warp::Rejectio
     }))
 }
 
-pub async fn logout(pool: Pool, socket_ctx: socket::Context, session_id: db::SessionID)
+pub async fn logout(pool: Pool, socket_ctx: socket::Context, google: GoogleConfigRef, session_id: db::SessionID)
     -> Result<impl warp::Reply, warp::Rejection>
 {
     if let Some(user_id) = db::session_user_id(pool.clone(), &session_id).await? {
         db::delete_user_sessions(pool, user_id).await?;
         socket_ctx.kick_user(user_id).await;
     }
-    Ok(login(LoginQuery { redirect: "/".to_owned() }).await?)
+    login(LoginQuery { redirect: "/".to_owned() }, google).await
 }
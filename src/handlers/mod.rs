@@ -4,6 +4,10 @@ mod channel;
 mod login;
 mod group;
 mod invite;
+mod conversation;
+mod attachment;
+mod avatar;
+mod picture;
 
 pub use auth::*;
 pub use user::*;
@@ -11,3 +15,7 @@ pub use channel::*;
 pub use login::*;
 pub use group::*;
 pub use invite::*;
+pub use conversation::*;
+pub use attachment::*;
+pub use avatar::*;
+pub use picture::*;
@@ -0,0 +1,206 @@
+use serde::Deserialize;
+use crate::error::Error;
+use crate::database as db;
+use deadpool_postgres::Pool;
+use crate::fetch::Fetcher;
+use crate::proxy::{PictureCache, PictureCacheRef};
+use crate::utils::cache_short;
+
+#[derive(Deserialize)]
+pub struct PictureQuery {
+    url: String,
+}
+
+/// The maximum size of a picture fetched by the proxy in bytes.
+pub const MAX_PICTURE_SIZE: usize = 2 * 1024 * 1024;
+
+/// SVG is not included because it can contain scripts.
+const PICTURE_CONTENT_TYPES: &[&str] = &["image/png", "image/jpeg", "image/gif", "image/webp"];
+
+/// Fetch an external user or group picture, or the image of a link preview, on
+/// behalf of the client.
+pub async fn picture(query: PictureQuery, pool: Pool, fetcher: Fetcher, cache: PictureCacheRef)
+    -> Result<Box<dyn warp::Reply>, warp::Rejection>
+{
+    // Only pictures that are in use are fetched. Otherwise, this would be an
+    // open proxy.
+    let in_use = !query.url.contains('\n') && db::picture_in_use(pool, &query.url).await?;
+    Ok(serve_picture(&query.url, in_use, &fetcher, &cache).await?)
+}
+
+async fn serve_picture(url: &str, in_use: bool, fetcher: &Fetcher, cache: &PictureCache)
+    -> Result<Box<dyn warp::Reply>, Error>
+{
+    if !in_use {
+        return Ok(Box::new(warp::http::StatusCode::NOT_FOUND));
+    }
+
+    let (content_type, data) = match cache.get(url).await? {
+        Some(cached) if !cached.expired => (cached.content_type, cached.data),
+        cached => {
+            let accept = |content_type: &str| PICTURE_CONTENT_TYPES.contains(&content_type);
+            match fetcher.get(url, accept, MAX_PICTURE_SIZE).await {
+                Ok(fetched) => {
+                    cache.put(url, &fetched.content_type, &fetched.data).await?;
+                    (fetched.content_type, fetched.data)
+                },
+                // Keep using the old picture if it can't be fetched anymore.
+                Err(_) => match cached {
+                    Some(cached) => (cached.content_type, cached.data),
+                    None => return Ok(Box::new(warp::http::StatusCode::BAD_GATEWAY))
+                }
+            }
+        }
+    };
+
+    let reply = warp::reply::with_header(data, "Content-Type", content_type);
+    let reply = warp::reply::with_header(reply, "X-Content-Type-Options", "nosniff");
+    Ok(Box::new(cache_short(reply)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use warp::Filter;
+    use warp::http::StatusCode;
+    use warp::hyper::Body;
+    use crate::config::PictureProxyConfig;
+    use crate::testing::{self, Hits, TempDir};
+
+    const PNG: &[u8] = b"\x89PNG\r\n\x1a\n";
+
+    fn cache(expiry: u64) -> (PictureCache, TempDir) {
+        let dir = TempDir::new();
+        let cache = PictureCache::new(&PictureProxyConfig {
+            cache_path: dir.path().to_str().unwrap().to_owned(),
+            cache_expiry: expiry,
+        });
+        (cache, dir)
+    }
+
+    fn with_type(data: Vec<u8>, content_type: &'static str) -> warp::http::Response<Body> {
+        warp::http::Response::builder()
+            .header("Content-Type", content_type)
+            .body(Body::from(data))
+            .unwrap()
+    }
+
+    /// A response without a Content-Length.
+    fn chunked(size: usize) -> warp::http::Response<Body> {
+        let chunks = vec![vec![0u8; 1024]; size / 1024].into_iter()
+            .map(Ok::<_, std::convert::Infallible>);
+        warp::http::Response::builder()
+            .header("Content-Type", "image/png")
+            .body(Body::wrap_stream(futures::stream::iter(chunks)))
+            .unwrap()
+    }
+
+    async fn request(url: &str, in_use: bool, cache: &PictureCache) -> (StatusCode, Vec<u8>) {
+        let reply = serve_picture(url, in_use, &testing::fetcher(), cache).await.unwrap();
+        let response = warp::Reply::into_response(reply);
+        let status = response.status();
+        let body = warp::hyper::body::to_bytes(response.into_body()).await.unwrap();
+        (status, body.to_vec())
+    }
+
+    #[tokio::test]
+    async fn rejects_content_types() {
+        let svg = warp::path("s.svg").map(|| with_type(b"<svg></svg>".to_vec(), "image/svg+xml"));
+        let html = warp::path("page.html").map(|| with_type(b"<html></html>".to_vec(), "text/html"));
+        let addr = testing::serve(svg.or(html));
+        let (cache, _dir) = cache(3600);
+
+        let (status, _) = request(&format!("http://{}/s.svg", addr), true, &cache).await;
+        assert_eq!(status, StatusCode::BAD_GATEWAY);
+        let (status, _) = request(&format!("http://{}/page.html", addr), true, &cache).await;
+        assert_eq!(status, StatusCode::BAD_GATEWAY);
+    }
+
+    #[tokio::test]
+    async fn limits_size() {
+        let big = warp::path("big.png").map(|| with_type(vec![0; MAX_PICTURE_SIZE + 1], "image/png"));
+        let big_chunked = warp::path("big-chunked.png").map(|| chunked(MAX_PICTURE_SIZE + 1024));
+        let chunked = warp::path("chunked.png").map(|| chunked(MAX_PICTURE_SIZE));
+        let addr = testing::serve(big.or(big_chunked).or(chunked));
+        let (cache, _dir) = cache(3600);
+
+        let (status, _) = request(&format!("http://{}/big.png", addr), true, &cache).await;
+        assert_eq!(status, StatusCode::BAD_GATEWAY);
+        let (status, _) = request(&format!("http://{}/big-chunked.png", addr), true, &cache).await;
+        assert_eq!(status, StatusCode::BAD_GATEWAY);
+        let (status, body) = request(&format!("http://{}/chunked.png", addr), true, &cache).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(body.len(), MAX_PICTURE_SIZE);
+    }
+
+    #[tokio::test]
+    async fn not_in_use() {
+        let hits = Hits::default();
+        let routes = hits.filter().map(|| with_type(PNG.to_vec(), "image/png"));
+        let addr = testing::serve(routes);
+        let (cache, _dir) = cache(3600);
+
+        let (status, _) = request(&format!("http://{}/a.png", addr), false, &cache).await;
+        assert_eq!(status, StatusCode::NOT_FOUND);
+        assert_eq!(hits.count(), 0);
+    }
+
+    #[tokio::test]
+    async fn cache_hit() {
+        let hits = Hits::default();
+        let routes = hits.filter().map(|| with_type(PNG.to_vec(), "image/png"));
+        let addr = testing::serve(routes);
+        let (cache, _dir) = cache(3600);
+        let url = format!("http://{}/a.png", addr);
+
+        for _ in 0..2 {
+            let (status, body) = request(&url, true, &cache).await;
+            assert_eq!(status, StatusCode::OK);
+            assert_eq!(body, PNG);
+        }
+        assert_eq!(hits.count(), 1);
+    }
+
+    #[tokio::test]
+    async fn cache_expiry() {
+        let hits = Hits::default();
+        let routes = hits.filter().map(|| with_type(PNG.to_vec(), "image/png"));
+        let addr = testing::serve(routes);
+        let (cache, _dir) = cache(0);
+        let url = format!("http://{}/a.png", addr);
+
+        for _ in 0..2 {
+            let (status, _) = request(&url, true, &cache).await;
+            assert_eq!(status, StatusCode::OK);
+            tokio::time::delay_for(std::time::Duration::from_millis(10)).await;
+        }
+        assert_eq!(hits.count(), 2);
+    }
+
+    #[tokio::test]
+    async fn stale_fallback() {
+        let hits = Hits::default();
+        let counter = hits.clone();
+        // The picture is only available the first time.
+        let routes = hits.filter().map(move || {
+            if counter.count() == 1 {
+                with_type(PNG.to_vec(), "image/png")
+            } else {
+                let mut response = with_type(Vec::new(), "text/plain");
+                *response.status_mut() = StatusCode::NOT_FOUND;
+                response
+            }
+        });
+        let addr = testing::serve(routes);
+        let (cache, _dir) = cache(0);
+        let url = format!("http://{}/a.png", addr);
+
+        let (status, _) = request(&url, true, &cache).await;
+        assert_eq!(status, StatusCode::OK);
+        tokio::time::delay_for(std::time::Duration::from_millis(10)).await;
+        let (status, body) = request(&url, true, &cache).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(body, PNG);
+        assert_eq!(hits.count(), 2);
+    }
+}
@@ -23,9 +23,12 @@ pub struct RenameUserRequest {
 pub const RENAME_USER_LIMIT: u64 =
     ("{'name':'','picture':''}".len() + db::MAX_USER_NAME_LENGTH + db::MAX_URL_LENGTH) as u64;
 
-pub async fn rename_user(session_id: db::SessionID, request: RenameUserRequest, pool: Pool, socket_ctx: socket::Context)
+pub async fn rename_user(session_id: db::SessionID, mut request: RenameUserRequest, pool: Pool, socket_ctx: socket::Context)
     -> Result<Box<dyn warp::Reply>, warp::Rejection>
 {
+    // The client sends back the proxied URL if the picture wasn't changed.
+    request.picture = crate::proxy::original_url(request.picture);
+
     let user_id = match db::session_user_id(pool.clone(), &session_id).await? {
         Some(id) => id,
         None => return Ok(Box::new(warp::http::StatusCode::UNAUTHORIZED))
@@ -35,7 +38,7 @@ pub async fn rename_user(session_id: db::SessionID, request: RenameUserRequest,
         return Ok(Box::new("name_invalid"));
     }
 
-    if !db::valid_url(&request.picture) {
+    if !crate::avatar::valid_picture(&request.picture) {
         return Ok(Box::new("picture_invalid"));
     }
 
@@ -57,8 +60,12 @@ pub async fn delete_user(session_id: db::SessionID, pool: Pool, socket_ctx: sock
         None => return Ok(warp::http::StatusCode::UNAUTHORIZED)
     };
 
+    // The owner of a group must delete the group first, as they would if they
+    // were leaving it.
     let groups = db::user_group_ids(pool.clone(), user_id).await?;
-    db::delete_user(pool, user_id).await?;
+    if !db::delete_user(pool, user_id).await? {
+        return Ok(warp::http::StatusCode::FORBIDDEN);
+    }
     socket_ctx.kick_user(user_id).await;
     socket_ctx.delete_user(groups, user_id).await;
 
@@ -73,8 +80,13 @@ pub async fn leave_group(group_id: db::GroupID, session_id: db::SessionID, pool:
         None => return Ok(warp::http::StatusCode::UNAUTHORIZED)
     };
 
-    db::leave_group(pool.clone(), user_id, group_id).await?;
-    db::anonymize_messages(pool, user_id, group_id).await?;
+    // The owner must delete the group instead of leaving it. Otherwise, nobody
+    // would be able to delete the group.
+    if db::group_role(pool.clone(), user_id, group_id).await? == Some(db::Role::Owner) {
+        return Ok(warp::http::StatusCode::FORBIDDEN);
+    }
+
+    db::remove_member(pool, user_id, group_id, None).await?;
     socket_ctx.kick_user_from_group(user_id, group_id).await;
     socket_ctx.delete_user(vec![group_id], user_id).await;
 
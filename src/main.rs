@@ -1,11 +1,25 @@
+// Checking that the link preview task is Send goes deeper than the default.
+#![recursion_limit = "256"]
+
 mod filters;
 mod handlers;
 mod error;
 mod database;
 mod utils;
 mod socket;
+mod config;
+mod storage;
+mod avatar;
+mod fetch;
+mod proxy;
+mod markdown;
+mod unfurl;
+mod sweep;
+#[cfg(test)]
+mod testing;
 
 use warp::Filter;
+use std::time::Duration;
 use deadpool_postgres::{Pool, Manager};
 use deadpool_postgres::tokio_postgres::{Config, NoTls};
 
@@ -13,22 +27,41 @@ use deadpool_postgres::tokio_postgres::{Config, NoTls};
 // let _a: &[u8; 5] = b"hello";
 // let _b: &str = "hello";
 
-fn create_pool() -> Pool {
+fn create_pool(db_config: &config::DatabaseConfig) -> Pool {
     let mut config = Config::new();
-    config.host("localhost");
-    config.user("postgres");
-    config.dbname("chat");
+    config.host(db_config.host.as_str());
+    config.port(db_config.port);
+    config.user(db_config.user.as_str());
+    if let Some(password) = &db_config.password {
+        config.password(password.as_str());
+    }
+    config.dbname(db_config.dbname.as_str());
 
     let manager = Manager::new(config, NoTls);
-    Pool::new(manager, 16)
+    Pool::new(manager, db_config.pool_size)
+}
+
+async fn migrate(pool: &Pool, dry_run: bool) {
+    let applied = match database::migrate(pool.clone(), dry_run).await {
+        Ok(applied) => applied,
+        Err(e) => {
+            eprintln!("Migration failed: {}", e);
+            std::process::exit(1);
+        }
+    };
+
+    let verb = if dry_run { "Would apply" } else { "Applied" };
+    for migration in applied.iter() {
+        println!("{} migration {:04}_{}", verb, migration.version, migration.name);
+    }
+    if applied.is_empty() {
+        println!("Database schema is up to date");
+    }
 }
 
 async fn print_message_count(pool: &Pool) {
     let client = pool.get().await.unwrap();
 
-    let init = std::fs::read_to_string("initialize.sql").unwrap();
-    client.batch_execute(init.as_str()).await.unwrap();
-
     let rows = client
         .query("SELECT COUNT(*) FROM Message", &[])
         .await.unwrap();
@@ -38,28 +71,77 @@ async fn print_message_count(pool: &Pool) {
 
 #[tokio::main]
 async fn main() {
-    let pool = create_pool();
+    let config = match config::Config::load() {
+        Ok(config) => config,
+        Err(e) => {
+            eprintln!("{}", e);
+            std::process::exit(1);
+        }
+    };
+
+    let pool = create_pool(&config.database);
+
+    // chat migrate [--dry-run]
+    let args = std::env::args().skip(1).collect::<Vec<_>>();
+    match args.first().map(String::as_str) {
+        Some("migrate") => {
+            migrate(&pool, args[1..].iter().any(|arg| arg == "--dry-run")).await;
+            return;
+        },
+        Some(arg) => {
+            eprintln!("Unknown argument: {}", arg);
+            eprintln!("Usage: chat [migrate [--dry-run]]");
+            std::process::exit(2);
+        },
+        None => {}
+    }
+
+    migrate(&pool, false).await;
     print_message_count(&pool).await;
-    let socket_ctx = crate::socket::Context::new(pool.clone());
     let client = reqwest::Client::new();
+    let fetcher = fetch::Fetcher::new(&config.fetch);
+    let socket_ctx = crate::socket::Context::new(pool.clone(), unfurl::Unfurler::new(fetcher.clone()));
+    let picture_cache = proxy::PictureCacheRef::new(proxy::PictureCache::new(&config.picture_proxy));
     let cert_cache = handlers::CertificateCache::default();
+    let google = config::GoogleConfigRef::new(config.google);
+    let storage: storage::StorageRef = std::sync::Arc::new(storage::LocalStorage::new(config.storage.path));
 
     pretty_env_logger::init();
 
+    tokio::spawn(sweep::run(
+        pool.clone(),
+        storage.clone(),
+        picture_cache.clone(),
+        Duration::from_secs(config.storage.unattached_expiry),
+        Duration::from_secs(config.storage.sweep_interval),
+    ));
+
     let routes = filters::root(pool.clone())
-        .or(filters::login())
-        .or(filters::logout(pool.clone(), socket_ctx.clone()))
+        .or(filters::login(google.clone()))
+        .or(filters::logout(pool.clone(), socket_ctx.clone(), google.clone()))
         .or(filters::channel(pool.clone()))
-        .or(filters::invite(pool.clone()))
-        .or(filters::create_group(pool.clone()))
+        .or(filters::invite(pool.clone(), socket_ctx.clone()))
+        .or(filters::create_group(pool.clone(), socket_ctx.clone()))
         .or(filters::delete_group(pool.clone(), socket_ctx.clone()))
         .or(filters::create_invite(pool.clone()))
+        .or(filters::list_invites(pool.clone()))
+        .or(filters::delete_invite(pool.clone()))
+        .or(filters::create_conversation(pool.clone()))
+        .or(filters::list_conversations(pool.clone()))
+        .or(filters::upload_attachment(pool.clone(), storage.clone()))
+        .or(filters::download_attachment(pool.clone(), storage.clone()))
+        .or(filters::upload_avatar(pool.clone(), storage.clone()))
+        .or(filters::avatar(storage))
+        .or(filters::picture(pool.clone(), fetcher, picture_cache))
         .or(filters::leave_group(pool.clone(), socket_ctx.clone()))
+        .or(filters::kick_user(pool.clone(), socket_ctx.clone()))
+        .or(filters::ban_user(pool.clone(), socket_ctx.clone()))
+        .or(filters::unban_user(pool.clone()))
         .or(filters::user(pool.clone()))
         .or(filters::rename_user(pool.clone(), socket_ctx.clone()))
         .or(filters::delete_user(pool.clone(), socket_ctx.clone()))
         .or(filters::socket(socket_ctx))
-        .or(filters::auth_success(pool.clone(), client, cert_cache))
+        .or(filters::auth_success(pool.clone(), client, cert_cache, google))
         .or(filters::auth_fail())
         .or(filters::favicon())
         .or(filters::js())
@@ -68,8 +150,8 @@ async fn main() {
 
     warp::serve(routes.with(warp::log("chat")))
         .tls()
-        .cert_path("tls/localhost.crt")
-        .key_path("tls/localhost.key")
-        .run(([0, 0, 0, 0], 443))
+        .cert_path(config.tls.cert_path)
+        .key_path(config.tls.key_path)
+        .run(config.server.address)
         .await;
 }
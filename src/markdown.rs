@@ -0,0 +1,320 @@
+/*
+Messages are formatted with a tiny subset of Markdown:
+
+    **bold** or __bold__
+    *italic* or _italic_
+    `inline code`
+    [link text](https://example.com)
+
+A backslash before a punctuation character stops it from being treated as
+formatting. Anything that doesn't form a complete span is left as it is.
+
+The messages are rendered to HTML on the server so that every client displays
+the same thing. All text is escaped and only the handful of elements above are
+produced so the result is safe to insert into the page. The HTML is rendered
+when a message is created or edited and stored along with the raw text, so
+changes to the rules only apply to new and edited messages. Messages that were
+stored without HTML are rendered when they're read.
+*/
+
+enum Node {
+    Text(String),
+    Bold(Vec<Node>),
+    Italic(Vec<Node>),
+    Code(String),
+    Link(String, Vec<Node>),
+}
+
+/// Spans nested deeper than this are left as text.
+const MAX_DEPTH: usize = 8;
+
+/// Find the next occurrence of a character, starting at an index.
+fn find_char(chars: &[char], start: usize, ch: char) -> Option<usize> {
+    chars[start.min(chars.len())..].iter()
+        .position(|&c| c == ch)
+        .map(|index| start + index)
+}
+
+/// The number of times a character is repeated, starting at an index.
+fn run_length(chars: &[char], start: usize, ch: char) -> usize {
+    chars[start..].iter().take_while(|&&c| c == ch).count()
+}
+
+fn is_word_char(ch: Option<&char>) -> bool {
+    ch.is_some_and(|ch| ch.is_alphanumeric())
+}
+
+/// Underscores in the middle of a word (like snake_case) are not formatting.
+fn can_open(chars: &[char], index: usize, len: usize, delim: char) -> bool {
+    let next = chars.get(index + len);
+    if next.is_none_or(|ch| ch.is_whitespace()) {
+        return false;
+    }
+    delim != '_' || index == 0 || !is_word_char(chars.get(index - 1))
+}
+
+fn can_close(chars: &[char], index: usize, len: usize, delim: char) -> bool {
+    if chars[index - 1].is_whitespace() {
+        return false;
+    }
+    delim != '_' || !is_word_char(chars.get(index + len))
+}
+
+/// Find the delimiter that closes a bold or italic span. Code spans and
+/// escaped characters are skipped over.
+fn find_closer(chars: &[char], start: usize, delim: char, len: usize) -> Option<usize> {
+    let mut index = start;
+    while index < chars.len() {
+        match chars[index] {
+            '\\' => index += 2,
+            '`' => match find_char(chars, index + 1, '`') {
+                Some(end) => index = end + 1,
+                None => index += 1,
+            },
+            ch if ch == delim => {
+                let run = run_length(chars, index, delim);
+                if run == len && index > start && can_close(chars, index, len, delim) {
+                    return Some(index);
+                }
+                index += run;
+            },
+            _ => index += 1,
+        }
+    }
+    None
+}
+
+/// Find the end of the link text and the end of the URL of a link starting at
+/// an index.
+fn find_link(chars: &[char], start: usize) -> Option<(usize, usize)> {
+    let mut index = start + 1;
+    let text_end = loop {
+        match chars.get(index)? {
+            '\\' => index += 2,
+            '`' => match find_char(chars, index + 1, '`') {
+                Some(end) => index = end + 1,
+                None => index += 1,
+            },
+            '[' => return None,
+            ']' => break index,
+            _ => index += 1,
+        }
+    };
+    if text_end == start + 1 || chars.get(text_end + 1) != Some(&'(') {
+        return None;
+    }
+    let url_end = find_char(chars, text_end + 2, ')')?;
+    let url = &chars[text_end + 2..url_end];
+    if url.is_empty() || url.iter().any(|ch| ch.is_whitespace()) {
+        return None;
+    }
+    Some((text_end, url_end))
+}
+
+/// Only web and email links are allowed. In particular, javascript: URLs are
+/// not.
+fn link_url(url: &[char]) -> Option<String> {
+    let url = reqwest::Url::parse(&url.iter().collect::<String>()).ok()?;
+    match url.scheme() {
+        "http" | "https" | "mailto" => Some(url.into_string()),
+        _ => None,
+    }
+}
+
+fn flush_text(nodes: &mut Vec<Node>, text: &mut String) {
+    if !text.is_empty() {
+        nodes.push(Node::Text(std::mem::take(text)));
+    }
+}
+
+fn parse(chars: &[char], in_link: bool, depth: usize) -> Vec<Node> {
+    let mut nodes = Vec::new();
+    let mut text = String::new();
+    let mut index = 0;
+
+    while index < chars.len() {
+        let ch = chars[index];
+
+        if ch == '\\' && chars.get(index + 1).is_some_and(|next| next.is_ascii_punctuation()) {
+            text.push(chars[index + 1]);
+            index += 2;
+            continue;
+        }
+
+        if ch == '`' {
+            if let Some(end) = find_char(chars, index + 1, '`') {
+                if end > index + 1 {
+                    flush_text(&mut nodes, &mut text);
+                    nodes.push(Node::Code(chars[index + 1..end].iter().collect()));
+                    index = end + 1;
+                    continue;
+                }
+            }
+        }
+
+        if (ch == '*' || ch == '_') && depth < MAX_DEPTH {
+            let len = run_length(chars, index, ch).min(2);
+            if can_open(chars, index, len, ch) {
+                if let Some(end) = find_closer(chars, index + len, ch, len) {
+                    let children = parse(&chars[index + len..end], in_link, depth + 1);
+                    flush_text(&mut nodes, &mut text);
+                    nodes.push(if len == 2 { Node::Bold(children) } else { Node::Italic(children) });
+                    index = end + len;
+                    continue;
+                }
+            }
+            // Keep the whole run together so that ** isn't reinterpreted as
+            // two italic delimiters.
+            text.extend(&chars[index..index + len]);
+            index += len;
+            continue;
+        }
+
+        if ch == '[' && !in_link && depth < MAX_DEPTH {
+            if let Some((text_end, url_end)) = find_link(chars, index) {
+                if let Some(url) = link_url(&chars[text_end + 2..url_end]) {
+                    let children = parse(&chars[index + 1..text_end], true, depth + 1);
+                    flush_text(&mut nodes, &mut text);
+                    nodes.push(Node::Link(url, children));
+                    index = url_end + 1;
+                    continue;
+                }
+            }
+        }
+
+        text.push(ch);
+        index += 1;
+    }
+
+    flush_text(&mut nodes, &mut text);
+    nodes
+}
+
+fn escape(text: &str, html: &mut String) {
+    for ch in text.chars() {
+        match ch {
+            '&' => html.push_str("&amp;"),
+            '<' => html.push_str("&lt;"),
+            '>' => html.push_str("&gt;"),
+            '"' => html.push_str("&quot;"),
+            '\'' => html.push_str("&#39;"),
+            _ => html.push(ch),
+        }
+    }
+}
+
+fn write_html(nodes: &[Node], html: &mut String) {
+    for node in nodes.iter() {
+        match node {
+            Node::Text(text) => escape(text, html),
+            Node::Bold(children) => {
+                html.push_str("<strong>");
+                write_html(children, html);
+                html.push_str("</strong>");
+            },
+            Node::Italic(children) => {
+                html.push_str("<em>");
+                write_html(children, html);
+                html.push_str("</em>");
+            },
+            Node::Code(code) => {
+                html.push_str("<code>");
+                escape(code, html);
+                html.push_str("</code>");
+            },
+            Node::Link(url, children) => {
+                html.push_str("<a href=\"");
+                escape(url, html);
+                html.push_str("\" target=\"_blank\" rel=\"noopener noreferrer nofollow\">");
+                write_html(children, html);
+                html.push_str("</a>");
+            },
+        }
+    }
+}
+
+/// Render the content of a message as HTML.
+pub fn render(content: &str) -> String {
+    let chars = content.chars().collect::<Vec<_>>();
+    let mut html = String::with_capacity(content.len());
+    write_html(&parse(&chars, false, 0), &mut html);
+    html
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn formatting() {
+        assert_eq!(render("**bold** __bold__"), "<strong>bold</strong> <strong>bold</strong>");
+        assert_eq!(render("*italic* _italic_"), "<em>italic</em> <em>italic</em>");
+        assert_eq!(render("**bold *and italic* too**"), "<strong>bold <em>and italic</em> too</strong>");
+        assert_eq!(render("**__*_x_*__**"), "<strong><strong><em><em>x</em></em></strong></strong>");
+        assert_eq!(render("`*code*`"), "<code>*code*</code>");
+        assert_eq!(render("snake_case_name"), "snake_case_name");
+        assert_eq!(render("\\*not italic\\*"), "*not italic*");
+    }
+
+    #[test]
+    fn escaping() {
+        assert_eq!(
+            render("<script>alert(\"1\" & '2')</script>"),
+            "&lt;script&gt;alert(&quot;1&quot; &amp; &#39;2&#39;)&lt;/script&gt;"
+        );
+        assert_eq!(render("`<b>`"), "<code>&lt;b&gt;</code>");
+        assert_eq!(render("**<i>**"), "<strong>&lt;i&gt;</strong>");
+    }
+
+    #[test]
+    fn links() {
+        assert_eq!(
+            render("[a *link*](https://example.com/?a=1&b=\"2\")"),
+            "<a href=\"https://example.com/?a=1&amp;b=%222%22\" target=\"_blank\" \
+            rel=\"noopener noreferrer nofollow\">a <em>link</em></a>"
+        );
+        assert_eq!(render("[[nested](https://a.com)](https://b.com)"), "[<a href=\"https://a.com/\" target=\"_blank\" \
+            rel=\"noopener noreferrer nofollow\">nested</a>](https://b.com)");
+    }
+
+    #[test]
+    fn unsafe_links() {
+        assert_eq!(render("[click](javascript:alert(1\\))"), "[click](javascript:alert(1))");
+        assert_eq!(render("[click](JavaScript:alert)"), "[click](JavaScript:alert)");
+        assert_eq!(render("[click](data:text/html,hi)"), "[click](data:text/html,hi)");
+        assert_eq!(render("[click](/relative)"), "[click](/relative)");
+    }
+
+    #[test]
+    fn unclosed_spans() {
+        assert_eq!(render("**bold"), "**bold");
+        assert_eq!(render("*italic"), "*italic");
+        assert_eq!(render("`code"), "`code");
+        assert_eq!(render("[text](https://example.com"), "[text](https://example.com");
+        assert_eq!(render("[text]"), "[text]");
+        assert_eq!(render("** not bold **"), "** not bold **");
+        assert_eq!(render("*a **b* c**"), "<em>a **b</em> c**");
+    }
+
+    fn render_at_depth(content: &str, depth: usize) -> String {
+        let chars = content.chars().collect::<Vec<_>>();
+        let mut html = String::new();
+        write_html(&parse(&chars, false, depth), &mut html);
+        html
+    }
+
+    #[test]
+    fn max_depth() {
+        let content = "*a* [b](https://example.com) `c`";
+        assert_eq!(
+            render_at_depth(content, MAX_DEPTH - 1),
+            "<em>a</em> <a href=\"https://example.com/\" target=\"_blank\" \
+            rel=\"noopener noreferrer nofollow\">b</a> <code>c</code>"
+        );
+        // Past the limit, spans and links are left as text but code is still
+        // formatted.
+        assert_eq!(render_at_depth(content, MAX_DEPTH), "*a* [b](https://example.com) <code>c</code>");
+        assert_eq!(render_at_depth("**a**", MAX_DEPTH - 1), "<strong>a</strong>");
+        assert_eq!(render_at_depth("**a**", MAX_DEPTH), "**a**");
+    }
+}
@@ -0,0 +1,242 @@
+use std::io::ErrorKind;
+use std::path::PathBuf;
+use std::time::Duration;
+use sha2::{Digest, Sha256};
+use serde::Serializer;
+use tokio::io::AsyncReadExt;
+use crate::config::PictureProxyConfig;
+use crate::utils::generate_random_base64url;
+use crate::database::MAX_URL_LENGTH;
+
+const PROXY_PREFIX: &str = "/picture?url=";
+
+/// Rewrite an external picture URL so that the browser gets it through the
+/// proxy instead of from a third party. Pictures that are served by this
+/// server (uploaded pictures) are left alone.
+pub fn proxy_url(picture: &str) -> String {
+    if picture.is_empty() || picture.starts_with('/') {
+        return picture.to_owned();
+    }
+    let mut url = PROXY_PREFIX.to_owned();
+    url.extend(form_urlencoded::byte_serialize(picture.as_bytes()));
+    url
+}
+
+/// The opposite of proxy_url. The client sends back the picture URL that it
+/// was given when renaming a user or group.
+pub fn original_url(picture: String) -> String {
+    if !picture.starts_with(PROXY_PREFIX) {
+        return picture;
+    }
+    let query = &picture[PROXY_PREFIX.find('?').unwrap() + 1..];
+    let original = form_urlencoded::parse(query.as_bytes())
+        .find(|(key, _)| key == "url")
+        .map(|(_, value)| value.into_owned());
+    original.unwrap_or(picture)
+}
+
+/// Used with #[serde(serialize_with)] on picture fields so that every picture
+/// sent to the client goes through proxy_url.
+pub fn serialize_picture<P: AsRef<str>, S: Serializer>(picture: &P, serializer: S) -> Result<S::Ok, S::Error> {
+    serializer.serialize_str(&proxy_url(picture.as_ref()))
+}
+
+pub fn serialize_optional_picture<S: Serializer>(picture: &Option<String>, serializer: S) -> Result<S::Ok, S::Error> {
+    match picture {
+        Some(picture) => serialize_picture(picture, serializer),
+        None => serializer.serialize_none(),
+    }
+}
+
+pub struct CachedPicture {
+    pub content_type: String,
+    pub data: Vec<u8>,
+    /// Whether the picture should be fetched again.
+    pub expired: bool,
+}
+
+/// Caches fetched pictures in a directory. Each file contains the URL and the
+/// content type on their own lines followed by the picture. The modification
+/// time of the file is the time that the picture was fetched.
+pub struct PictureCache {
+    path: PathBuf,
+    expiry: Duration,
+}
+
+pub type PictureCacheRef = std::sync::Arc<PictureCache>;
+
+impl PictureCache {
+    pub fn new(config: &PictureProxyConfig) -> Self {
+        Self {
+            path: PathBuf::from(&config.cache_path),
+            expiry: Duration::from_secs(config.cache_expiry),
+        }
+    }
+
+    /// The name of the file is the SHA-256 of the URL so that it stays the same
+    /// when the server is rebuilt.
+    fn file_path(&self, url: &str) -> PathBuf {
+        let hash = Sha256::digest(url.as_bytes());
+        let name = hash.iter().map(|byte| format!("{:02x}", byte)).collect::<String>();
+        self.path.join(name)
+    }
+
+    pub async fn get(&self, url: &str) -> std::io::Result<Option<CachedPicture>> {
+        let path = self.file_path(url);
+        let file = match tokio::fs::read(&path).await {
+            Ok(file) => file,
+            Err(e) if e.kind() == ErrorKind::NotFound => return Ok(None),
+            Err(e) => return Err(e),
+        };
+        let modified = tokio::fs::metadata(&path).await?.modified()?;
+
+        let mut parts = file.splitn(3, |&b| b == b'\n');
+        let (cached_url, content_type, data) = match (parts.next(), parts.next(), parts.next()) {
+            (Some(url), Some(content_type), Some(data)) => (url, content_type, data),
+            _ => return Ok(None),
+        };
+
+        // Different URLs can have the same hash.
+        if cached_url != url.as_bytes() {
+            return Ok(None);
+        }
+
+        let content_type = match std::str::from_utf8(content_type) {
+            Ok(content_type) => content_type.to_owned(),
+            Err(_) => return Ok(None),
+        };
+
+        let expired = match modified.elapsed() {
+            Ok(age) => age > self.expiry,
+            Err(_) => false,
+        };
+
+        Ok(Some(CachedPicture { content_type, data: data.to_vec(), expired }))
+    }
+
+    /// The URL must not contain a newline.
+    pub async fn put(&self, url: &str, content_type: &str, data: &[u8]) -> std::io::Result<()> {
+        tokio::fs::create_dir_all(&self.path).await?;
+
+        let mut file = Vec::with_capacity(url.len() + content_type.len() + data.len() + 2);
+        file.extend_from_slice(url.as_bytes());
+        file.push(b'\n');
+        file.extend_from_slice(content_type.as_bytes());
+        file.push(b'\n');
+        file.extend_from_slice(data);
+
+        // Writing to a temporary file first so that a request for the same
+        // picture never sees a partially written file.
+        let path = self.file_path(url);
+        let temp_path = path.with_extension(generate_random_base64url(8));
+        tokio::fs::write(&temp_path, file).await?;
+        tokio::fs::rename(&temp_path, &path).await
+    }
+
+    /// Find the files that are older than the expiry. The caller decides
+    /// whether to remove them.
+    pub async fn expired(&self) -> std::io::Result<Vec<ExpiredPicture>> {
+        let mut dir = match tokio::fs::read_dir(&self.path).await {
+            Ok(dir) => dir,
+            Err(e) if e.kind() == ErrorKind::NotFound => return Ok(Vec::new()),
+            Err(e) => return Err(e),
+        };
+
+        let mut expired = Vec::new();
+        while let Some(entry) = dir.next_entry().await? {
+            let modified = entry.metadata().await?.modified()?;
+            if !modified.elapsed().is_ok_and(|age| age > self.expiry) {
+                continue;
+            }
+
+            // Temporary files have an extension. They are left behind if the
+            // server stops while writing one.
+            let path = entry.path();
+            if path.extension().is_some() {
+                expired.push(ExpiredPicture { path, url: None });
+                continue;
+            }
+
+            // Only the first line is needed.
+            let mut start = Vec::new();
+            tokio::fs::File::open(&path).await?
+                .take(MAX_URL_LENGTH as u64 * 4 + 1)
+                .read_to_end(&mut start)
+                .await?;
+            let url = start.split(|&b| b == b'\n')
+                .next()
+                .and_then(|url| std::str::from_utf8(url).ok())
+                .map(str::to_owned);
+            expired.push(ExpiredPicture { path, url });
+        }
+
+        Ok(expired)
+    }
+
+    pub async fn remove(&self, picture: ExpiredPicture) -> std::io::Result<()> {
+        match tokio::fs::remove_file(&picture.path).await {
+            Err(e) if e.kind() != ErrorKind::NotFound => Err(e),
+            _ => Ok(()),
+        }
+    }
+}
+
+/// A file in the cache that is older than the expiry.
+pub struct ExpiredPicture {
+    path: PathBuf,
+    /// The URL of the picture, or None if the file isn't a complete picture.
+    pub url: Option<String>,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::TempDir;
+
+    #[test]
+    fn proxy_url_round_trip() {
+        let picture = "https://example.com/a picture.png?size=64&format=png#top";
+        let proxied = proxy_url(picture);
+        assert!(proxied.starts_with(PROXY_PREFIX));
+        assert!(!proxied[PROXY_PREFIX.len()..].contains(&['&', '#', ' '][..]));
+        assert_eq!(original_url(proxied), picture);
+    }
+
+    #[test]
+    fn local_pictures_are_not_proxied() {
+        assert_eq!(proxy_url(""), "");
+        assert_eq!(proxy_url("/avatar/abc.png"), "/avatar/abc.png");
+        assert_eq!(original_url("/avatar/abc.png".to_owned()), "/avatar/abc.png");
+        assert_eq!(original_url("https://example.com/a.png".to_owned()), "https://example.com/a.png");
+    }
+
+    #[tokio::test]
+    async fn expired_pictures() {
+        let dir = TempDir::new();
+        let cache = PictureCache::new(&PictureProxyConfig {
+            cache_path: dir.path().to_str().unwrap().to_owned(),
+            cache_expiry: 0,
+        });
+        let url = "https://example.com/a.png";
+
+        assert!(cache.get(url).await.unwrap().is_none());
+        cache.put(url, "image/png", b"picture").await.unwrap();
+        let cached = cache.get(url).await.unwrap().unwrap();
+        assert_eq!(cached.content_type, "image/png");
+        assert_eq!(cached.data, b"picture");
+        assert!(cache.get("https://example.com/b.png").await.unwrap().is_none());
+
+        tokio::fs::write(dir.path().join("abc.tmp"), b"partial").await.unwrap();
+        tokio::time::delay_for(Duration::from_millis(10)).await;
+
+        let mut expired = cache.expired().await.unwrap();
+        expired.sort_by(|a, b| a.url.cmp(&b.url));
+        assert_eq!(expired.iter().map(|picture| picture.url.as_deref()).collect::<Vec<_>>(), [None, Some(url)]);
+
+        for picture in expired {
+            cache.remove(picture).await.unwrap();
+        }
+        assert!(cache.get(url).await.unwrap().is_none());
+        assert!(cache.expired().await.unwrap().is_empty());
+    }
+}
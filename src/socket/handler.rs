@@ -1,16 +1,46 @@
 use log::error;
 use warp::ws::Message;
-use std::time::SystemTime;
+use crate::error::Error;
+use std::time::{Duration, Instant, SystemTime};
+use crate::markdown;
+use crate::unfurl::{self, Unfurler};
 use crate::database as db;
+use deadpool_postgres::Pool;
+use crate::utils::as_timestamp;
 use serde::{Serialize, Deserialize};
-use deadpool_postgres::{Pool, PoolError};
-use super::upgrade::{ConnID, Sender, Group, Groups, UserGroups};
+use deadpool_postgres::tokio_postgres::Row;
+use super::upgrade::{ConnID, Sender, Group, Groups, OnlineUser, Users, Typing, leave_group};
+
+/// A connection's typing notifications are only fanned out once within this
+/// period for each channel. Clients should send them at about this rate while
+/// the user is typing.
+const TYPING_THROTTLE: Duration = Duration::from_secs(3);
+
+/// Typing state older than this is forgotten. Clients should stop showing a
+/// typing indicator after this long without another UserTyping.
+const TYPING_EXPIRY: Duration = Duration::from_secs(6);
+
+/// The maximum number of channels that a client can catch up on with a single
+/// Resume.
+const MAX_RESUME_POINTS: usize = 256;
+
+#[derive(Deserialize)]
+struct ResumePoint {
+    channel_id: db::ChannelID,
+    message_id: db::MessageID,
+}
 
 #[derive(Deserialize)]
 #[serde(tag="type")]
 #[serde(rename_all="snake_case")]
 enum ClientMessage {
-    CreateMessage { content: String, channel_id: db::ChannelID },
+    CreateMessage {
+        content: String,
+        channel_id: db::ChannelID,
+        reply_to: Option<db::MessageID>,
+        #[serde(default)]
+        attachments: Vec<db::AttachmentID>,
+    },
     RequestRecentMessages { channel_id: db::ChannelID },
     RequestOldMessages { channel_id: db::ChannelID, message_id: db::MessageID },
     CreateChannel { name: String },
@@ -19,15 +49,60 @@ enum ClientMessage {
     RenameChannel { channel_id: db::ChannelID, name: String },
     RequestUsers,
     RenameGroup { name: String, picture: String },
+    EditMessage { message_id: db::MessageID, content: String },
+    DeleteMessage { message_id: db::MessageID },
+    SetRole { user_id: db::UserID, role: db::Role },
+    KickUser { user_id: db::UserID, #[serde(default)] ban: bool },
+    SearchMessages { query: String, channel_id: Option<db::ChannelID>, before: Option<db::MessageID> },
+    RequestNewMessages { channel_id: db::ChannelID, after: db::MessageID },
+    RequestMessagesAround { channel_id: db::ChannelID, message_id: db::MessageID },
+    Resume { channels: Vec<ResumePoint> },
+    RequestThread { message_id: db::MessageID },
+    AddReaction { message_id: db::MessageID, emoji: String },
+    RemoveReaction { message_id: db::MessageID, emoji: String },
+    MarkRead { channel_id: db::ChannelID, message_id: db::MessageID },
+    PinMessage { message_id: db::MessageID },
+    UnpinMessage { message_id: db::MessageID },
+    RequestPinnedMessages { channel_id: db::ChannelID },
+    Typing { channel_id: db::ChannelID },
+    SetPresence { presence: db::Presence, status_text: Option<String> },
+    RequestMentions { before: Option<db::MessageID> },
+    CreateDirectMessage { content: String, conversation_id: db::ConversationID },
+    RequestDirectMessages { conversation_id: db::ConversationID, before: Option<db::MessageID> },
 }
 
+/// Every message sent by the client names the group that it applies to.
+/// Messages that don't apply to a particular group (such as direct messages)
+/// may omit the group_id.
+#[derive(Deserialize)]
+struct ClientEnvelope {
+    group_id: Option<db::GroupID>,
+    #[serde(flatten)]
+    message: ClientMessage,
+}
+
+/// A message in a channel has a channel_id. A direct message has a
+/// conversation_id instead.
 #[derive(Serialize)]
 struct RecentMessage {
     message_id: db::MessageID,
     timestamp: u64,
     author: db::UserID,
     content: String,
-    channel_id: db::ChannelID,
+    html: String,
+    channel_id: Option<db::ChannelID>,
+    conversation_id: Option<db::ConversationID>,
+    reply_to: Option<db::MessageID>,
+    mentions: db::Mentions,
+    attachments: Vec<db::Attachment>,
+}
+
+#[derive(Serialize)]
+struct ReactionCount {
+    emoji: String,
+    count: i64,
+    /// Whether the current user reacted with this emoji.
+    me: bool,
 }
 
 #[derive(Serialize)]
@@ -36,21 +111,117 @@ struct GenericRecentMessage {
     timestamp: u64,
     author: db::UserID,
     content: String,
+    html: String,
+    edited_at: Option<u64>,
+    reply_to: Option<db::MessageID>,
+    reactions: Vec<ReactionCount>,
+    mentions: db::Mentions,
+    attachments: Vec<db::Attachment>,
+    preview: Option<db::LinkPreview>,
+}
+
+/// Messages created before the HTML was stored don't have it.
+fn stored_html(html: Option<String>, content: &str) -> String {
+    html.unwrap_or_else(|| markdown::render(content))
+}
+
+impl GenericRecentMessage {
+    /// Create from a row returned by db::recent_messages or db::old_messages.
+    fn from_row(row: &Row) -> Self {
+        let content: String = row.get(3);
+        Self {
+            message_id: row.get(0),
+            timestamp: as_timestamp(row.get(1)),
+            author: row.get(2),
+            html: stored_html(row.get(4), &content),
+            content,
+            edited_at: row.get::<_, Option<SystemTime>>(5).map(as_timestamp),
+            reply_to: row.get(6),
+            reactions: Vec::new(),
+            mentions: db::Mentions::default(),
+            attachments: Vec::new(),
+            preview: None,
+        }
+    }
+}
+
+#[derive(Serialize)]
+struct MentionResult {
+    group_id: db::GroupID,
+    channel_id: db::ChannelID,
+    #[serde(flatten)]
+    message: GenericRecentMessage,
+}
+
+#[derive(Serialize)]
+struct SearchResult {
+    message_id: db::MessageID,
+    timestamp: u64,
+    author: db::UserID,
+    content: String,
+    html: String,
+    edited_at: Option<u64>,
+    reply_to: Option<db::MessageID>,
+    channel_id: db::ChannelID,
+}
+
+impl SearchResult {
+    /// Create from a row returned by db::search_messages.
+    fn from_row(row: &Row) -> Self {
+        let content: String = row.get(3);
+        Self {
+            message_id: row.get(0),
+            timestamp: as_timestamp(row.get(1)),
+            author: row.get(2),
+            html: stored_html(row.get(4), &content),
+            content,
+            edited_at: row.get::<_, Option<SystemTime>>(5).map(as_timestamp),
+            reply_to: row.get(6),
+            channel_id: row.get(7),
+        }
+    }
 }
 
 #[derive(Serialize)]
 #[serde(rename_all="snake_case")]
 enum UserStatus {
     Online,
+    Away,
+    DoNotDisturb,
     Offline,
 }
 
+impl UserStatus {
+    /// The status that other users see for a user that is connected.
+    /// Invisible users appear to be offline.
+    fn from_presence(presence: db::Presence) -> Self {
+        match presence {
+            db::Presence::Online => UserStatus::Online,
+            db::Presence::Away => UserStatus::Away,
+            db::Presence::DoNotDisturb => UserStatus::DoNotDisturb,
+            db::Presence::Invisible => UserStatus::Offline,
+        }
+    }
+}
+
+/// The custom status text that other users see for a user that is connected.
+fn visible_status_text(presence: &db::UserPresence) -> Option<&String> {
+    match presence.presence {
+        db::Presence::Invisible => None,
+        _ => presence.status_text.as_ref()
+    }
+}
+
 #[derive(Serialize)]
 struct User {
     user_id: db::UserID,
     name: String,
+    #[serde(serialize_with = "crate::proxy::serialize_picture")]
     picture: String,
+    role: db::Role,
     status: UserStatus,
+    status_text: Option<String>,
+    last_seen: Option<u64>,
 }
 
 #[derive(Serialize)]
@@ -62,6 +233,8 @@ enum ErrorCategory {
     ChannelRename,
     ChannelDelete,
     GroupRename,
+    RoleChange,
+    UserKick,
 }
 
 use ErrorCategory::*;
@@ -72,11 +245,25 @@ enum ErrorCode {
     Json,
     Database,
     ChannelIdInvalid,
+    MessageIdInvalid,
     MessageInvalid,
     NameInvalid,
     NameExists,
     LoneChannel,
     PictureInvalid,
+    PermissionDenied,
+    UserIdInvalid,
+    RoleInvalid,
+    QueryInvalid,
+    ReplyToInvalid,
+    EmojiInvalid,
+    ConversationIdInvalid,
+    GroupIdInvalid,
+    StatusTextInvalid,
+    PinLimitReached,
+    ResumeLimitReached,
+    ReactionLimitReached,
+    AttachmentIdInvalid,
 }
 
 use ErrorCode::*;
@@ -86,24 +273,91 @@ use ErrorCode::*;
 #[serde(rename_all="snake_case")]
 enum ServerMessage<'a> {
     Error { category: ErrorCategory, code: ErrorCode },
-    MessageReceipt { message_id: db::MessageID, timestamp: u64, channel_id: db::ChannelID },
+    MessageReceipt {
+        message_id: db::MessageID,
+        timestamp: u64,
+        channel_id: Option<db::ChannelID>,
+        conversation_id: Option<db::ConversationID>,
+        html: String,
+    },
+    MessageEdited {
+        message_id: db::MessageID,
+        channel_id: db::ChannelID,
+        content: String,
+        html: String,
+        edited_at: u64,
+        mentions: db::Mentions,
+    },
+    MessageDeleted { channel_id: db::ChannelID, message_id: db::MessageID },
+    MessagePreviewReady { channel_id: db::ChannelID, message_id: db::MessageID, preview: db::LinkPreview },
     RecentMessage(RecentMessage),
     RecentMessageList { channel_id: db::ChannelID, messages: Vec<GenericRecentMessage> },
     OldMessageList { channel_id: db::ChannelID, messages: Vec<GenericRecentMessage> },
+    NewMessageList { channel_id: db::ChannelID, messages: Vec<GenericRecentMessage> },
+    ResumeComplete,
+    MessagesAroundList {
+        channel_id: db::ChannelID,
+        message_id: db::MessageID,
+        messages: Vec<GenericRecentMessage>,
+        more_before: bool,
+        more_after: bool,
+    },
+    DirectMessageList { conversation_id: db::ConversationID, messages: Vec<GenericRecentMessage> },
+    ReactionAdded { channel_id: db::ChannelID, message_id: db::MessageID, user_id: db::UserID, emoji: String },
+    ReactionRemoved { channel_id: db::ChannelID, message_id: db::MessageID, user_id: db::UserID, emoji: String },
+    ThreadMessageList {
+        root_id: db::MessageID,
+        channel_id: db::ChannelID,
+        messages: Vec<GenericRecentMessage>,
+        more: bool,
+    },
+    MessagePinned { channel_id: db::ChannelID, message_id: db::MessageID, user_id: db::UserID, timestamp: u64 },
+    MessageUnpinned { channel_id: db::ChannelID, message_id: db::MessageID },
+    PinnedMessageList { channel_id: db::ChannelID, messages: Vec<GenericRecentMessage> },
     ChannelCreated { channel_id: db::ChannelID, name: &'a String },
-    ChannelList { channels: &'a Vec<db::Channel> },
+    ChannelList { channels: &'a Vec<db::Channel>, read_states: Vec<db::ReadState> },
+    ReadMarkerChanged { channel_id: db::ChannelID, message_id: db::MessageID },
+    UserTyping { channel_id: db::ChannelID, user_id: db::UserID },
     ChannelDeleted { channel_id: db::ChannelID },
     ChannelRenamed { channel_id: db::ChannelID, name: &'a String },
     UserList { users: Vec<User> },
-    UserStatusChanged { user_id: db::UserID, status: UserStatus },
-    UserRenamed { user_id: db::UserID, name: &'a String, picture: &'a String },
+    UserStatusChanged {
+        user_id: db::UserID,
+        status: UserStatus,
+        status_text: Option<&'a String>,
+        last_seen: Option<u64>,
+    },
+    PresenceChanged { presence: db::Presence, status_text: Option<&'a String> },
+    UserRenamed {
+        user_id: db::UserID,
+        name: &'a String,
+        #[serde(serialize_with = "crate::proxy::serialize_picture")]
+        picture: &'a String,
+    },
     UserDeleted { user_id: db::UserID },
-    GroupRenamed { group_id: db::GroupID, name: String, picture: String },
-    GroupDeleted { group_id: db::GroupID },
+    UserRoleChanged { user_id: db::UserID, role: db::Role },
+    MentionList { messages: Vec<MentionResult>, more: bool },
+    SearchResultList { query: String, channel_id: Option<db::ChannelID>, messages: Vec<SearchResult>, more: bool },
+    GroupRenamed {
+        name: String,
+        #[serde(serialize_with = "crate::proxy::serialize_picture")]
+        picture: String,
+    },
+    GroupDeleted,
+    GroupLeft,
+}
+
+/// Every message sent to the client names the group that it applies to. The
+/// group_id is null for messages that don't apply to a particular group.
+#[derive(Serialize)]
+struct ServerEnvelope<'a> {
+    group_id: Option<db::GroupID>,
+    #[serde(flatten)]
+    message: ServerMessage<'a>,
 }
 
-fn as_timestamp(time: SystemTime) -> u64 {
-    time.duration_since(SystemTime::UNIX_EPOCH).unwrap().as_secs()
+fn encode(group_id: Option<db::GroupID>, message: ServerMessage) -> String {
+    serde_json::to_string(&ServerEnvelope { group_id, message }).unwrap()
 }
 
 fn send_message(ch_tx: &Sender, message: String) {
@@ -124,9 +378,13 @@ impl Group {
         self.find_channel(channel_id) != usize::MAX
     }
 
+    fn encode(&self, message: ServerMessage) -> String {
+        encode(Some(self.group_id), message)
+    }
+
     /// Send a message to all connections.
     fn send_all(&self, message: ServerMessage) {
-        let response = serde_json::to_string(&message).unwrap();
+        let response = self.encode(message);
         for (_, ch_tx) in self.connections.iter() {
             send_message(ch_tx, response.clone());
         }
@@ -135,8 +393,8 @@ impl Group {
     /// Send a peer message to all connections but the current connection.
     /// Send a reply message to the current connection.
     fn send_peer_reply(&self, conn_id: ConnID, peer: ServerMessage, reply: ServerMessage) {
-        let peer_response = serde_json::to_string(&peer).unwrap();
-        let reply_response = serde_json::to_string(&reply).unwrap();
+        let peer_response = self.encode(peer);
+        let reply_response = self.encode(reply);
         for (&other_conn_id, ch_tx) in self.connections.iter() {
             if other_conn_id == conn_id {
                 send_message(ch_tx, reply_response.clone());
@@ -146,10 +404,36 @@ impl Group {
         }
     }
 
+    /// Send a peer message to all connections but the current connection.
+    fn send_peer(&self, conn_id: ConnID, peer: ServerMessage) {
+        let peer_response = self.encode(peer);
+        for (&other_conn_id, ch_tx) in self.connections.iter() {
+            if other_conn_id != conn_id {
+                send_message(ch_tx, peer_response.clone());
+            }
+        }
+    }
+
     /// Send a reply message to the current connection.
     fn send_reply(&self, conn_id: ConnID, message: ServerMessage) {
-        let sender = &self.connections[&conn_id];
-        send_message(sender, serde_json::to_string(&message).unwrap());
+        // The connection may have been unsubscribed from the group while the
+        // request was being handled.
+        if let Some(sender) = self.connections.get(&conn_id) {
+            send_message(sender, self.encode(message));
+        }
+    }
+
+    /// Send a message to all of a user's connections but the current
+    /// connection.
+    fn send_user_peer(&self, user_id: db::UserID, conn_id: ConnID, message: ServerMessage) {
+        if let Some(conn_ids) = self.online_users.get(&user_id) {
+            let response = self.encode(message);
+            for other_conn_id in conn_ids.iter() {
+                if *other_conn_id != conn_id {
+                    send_message(&self.connections[other_conn_id], response.clone());
+                }
+            }
+        }
     }
 
     /// Send a reply error to the current connection
@@ -159,19 +443,53 @@ impl Group {
         });
     }
 
-    fn send_user_status(&self, user_id: db::UserID, status: UserStatus) {
+    /// Record that the user of a connection is typing in a channel and
+    /// forget any typing state that has expired.
+    ///
+    /// Returns false if the connection has already reported typing in this
+    /// channel recently so the notification should be dropped.
+    fn update_typing(&self, conn_id: ConnID, channel_id: db::ChannelID) -> bool {
+        let now = Instant::now();
+        let mut typing_map = self.typing.lock().unwrap();
+        typing_map.retain(|_, typing| now.duration_since(typing.time) < TYPING_EXPIRY);
+
+        if let Some(typing) = typing_map.get(&conn_id) {
+            if typing.channel_id == channel_id && now.duration_since(typing.time) < TYPING_THROTTLE {
+                return false;
+            }
+        }
+
+        typing_map.insert(conn_id, Typing { channel_id, time: now });
+        true
+    }
+
+    fn send_user_status(
+        &self,
+        user_id: db::UserID,
+        status: UserStatus,
+        status_text: Option<&String>,
+        last_seen: Option<u64>
+    ) {
         self.send_all(ServerMessage::UserStatusChanged {
             user_id,
             status,
+            status_text,
+            last_seen,
         });
     }
 
-    pub fn send_user_online(&self, user_id: db::UserID) {
-        self.send_user_status(user_id, UserStatus::Online);
+    /// Tell everyone about the presence of a user that is connected.
+    pub fn send_user_presence(&self, user_id: db::UserID, presence: &db::UserPresence) {
+        self.send_user_status(
+            user_id,
+            UserStatus::from_presence(presence.presence),
+            visible_status_text(presence),
+            None
+        );
     }
 
-    pub fn send_user_offline(&self, user_id: db::UserID) {
-        self.send_user_status(user_id, UserStatus::Offline);
+    pub fn send_user_offline(&self, user_id: db::UserID, last_seen: SystemTime) {
+        self.send_user_status(user_id, UserStatus::Offline, None, Some(as_timestamp(last_seen)));
     }
 
     pub fn send_user_renamed(&self, user_id: db::UserID, name: &String, picture: &String) {
@@ -182,281 +500,1266 @@ impl Group {
         })
     }
 
-    pub fn kick_user(&self, user_id: db::UserID) {
-        let message = Message::close_with(4000u16, "kick");
-        for conn_id in self.online_users[&user_id].iter() {
-            if self.connections[conn_id].send(Ok(message.clone())).is_err() {}
-        }
+    pub fn send_delete_group(&self) {
+        self.send_all(ServerMessage::GroupDeleted);
     }
 
-    pub fn send_delete_group(&self, user_id: db::UserID, group_id: db::GroupID) {
-        let message = serde_json::to_string(&ServerMessage::GroupDeleted {
-            group_id
-        }).unwrap();
-        for conn_id in self.online_users[&user_id].iter() {
-            send_message(&self.connections[conn_id], message.clone());
+    pub fn send_delete_user(&self, user_id: db::UserID) {
+        self.send_all(ServerMessage::UserDeleted { user_id });
+    }
+}
+
+impl OnlineUser {
+    /// Send a message to all of the user's connections.
+    fn send_all(&self, group_id: Option<db::GroupID>, message: ServerMessage) {
+        let response = encode(group_id, message);
+        for (_, ch_tx) in self.connections.iter() {
+            send_message(ch_tx, response.clone());
         }
     }
 
-    pub fn send_delete_user(&self, user_id: db::UserID) {
-        self.send_all(ServerMessage::UserDeleted { user_id });
+    pub fn send_group_left(&self, group_id: db::GroupID) {
+        self.send_all(Some(group_id), ServerMessage::GroupLeft);
+    }
+
+    /// Close all of the user's connections.
+    pub fn kick(&self) {
+        let message = Message::close_with(4000u16, "kick");
+        for (_, ch_tx) in self.connections.iter() {
+            if ch_tx.send(Ok(message.clone())).is_err() {}
+        }
     }
 }
 
-pub struct MessageContext<'a> {
+/// A message received from the client is handled by SocketContext if it
+/// doesn't apply to a particular group. Otherwise, it is handled by
+/// MessageContext.
+pub struct SocketContext<'a> {
     pub user_id: db::UserID,
-    pub group_id: db::GroupID,
     pub conn_id: ConnID,
+    pub sender: Sender,
     pub groups: &'a Groups,
-    pub user_groups: &'a UserGroups,
+    pub users: &'a Users,
     pub pool: &'a Pool,
+    pub unfurler: &'a Unfurler,
 }
 
-impl<'a> MessageContext<'a> {
+/// The group may be removed from the group map while a message is being
+/// handled (if the user leaves or the group is deleted) so the handlers don't
+/// assume that it exists.
+struct MessageContext<'a> {
+    user_id: db::UserID,
+    group_id: db::GroupID,
+    conn_id: ConnID,
+    groups: &'a Groups,
+    users: &'a Users,
+    pool: &'a Pool,
+    unfurler: &'a Unfurler,
+}
+
+/// Create a list of messages from rows returned by db::recent_messages or
+/// similar and attach the reactions, mentions and attachments to each message.
+async fn message_list<'r, I>(pool: &Pool, user_id: db::UserID, rows: I)
+    -> Result<Vec<GenericRecentMessage>, Error>
+    where I: Iterator<Item=&'r Row>
+{
+    let mut messages = rows.map(GenericRecentMessage::from_row).collect::<Vec<_>>();
+    if messages.is_empty() {
+        return Ok(messages);
+    }
+
+    let message_ids = messages.iter().map(|m| m.message_id).collect::<Vec<_>>();
+    let reactions = db::message_reactions(pool.clone(), user_id, &message_ids).await?;
+    let indices = message_ids.iter()
+        .enumerate()
+        .map(|(index, &message_id)| (message_id, index))
+        .collect::<std::collections::HashMap<_, _>>();
+
+    for row in reactions.iter() {
+        let message_id: db::MessageID = row.get(0);
+        messages[indices[&message_id]].reactions.push(ReactionCount {
+            emoji: row.get(1),
+            count: row.get(2),
+            me: row.get(3),
+        });
+    }
+
+    let mentions = db::message_mentions(pool.clone(), &message_ids).await?;
+    for row in mentions.iter() {
+        let message_id: db::MessageID = row.get(0);
+        let message_mentions = &mut messages[indices[&message_id]].mentions;
+        if let Some(user_id) = row.get(1) {
+            message_mentions.user_ids.push(user_id);
+        }
+        if let Some(channel_id) = row.get(2) {
+            message_mentions.channel_ids.push(channel_id);
+        }
+    }
+
+    let attachments = db::message_attachments(pool.clone(), &message_ids).await?;
+    for (message_id, attachment) in attachments {
+        messages[indices[&message_id]].attachments.push(attachment);
+    }
+
+    let previews = db::message_link_previews(pool.clone(), &message_ids).await?;
+    for (message_id, preview) in previews {
+        messages[indices[&message_id]].preview = Some(preview);
+    }
+
+    Ok(messages)
+}
+
+/// Fetch a preview of a link in a message and send it to the group once it's
+/// ready. Links that can't be previewed are ignored.
+async fn unfurl_message(
+    pool: Pool,
+    groups: Groups,
+    unfurler: Unfurler,
+    group_id: db::GroupID,
+    channel_id: db::ChannelID,
+    message_id: db::MessageID,
+    url: String,
+) {
+    // The fetch time of a reused preview is kept so that it isn't reused
+    // forever.
+    let since = SystemTime::now() - unfurl::PREVIEW_REUSE_TIME;
+    let (fetch_time, preview) = match db::recent_link_preview(pool.clone(), &url, since).await {
+        Ok(Some(recent)) => recent,
+        Ok(None) => match unfurler.unfurl(&url).await {
+            Some(preview) => (SystemTime::now(), preview),
+            None => return,
+        },
+        Err(e) => {
+            error!("{}", e);
+            return;
+        }
+    };
+
+    // The message may have been deleted while the page was being fetched.
+    match db::set_link_preview(pool, fetch_time, message_id, &preview).await {
+        Ok(true) => {},
+        Ok(false) => return,
+        Err(e) => {
+            error!("{}", e);
+            return;
+        }
+    }
+
+    if let Some(group) = groups.read().await.get(&group_id) {
+        group.send_all(ServerMessage::MessagePreviewReady { channel_id, message_id, preview });
+    }
+}
+
+impl<'a> SocketContext<'a> {
     pub async fn handle(&self, message: Message) {
         let message = match message.to_str() {
             Ok(m) => m,
             Err(_) => return,
         };
 
-        let client_message = match serde_json::from_str::<ClientMessage>(message) {
+        let envelope = match serde_json::from_str::<ClientEnvelope>(message) {
             Ok(m) => m,
             Err(e) => {
                 error!("{}", e);
-                let group = &self.groups.read().await[&self.group_id];
-                group.send_reply_error(self.conn_id, Request, Json);
+                self.send_reply_error(None, Request, Json);
                 return;
             }
         };
 
-        let result = match client_message {
-            ClientMessage::CreateMessage { content, channel_id } =>
-                self.create_message(content, channel_id).await,
-            ClientMessage::RequestRecentMessages { channel_id } =>
-                self.request_recent_messages(channel_id).await,
-            ClientMessage::RequestOldMessages { channel_id, message_id } =>
-                self.request_old_messages(channel_id, message_id).await,
-            ClientMessage::CreateChannel { name } =>
-                self.create_channel(name).await,
-            ClientMessage::RequestChannels =>
-                self.request_channels().await,
-            ClientMessage::DeleteChannel { channel_id } =>
-                self.delete_channel(channel_id).await,
-            ClientMessage::RequestUsers =>
-                self.request_users().await,
-            ClientMessage::RenameChannel { channel_id, name } =>
-                self.rename_channel(channel_id, name).await,
-            ClientMessage::RenameGroup { name, picture } =>
-                self.rename_group(name, picture).await,
+        let group_id = envelope.group_id;
+
+        let result = match envelope.message {
+            ClientMessage::CreateDirectMessage { content, conversation_id } =>
+                self.create_direct_message(content, conversation_id).await,
+            ClientMessage::RequestDirectMessages { conversation_id, before } =>
+                self.request_direct_messages(conversation_id, before).await,
+            ClientMessage::Resume { channels } =>
+                self.resume(channels).await,
+            ClientMessage::SetPresence { presence, status_text } =>
+                self.set_presence(presence, status_text).await,
+            ClientMessage::RequestMentions { before } =>
+                self.request_mentions(before).await,
+            client_message => match group_id {
+                Some(group_id) if self.subscribed(group_id).await => {
+                    let message_ctx = MessageContext {
+                        user_id: self.user_id,
+                        group_id,
+                        conn_id: self.conn_id,
+                        groups: self.groups,
+                        users: self.users,
+                        pool: self.pool,
+                        unfurler: self.unfurler,
+                    };
+                    message_ctx.handle(client_message).await
+                },
+                _ => {
+                    self.send_reply_error(group_id, Request, GroupIdInvalid);
+                    Ok(())
+                }
+            }
         };
 
         if let Err(e) = result {
             error!("{}", e);
-            let group = &self.groups.read().await[&self.group_id];
-            group.send_reply_error(self.conn_id, Application, Database);
+            self.send_reply_error(group_id, Application, Database);
         }
     }
 
-    async fn create_message(&self, content: String, channel_id: db::ChannelID)
-        -> Result<(), PoolError>
+    async fn create_direct_message(&self, content: String, conversation_id: db::ConversationID)
+        -> Result<(), Error>
     {
         let time = SystemTime::now();
         let timestamp = as_timestamp(time);
 
-        let groups_guard = self.groups.read().await;
-        let group = &groups_guard[&self.group_id];
-
         if !db::valid_message(&content) {
-            group.send_reply_error(self.conn_id, Request, MessageInvalid);
+            self.send_reply_error(None, Request, MessageInvalid);
             return Ok(());
         }
 
-        if !group.contains_channel(channel_id) {
-            group.send_reply_error(self.conn_id, Request, ChannelIdInvalid);
+        let users = db::conversation_users(self.pool.clone(), conversation_id).await?;
+
+        if !users.contains(&self.user_id) {
+            self.send_reply_error(None, Request, ConversationIdInvalid);
             return Ok(());
         }
 
-        let message_id = db::create_message(self.pool.clone(), time, self.user_id, &content, channel_id).await?;
+        let html = markdown::render(&content);
 
-        let peer = ServerMessage::RecentMessage(RecentMessage {
+        let message_id = db::create_direct_message(
+            self.pool.clone(), time, self.user_id, &content, &html, conversation_id
+        ).await?;
+
+        let echo = encode(None, ServerMessage::MessageReceipt {
             message_id,
             timestamp,
-            author: self.user_id,
-            content,
-            channel_id,
+            channel_id: None,
+            conversation_id: Some(conversation_id),
+            html: html.clone(),
         });
 
-        let echo = ServerMessage::MessageReceipt {
+        let peer = encode(None, ServerMessage::RecentMessage(RecentMessage {
             message_id,
             timestamp,
-            channel_id,
-        };
-
-        group.send_peer_reply(self.conn_id, peer, echo);
-
-        Ok(())
-    }
+            author: self.user_id,
+            content,
+            html,
+            channel_id: None,
+            conversation_id: Some(conversation_id),
+            reply_to: None,
+            mentions: db::Mentions::default(),
+            attachments: Vec::new(),
+        }));
 
-    async fn request_recent_messages(&self, channel_id: db::ChannelID)
-        -> Result<(), PoolError>
-    {
-        let groups_guard = self.groups.read().await;
-        let group = &groups_guard[&self.group_id];
+        let users_guard = self.users.read().await;
 
-        if !group.contains_channel(channel_id) {
-            group.send_reply_error(self.conn_id, Request, ChannelIdInvalid);
-            return Ok(());
+        for user_id in users.iter() {
+            if let Some(user) = users_guard.get(user_id) {
+                for (conn_id, ch_tx) in user.connections.iter() {
+                    if *conn_id == self.conn_id {
+                        send_message(ch_tx, echo.clone());
+                    } else {
+                        send_message(ch_tx, peer.clone());
+                    }
+                }
+            }
         }
 
-        let rows = db::recent_messages(self.pool.clone(), channel_id).await?;
-
-        group.send_reply(self.conn_id, ServerMessage::RecentMessageList {
-            channel_id,
-            messages: rows.iter()
-                .map(|row| GenericRecentMessage {
-                    message_id: row.get(0),
-                    timestamp: as_timestamp(row.get(1)),
-                    author: row.get(2),
-                    content: row.get(3)
-                })
-                .collect()
-        });
-
         Ok(())
     }
 
-    async fn request_old_messages(&self, channel_id: db::ChannelID, message_id: db::MessageID)
-        -> Result<(), PoolError>
+    async fn request_direct_messages(&self, conversation_id: db::ConversationID, before: Option<db::MessageID>)
+        -> Result<(), Error>
     {
-        let groups_guard = self.groups.read().await;
-        let group = &groups_guard[&self.group_id];
+        let users = db::conversation_users(self.pool.clone(), conversation_id).await?;
 
-        if !group.contains_channel(channel_id) {
-            group.send_reply_error(self.conn_id, Request, ChannelIdInvalid);
+        if !users.contains(&self.user_id) {
+            self.send_reply_error(None, Request, ConversationIdInvalid);
             return Ok(());
         }
 
-        let rows = db::old_messages(self.pool.clone(), channel_id, message_id).await?;
+        let rows = db::direct_messages(self.pool.clone(), conversation_id, before).await?;
 
-        group.send_reply(self.conn_id, ServerMessage::OldMessageList {
-            channel_id,
-            messages: rows.iter()
-                .map(|row| GenericRecentMessage {
-                    message_id: row.get(0),
-                    timestamp: as_timestamp(row.get(1)),
-                    author: row.get(2),
-                    content: row.get(3)
-                })
-                .collect()
+        self.send_reply(None, ServerMessage::DirectMessageList {
+            conversation_id,
+            messages: message_list(self.pool, self.user_id, rows.iter()).await?
         });
 
         Ok(())
     }
 
-    async fn create_channel(&self, name: String) -> Result<(), PoolError> {
-        let mut groups_guard = self.groups.write().await;
-        let group = &mut groups_guard.get_mut(&self.group_id).unwrap();
-
-        if !db::valid_channel_name(&name) {
-            // This shouldn't happen unless someone is bypassing the JavaScript
-            // validation.
-            group.send_reply_error(self.conn_id, ChannelCreate, NameInvalid);
+    /// Sent by a client after reconnecting. For each channel, the client gives
+    /// the ID of the last message that it received. The client is sent every
+    /// message after that, in pages, followed by ResumeComplete.
+    async fn resume(&self, channels: Vec<ResumePoint>) -> Result<(), Error> {
+        if channels.len() > MAX_RESUME_POINTS {
+            self.send_reply_error(None, Request, ResumeLimitReached);
             return Ok(());
         }
 
-        let channel_id = match db::create_channel(self.pool.clone(), self.group_id, &name).await? {
-            Some(id) => id,
-            None => {
-                group.send_reply_error(self.conn_id, ChannelCreate, NameExists);
-                return Ok(());
-            }
+        // Find the group of each channel up front so that the lock isn't held
+        // while the messages are being fetched. The channel may have been
+        // deleted while the client was disconnected. The client will find out
+        // when it requests the channel list.
+        let points = {
+            let groups_guard = self.groups.read().await;
+            let channel_groups = groups_guard.values()
+                .filter(|group| group.connections.contains_key(&self.conn_id))
+                .flat_map(|group| group.channels.iter().map(move |channel| (channel.channel_id, group.group_id)))
+                .collect::<std::collections::HashMap<_, _>>();
+            channels.iter()
+                .filter_map(|point| channel_groups.get(&point.channel_id).map(|&group_id| (group_id, point)))
+                .collect::<Vec<_>>()
         };
 
-        group.send_all(ServerMessage::ChannelCreated {
-            channel_id,
-            name: &name,
-        });
-
-        group.channels.push(db::Channel {
-            channel_id,
-            name
-        });
-
-        Ok(())
-    }
+        for (group_id, point) in points {
+            let mut message_id = point.message_id;
+
+            loop {
+                let rows = db::new_messages(self.pool.clone(), point.channel_id, message_id).await?;
+                let full_page = rows.len() == db::MESSAGE_PAGE_LIMIT;
+
+                message_id = match rows.last() {
+                    Some(last) => last.get(0),
+                    None => break
+                };
+
+                let messages = message_list(self.pool, self.user_id, rows.iter()).await?;
+
+                // The user may have left the group while the messages were
+                // being fetched.
+                match self.groups.read().await.get(&group_id) {
+                    Some(group) if group.contains_channel(point.channel_id) => {
+                        group.send_reply(self.conn_id, ServerMessage::NewMessageList {
+                            channel_id: point.channel_id,
+                            messages,
+                        });
+                    },
+                    _ => break
+                }
 
-    async fn request_channels(&self) -> Result<(), PoolError> {
-        let groups_guard = self.groups.read().await;
-        let group = &groups_guard[&self.group_id];
+                if !full_page {
+                    break;
+                }
+            }
+        }
 
-        group.send_reply(self.conn_id, ServerMessage::ChannelList {
-            channels: &group.channels
-        });
+        self.send_reply(None, ServerMessage::ResumeComplete);
 
         Ok(())
     }
 
-    async fn delete_channel(&self, channel_id: db::ChannelID) -> Result<(), PoolError> {
-        let mut groups_guard = self.groups.write().await;
-        let group = &mut groups_guard.get_mut(&self.group_id).unwrap();
-
-        if group.channels.len() == 1 {
-            group.send_reply_error(self.conn_id, ChannelDelete, LoneChannel);
-            return Ok(());
+    /// Change the presence of the current user and tell everyone in the
+    /// user's groups about it. The user's own connections are told the actual
+    /// presence so that an invisible user doesn't see themself as offline.
+    async fn set_presence(&self, presence: db::Presence, status_text: Option<String>) -> Result<(), Error> {
+        if let Some(text) = status_text.as_ref() {
+            if !db::valid_status_text(text) {
+                self.send_reply_error(None, Request, StatusTextInvalid);
+                return Ok(());
+            }
         }
 
-        let channel_index = group.find_channel(channel_id);
-        if channel_index == usize::MAX {
-            group.send_reply_error(self.conn_id, Request, ChannelIdInvalid);
-            return Ok(());
-        }
+        db::set_presence(self.pool.clone(), self.user_id, presence, &status_text).await?;
 
-        if !db::delete_channel(self.pool.clone(), channel_id).await? {
-            // If the above checks pass then this cannot happen
-            group.send_reply_error(self.conn_id, Request, ChannelIdInvalid);
-            return Ok(());
+        // Going invisible looks the same as disconnecting.
+        let time = SystemTime::now();
+        if presence == db::Presence::Invisible {
+            db::set_last_seen(self.pool.clone(), self.user_id, time).await?;
         }
 
-        group.channels.remove(channel_index);
+        let groups_guard = self.groups.read().await;
+        let mut users_guard = self.users.write().await;
+        let user = match users_guard.get_mut(&self.user_id) {
+            Some(user) => user,
+            None => return Ok(())
+        };
 
-        group.send_all(ServerMessage::ChannelDeleted {
-            channel_id
+        let was_invisible = user.presence.presence == db::Presence::Invisible;
+        user.presence = db::UserPresence { presence, status_text };
+
+        for group_id in user.groups.iter() {
+            if let Some(group) = groups_guard.get(group_id) {
+                if presence != db::Presence::Invisible {
+                    group.send_user_presence(self.user_id, &user.presence);
+                } else if !was_invisible {
+                    group.send_user_offline(self.user_id, time);
+                }
+            }
+        }
+
+        user.send_all(None, ServerMessage::PresenceChanged {
+            presence,
+            status_text: user.presence.status_text.as_ref(),
         });
 
         Ok(())
     }
 
-    async fn request_users(&self) -> Result<(), PoolError> {
-        let groups_guard = self.groups.read().await;
-        let group = &groups_guard[&self.group_id];
-
-        let group_users = db::group_users(self.pool.clone(), self.group_id).await?;
-        let mut users = Vec::new();
+    async fn request_mentions(&self, before: Option<db::MessageID>) -> Result<(), Error> {
+        let rows = db::user_mentions(self.pool.clone(), self.user_id, before).await?;
+        let more = rows.len() > db::MENTION_LIMIT;
+        let rows = &rows[..rows.len().min(db::MENTION_LIMIT)];
 
-        for user in group_users.iter() {
-            let status = if group.online_users.contains_key(&user.user_id) {
-                UserStatus::Online
-            } else {
-                UserStatus::Offline
-            };
-            users.push(User {
-                user_id: user.user_id,
-                name: user.name.clone(),
-                picture: user.picture.clone(),
-                status
-            });
-        }
+        let messages = message_list(self.pool, self.user_id, rows.iter()).await?;
+        let messages = messages.into_iter()
+            .zip(rows.iter())
+            .map(|(message, row)| MentionResult {
+                group_id: row.get(8),
+                channel_id: row.get(7),
+                message,
+            })
+            .collect();
 
-        group.send_reply(self.conn_id, ServerMessage::UserList {
-            users
-        });
+        self.send_reply(None, ServerMessage::MentionList { messages, more });
 
         Ok(())
     }
 
-    async fn rename_channel(&self, channel_id: db::ChannelID, name: String) -> Result<(), PoolError> {
-        let mut groups_guard = self.groups.write().await;
-        let group = &mut groups_guard.get_mut(&self.group_id).unwrap();
+    /// Check whether the current connection is subscribed to a group. This is
+    /// true if the user is a member of the group.
+    async fn subscribed(&self, group_id: db::GroupID) -> bool {
+        match self.groups.read().await.get(&group_id) {
+            Some(group) => group.connections.contains_key(&self.conn_id),
+            None => false
+        }
+    }
+
+    /// Send a reply message to the current connection.
+    fn send_reply(&self, group_id: Option<db::GroupID>, message: ServerMessage) {
+        send_message(&self.sender, encode(group_id, message));
+    }
+
+    /// Send a reply error to the current connection.
+    fn send_reply_error(&self, group_id: Option<db::GroupID>, category: ErrorCategory, code: ErrorCode) {
+        self.send_reply(group_id, ServerMessage::Error {
+            category, code
+        });
+    }
+}
+
+impl<'a> MessageContext<'a> {
+    async fn handle(&self, client_message: ClientMessage) -> Result<(), Error> {
+        match client_message {
+            ClientMessage::CreateMessage { content, channel_id, reply_to, attachments } =>
+                self.create_message(content, channel_id, reply_to, attachments).await,
+            ClientMessage::RequestRecentMessages { channel_id } =>
+                self.request_recent_messages(channel_id).await,
+            ClientMessage::RequestOldMessages { channel_id, message_id } =>
+                self.request_old_messages(channel_id, message_id).await,
+            ClientMessage::CreateChannel { name } =>
+                self.create_channel(name).await,
+            ClientMessage::RequestChannels =>
+                self.request_channels().await,
+            ClientMessage::DeleteChannel { channel_id } =>
+                self.delete_channel(channel_id).await,
+            ClientMessage::RequestUsers =>
+                self.request_users().await,
+            ClientMessage::RenameChannel { channel_id, name } =>
+                self.rename_channel(channel_id, name).await,
+            ClientMessage::RenameGroup { name, picture } =>
+                self.rename_group(name, picture).await,
+            ClientMessage::EditMessage { message_id, content } =>
+                self.edit_message(message_id, content).await,
+            ClientMessage::DeleteMessage { message_id } =>
+                self.delete_message(message_id).await,
+            ClientMessage::SetRole { user_id, role } =>
+                self.set_role(user_id, role).await,
+            ClientMessage::KickUser { user_id, ban } =>
+                self.kick_user(user_id, ban).await,
+            ClientMessage::SearchMessages { query, channel_id, before } =>
+                self.search_messages(query, channel_id, before).await,
+            ClientMessage::RequestNewMessages { channel_id, after } =>
+                self.request_new_messages(channel_id, after).await,
+            ClientMessage::RequestMessagesAround { channel_id, message_id } =>
+                self.request_messages_around(channel_id, message_id).await,
+            ClientMessage::RequestThread { message_id } =>
+                self.request_thread(message_id).await,
+            ClientMessage::AddReaction { message_id, emoji } =>
+                self.add_reaction(message_id, emoji).await,
+            ClientMessage::RemoveReaction { message_id, emoji } =>
+                self.remove_reaction(message_id, emoji).await,
+            ClientMessage::MarkRead { channel_id, message_id } =>
+                self.mark_read(channel_id, message_id).await,
+            ClientMessage::PinMessage { message_id } =>
+                self.pin_message(message_id).await,
+            ClientMessage::UnpinMessage { message_id } =>
+                self.unpin_message(message_id).await,
+            ClientMessage::RequestPinnedMessages { channel_id } =>
+                self.request_pinned_messages(channel_id).await,
+            ClientMessage::Typing { channel_id } =>
+                self.typing(channel_id).await,
+            // These are handled by SocketContext
+            ClientMessage::CreateDirectMessage { .. }
+            | ClientMessage::RequestDirectMessages { .. }
+            | ClientMessage::Resume { .. }
+            | ClientMessage::SetPresence { .. }
+            | ClientMessage::RequestMentions { .. } => Ok(()),
+        }
+    }
+
+    async fn message_list<'r, I>(&self, rows: I) -> Result<Vec<GenericRecentMessage>, Error>
+        where I: Iterator<Item=&'r Row>
+    {
+        message_list(self.pool, self.user_id, rows).await
+    }
+
+    /// Find the users and channels in the group that are mentioned in the
+    /// content of a message.
+    async fn resolve_mentions(&self, group: &Group, content: &str) -> Result<db::Mentions, Error> {
+        // Avoid loading the members of the group if there can't be any.
+        let users = if content.contains('@') {
+            db::group_users(self.pool.clone(), self.group_id).await?
+        } else {
+            Vec::new()
+        };
+        Ok(db::parse_mentions(content, &users, &group.channels))
+    }
+
+    /// Determine whether the current user has the given permission. If they
+    /// don't, an error is sent to the current connection.
+    async fn check_permission(&self, group: &Group, category: ErrorCategory, permission: db::Permissions)
+        -> Result<bool, Error>
+    {
+        match db::group_permissions(self.pool.clone(), self.user_id, self.group_id).await? {
+            Some(perms) if perms.contains(permission) => Ok(true),
+            _ => {
+                group.send_reply_error(self.conn_id, category, PermissionDenied);
+                Ok(false)
+            }
+        }
+    }
+
+    async fn create_message(
+        &self,
+        content: String,
+        channel_id: db::ChannelID,
+        reply_to: Option<db::MessageID>,
+        mut attachment_ids: Vec<db::AttachmentID>
+    ) -> Result<(), Error> {
+        let time = SystemTime::now();
+        let timestamp = as_timestamp(time);
+
+        let groups_guard = self.groups.read().await;
+        let group = match groups_guard.get(&self.group_id) {
+            Some(group) => group,
+            None => return Ok(())
+        };
+
+        // A message with attachments doesn't need any text.
+        let attachments_only = content.is_empty() && !attachment_ids.is_empty();
+        if !attachments_only && !db::valid_message(&content) {
+            group.send_reply_error(self.conn_id, Request, MessageInvalid);
+            return Ok(());
+        }
+
+        if !group.contains_channel(channel_id) {
+            group.send_reply_error(self.conn_id, Request, ChannelIdInvalid);
+            return Ok(());
+        }
+
+        attachment_ids.sort_unstable();
+        attachment_ids.dedup();
+        if attachment_ids.len() > db::MAX_MESSAGE_ATTACHMENTS {
+            group.send_reply_error(self.conn_id, Request, AttachmentIdInvalid);
+            return Ok(());
+        }
+
+        let mentions = self.resolve_mentions(group, &content).await?;
+
+        let html = markdown::render(&content);
+
+        let message_id = match db::create_message(self.pool.clone(), &db::NewMessage {
+            time,
+            author: self.user_id,
+            content: &content,
+            html: &html,
+            channel_id,
+            reply_to,
+            mentions: &mentions,
+            attachment_ids: &attachment_ids,
+        }).await? {
+            db::CreateMessageResult::Created(id) => id,
+            db::CreateMessageResult::ReplyToInvalid => {
+                group.send_reply_error(self.conn_id, Request, ReplyToInvalid);
+                return Ok(());
+            },
+            db::CreateMessageResult::AttachmentIdInvalid => {
+                group.send_reply_error(self.conn_id, Request, AttachmentIdInvalid);
+                return Ok(());
+            }
+        };
+
+        let attachments = if attachment_ids.is_empty() {
+            Vec::new()
+        } else {
+            db::message_attachments(self.pool.clone(), &[message_id]).await?
+                .into_iter()
+                .map(|(_, attachment)| attachment)
+                .collect()
+        };
+
+        let url = unfurl::find_url(&content);
+
+        let echo = ServerMessage::MessageReceipt {
+            message_id,
+            timestamp,
+            channel_id: Some(channel_id),
+            conversation_id: None,
+            html: html.clone(),
+        };
+
+        let peer = ServerMessage::RecentMessage(RecentMessage {
+            message_id,
+            timestamp,
+            author: self.user_id,
+            content,
+            html,
+            channel_id: Some(channel_id),
+            conversation_id: None,
+            reply_to,
+            mentions,
+            attachments,
+        });
+
+        group.send_peer_reply(self.conn_id, peer, echo);
+
+        // The preview is fetched in the background so that the message isn't
+        // held up by a slow site.
+        if let Some(url) = url {
+            tokio::spawn(unfurl_message(
+                self.pool.clone(),
+                self.groups.clone(),
+                self.unfurler.clone(),
+                self.group_id,
+                channel_id,
+                message_id,
+                url,
+            ));
+        }
+
+        Ok(())
+    }
+
+    async fn edit_message(&self, message_id: db::MessageID, content: String)
+        -> Result<(), Error>
+    {
+        let time = SystemTime::now();
+
+        let groups_guard = self.groups.read().await;
+        let group = match groups_guard.get(&self.group_id) {
+            Some(group) => group,
+            None => return Ok(())
+        };
+
+        if !db::valid_message(&content) {
+            group.send_reply_error(self.conn_id, Request, MessageInvalid);
+            return Ok(());
+        }
+
+        let mentions = self.resolve_mentions(group, &content).await?;
+
+        let html = markdown::render(&content);
+        let url = unfurl::find_url(&content);
+
+        // This will fail if the message doesn't exist, isn't in this group or
+        // was written by someone else.
+        let (channel_id, has_preview) = match db::edit_message(self.pool.clone(), self.group_id, &db::MessageEdit {
+            time,
+            author: self.user_id,
+            message_id,
+            content: &content,
+            html: &html,
+            mentions: &mentions,
+            url: url.as_deref(),
+        }).await? {
+            Some(edited) => edited,
+            None => {
+                group.send_reply_error(self.conn_id, Request, MessageIdInvalid);
+                return Ok(());
+            }
+        };
+
+        group.send_all(ServerMessage::MessageEdited {
+            message_id,
+            channel_id,
+            html,
+            content,
+            edited_at: as_timestamp(time),
+            mentions,
+        });
+
+        // The old preview was removed if the link changed so the new link
+        // needs a preview.
+        if let (Some(url), false) = (url, has_preview) {
+            tokio::spawn(unfurl_message(
+                self.pool.clone(),
+                self.groups.clone(),
+                self.unfurler.clone(),
+                self.group_id,
+                channel_id,
+                message_id,
+                url,
+            ));
+        }
+
+        Ok(())
+    }
+
+    async fn delete_message(&self, message_id: db::MessageID) -> Result<(), Error> {
+        let groups_guard = self.groups.read().await;
+        let group = match groups_guard.get(&self.group_id) {
+            Some(group) => group,
+            None => return Ok(())
+        };
+
+        // Users that can manage messages can delete anyone's messages.
+        let author = match db::group_permissions(self.pool.clone(), self.user_id, self.group_id).await? {
+            Some(perms) if perms.contains(db::Permissions::MANAGE_MESSAGES) => None,
+            _ => Some(self.user_id)
+        };
+
+        // This will fail if the message doesn't exist, isn't in this group or
+        // was written by someone else.
+        let channel_id = match db::delete_message(
+            self.pool.clone(), author, self.group_id, message_id
+        ).await? {
+            Some(id) => id,
+            None => {
+                group.send_reply_error(self.conn_id, Request, MessageIdInvalid);
+                return Ok(());
+            }
+        };
+
+        group.send_all(ServerMessage::MessageDeleted {
+            channel_id,
+            message_id,
+        });
+
+        Ok(())
+    }
+
+    async fn request_recent_messages(&self, channel_id: db::ChannelID)
+        -> Result<(), Error>
+    {
+        let groups_guard = self.groups.read().await;
+        let group = match groups_guard.get(&self.group_id) {
+            Some(group) => group,
+            None => return Ok(())
+        };
+
+        if !group.contains_channel(channel_id) {
+            group.send_reply_error(self.conn_id, Request, ChannelIdInvalid);
+            return Ok(());
+        }
+
+        let rows = db::recent_messages(self.pool.clone(), channel_id).await?;
+
+        group.send_reply(self.conn_id, ServerMessage::RecentMessageList {
+            channel_id,
+            messages: self.message_list(rows.iter()).await?
+        });
+
+        Ok(())
+    }
+
+    async fn request_old_messages(&self, channel_id: db::ChannelID, message_id: db::MessageID)
+        -> Result<(), Error>
+    {
+        let groups_guard = self.groups.read().await;
+        let group = match groups_guard.get(&self.group_id) {
+            Some(group) => group,
+            None => return Ok(())
+        };
+
+        if !group.contains_channel(channel_id) {
+            group.send_reply_error(self.conn_id, Request, ChannelIdInvalid);
+            return Ok(());
+        }
+
+        let rows = db::old_messages(self.pool.clone(), channel_id, message_id).await?;
+
+        group.send_reply(self.conn_id, ServerMessage::OldMessageList {
+            channel_id,
+            messages: self.message_list(rows.iter()).await?
+        });
+
+        Ok(())
+    }
+
+    async fn search_messages(&self, query: String, channel_id: Option<db::ChannelID>, before: Option<db::MessageID>)
+        -> Result<(), Error>
+    {
+        let groups_guard = self.groups.read().await;
+        let group = match groups_guard.get(&self.group_id) {
+            Some(group) => group,
+            None => return Ok(())
+        };
+
+        if !db::valid_message(&query) {
+            group.send_reply_error(self.conn_id, Request, QueryInvalid);
+            return Ok(());
+        }
+
+        if let Some(channel_id) = channel_id {
+            if !group.contains_channel(channel_id) {
+                group.send_reply_error(self.conn_id, Request, ChannelIdInvalid);
+                return Ok(());
+            }
+        }
+
+        let rows = db::search_messages(self.pool.clone(), self.group_id, channel_id, &query, before).await?;
+        let more = rows.len() > db::SEARCH_LIMIT;
+
+        group.send_reply(self.conn_id, ServerMessage::SearchResultList {
+            query,
+            channel_id,
+            messages: rows.iter().take(db::SEARCH_LIMIT).map(SearchResult::from_row).collect(),
+            more,
+        });
+
+        Ok(())
+    }
+
+    async fn request_new_messages(&self, channel_id: db::ChannelID, message_id: db::MessageID)
+        -> Result<(), Error>
+    {
+        let groups_guard = self.groups.read().await;
+        let group = match groups_guard.get(&self.group_id) {
+            Some(group) => group,
+            None => return Ok(())
+        };
+
+        if !group.contains_channel(channel_id) {
+            group.send_reply_error(self.conn_id, Request, ChannelIdInvalid);
+            return Ok(());
+        }
+
+        let rows = db::new_messages(self.pool.clone(), channel_id, message_id).await?;
+
+        group.send_reply(self.conn_id, ServerMessage::NewMessageList {
+            channel_id,
+            messages: self.message_list(rows.iter()).await?
+        });
+
+        Ok(())
+    }
+
+    async fn request_messages_around(&self, channel_id: db::ChannelID, message_id: db::MessageID)
+        -> Result<(), Error>
+    {
+        let groups_guard = self.groups.read().await;
+        let group = match groups_guard.get(&self.group_id) {
+            Some(group) => group,
+            None => return Ok(())
+        };
+
+        if !group.contains_channel(channel_id) {
+            group.send_reply_error(self.conn_id, Request, ChannelIdInvalid);
+            return Ok(());
+        }
+
+        let window = match db::messages_around(self.pool.clone(), channel_id, message_id, db::AROUND_LIMIT).await? {
+            Some(window) => window,
+            None => {
+                group.send_reply_error(self.conn_id, Request, MessageIdInvalid);
+                return Ok(());
+            }
+        };
+
+        group.send_reply(self.conn_id, ServerMessage::MessagesAroundList {
+            channel_id,
+            message_id,
+            messages: self.message_list(window.messages.iter()).await?,
+            more_before: window.more_before,
+            more_after: window.more_after,
+        });
+
+        Ok(())
+    }
+
+    async fn add_reaction(&self, message_id: db::MessageID, emoji: String) -> Result<(), Error> {
+        let groups_guard = self.groups.read().await;
+        let group = match groups_guard.get(&self.group_id) {
+            Some(group) => group,
+            None => return Ok(())
+        };
+
+        if !db::valid_emoji(&emoji) {
+            group.send_reply_error(self.conn_id, Request, EmojiInvalid);
+            return Ok(());
+        }
+
+        let channel_id = match db::message_channel(self.pool.clone(), self.group_id, message_id).await? {
+            Some(id) => id,
+            None => {
+                group.send_reply_error(self.conn_id, Request, MessageIdInvalid);
+                return Ok(());
+            }
+        };
+
+        match db::add_reaction(self.pool.clone(), message_id, self.user_id, &emoji).await? {
+            db::ReactionResult::Added => group.send_all(ServerMessage::ReactionAdded {
+                channel_id,
+                message_id,
+                user_id: self.user_id,
+                emoji,
+            }),
+            db::ReactionResult::AlreadyAdded => {},
+            db::ReactionResult::LimitReached => group.send_reply_error(self.conn_id, Request, ReactionLimitReached),
+        }
+
+        Ok(())
+    }
+
+    async fn remove_reaction(&self, message_id: db::MessageID, emoji: String) -> Result<(), Error> {
+        let groups_guard = self.groups.read().await;
+        let group = match groups_guard.get(&self.group_id) {
+            Some(group) => group,
+            None => return Ok(())
+        };
+
+        let channel_id = match db::message_channel(self.pool.clone(), self.group_id, message_id).await? {
+            Some(id) => id,
+            None => {
+                group.send_reply_error(self.conn_id, Request, MessageIdInvalid);
+                return Ok(());
+            }
+        };
+
+        if db::remove_reaction(self.pool.clone(), message_id, self.user_id, &emoji).await? {
+            group.send_all(ServerMessage::ReactionRemoved {
+                channel_id,
+                message_id,
+                user_id: self.user_id,
+                emoji,
+            });
+        }
+
+        Ok(())
+    }
+
+    async fn mark_read(&self, channel_id: db::ChannelID, message_id: db::MessageID) -> Result<(), Error> {
+        let groups_guard = self.groups.read().await;
+        let group = match groups_guard.get(&self.group_id) {
+            Some(group) => group,
+            None => return Ok(())
+        };
+
+        if !group.contains_channel(channel_id) {
+            group.send_reply_error(self.conn_id, Request, ChannelIdInvalid);
+            return Ok(());
+        }
+
+        match db::message_channel(self.pool.clone(), self.group_id, message_id).await? {
+            Some(id) if id == channel_id => {},
+            _ => {
+                group.send_reply_error(self.conn_id, Request, MessageIdInvalid);
+                return Ok(());
+            }
+        }
+
+        // Keep the user's other tabs in sync.
+        if db::mark_read(self.pool.clone(), self.user_id, channel_id, message_id).await? {
+            group.send_user_peer(self.user_id, self.conn_id, ServerMessage::ReadMarkerChanged {
+                channel_id,
+                message_id,
+            });
+        }
+
+        Ok(())
+    }
+
+    async fn pin_message(&self, message_id: db::MessageID) -> Result<(), Error> {
+        let time = SystemTime::now();
+
+        let groups_guard = self.groups.read().await;
+        let group = match groups_guard.get(&self.group_id) {
+            Some(group) => group,
+            None => return Ok(())
+        };
+
+        if !self.check_permission(group, Request, db::Permissions::MANAGE_MESSAGES).await? {
+            return Ok(());
+        }
+
+        let channel_id = match db::message_channel(self.pool.clone(), self.group_id, message_id).await? {
+            Some(id) => id,
+            None => {
+                group.send_reply_error(self.conn_id, Request, MessageIdInvalid);
+                return Ok(());
+            }
+        };
+
+        match db::pin_message(self.pool.clone(), time, self.user_id, channel_id, message_id).await? {
+            db::PinResult::Pinned => group.send_all(ServerMessage::MessagePinned {
+                channel_id,
+                message_id,
+                user_id: self.user_id,
+                timestamp: as_timestamp(time),
+            }),
+            db::PinResult::AlreadyPinned => {},
+            db::PinResult::LimitReached => group.send_reply_error(self.conn_id, Request, PinLimitReached),
+        }
+
+        Ok(())
+    }
+
+    async fn unpin_message(&self, message_id: db::MessageID) -> Result<(), Error> {
+        let groups_guard = self.groups.read().await;
+        let group = match groups_guard.get(&self.group_id) {
+            Some(group) => group,
+            None => return Ok(())
+        };
+
+        if !self.check_permission(group, Request, db::Permissions::MANAGE_MESSAGES).await? {
+            return Ok(());
+        }
+
+        // This will fail if the message isn't pinned or isn't in this group.
+        let channel_id = match db::unpin_message(self.pool.clone(), self.group_id, message_id).await? {
+            Some(id) => id,
+            None => {
+                group.send_reply_error(self.conn_id, Request, MessageIdInvalid);
+                return Ok(());
+            }
+        };
+
+        group.send_all(ServerMessage::MessageUnpinned {
+            channel_id,
+            message_id,
+        });
+
+        Ok(())
+    }
+
+    async fn request_pinned_messages(&self, channel_id: db::ChannelID) -> Result<(), Error> {
+        let groups_guard = self.groups.read().await;
+        let group = match groups_guard.get(&self.group_id) {
+            Some(group) => group,
+            None => return Ok(())
+        };
+
+        if !group.contains_channel(channel_id) {
+            group.send_reply_error(self.conn_id, Request, ChannelIdInvalid);
+            return Ok(());
+        }
+
+        let rows = db::pinned_messages(self.pool.clone(), channel_id).await?;
+
+        group.send_reply(self.conn_id, ServerMessage::PinnedMessageList {
+            channel_id,
+            messages: self.message_list(rows.iter()).await?,
+        });
+
+        Ok(())
+    }
+
+    async fn typing(&self, channel_id: db::ChannelID) -> Result<(), Error> {
+        let groups_guard = self.groups.read().await;
+        let group = match groups_guard.get(&self.group_id) {
+            Some(group) => group,
+            None => return Ok(())
+        };
+
+        if !group.contains_channel(channel_id) {
+            group.send_reply_error(self.conn_id, Request, ChannelIdInvalid);
+            return Ok(());
+        }
+
+        if !group.update_typing(self.conn_id, channel_id) {
+            return Ok(());
+        }
+
+        // Typing would reveal that an invisible user is online.
+        let invisible = self.users.read().await.get(&self.user_id)
+            .is_some_and(|user| user.presence.presence == db::Presence::Invisible);
+
+        if !invisible {
+            group.send_peer(self.conn_id, ServerMessage::UserTyping {
+                channel_id,
+                user_id: self.user_id,
+            });
+        }
+
+        Ok(())
+    }
+
+    async fn request_thread(&self, message_id: db::MessageID) -> Result<(), Error> {
+        let groups_guard = self.groups.read().await;
+        let group = match groups_guard.get(&self.group_id) {
+            Some(group) => group,
+            None => return Ok(())
+        };
+
+        let rows = db::thread_messages(self.pool.clone(), self.group_id, message_id).await?;
+
+        let (root_id, channel_id) = match rows.first() {
+            Some(row) => (row.get(0), row.get(7)),
+            None => {
+                group.send_reply_error(self.conn_id, Request, MessageIdInvalid);
+                return Ok(());
+            }
+        };
+
+        group.send_reply(self.conn_id, ServerMessage::ThreadMessageList {
+            root_id,
+            channel_id,
+            messages: self.message_list(rows.iter().take(db::THREAD_LIMIT)).await?,
+            more: rows.len() > db::THREAD_LIMIT,
+        });
+
+        Ok(())
+    }
+
+    async fn create_channel(&self, name: String) -> Result<(), Error> {
+        let mut groups_guard = self.groups.write().await;
+        let group = match groups_guard.get_mut(&self.group_id) {
+            Some(group) => group,
+            None => return Ok(())
+        };
+
+        if !self.check_permission(group, ChannelCreate, db::Permissions::MANAGE_CHANNELS).await? {
+            return Ok(());
+        }
+
+        if !db::valid_channel_name(&name) {
+            // This shouldn't happen unless someone is bypassing the JavaScript
+            // validation.
+            group.send_reply_error(self.conn_id, ChannelCreate, NameInvalid);
+            return Ok(());
+        }
+
+        let channel_id = match db::create_channel(self.pool.clone(), self.group_id, &name).await? {
+            Some(id) => id,
+            None => {
+                group.send_reply_error(self.conn_id, ChannelCreate, NameExists);
+                return Ok(());
+            }
+        };
+
+        group.send_all(ServerMessage::ChannelCreated {
+            channel_id,
+            name: &name,
+        });
+
+        group.channels.push(db::Channel {
+            channel_id,
+            name
+        });
+
+        Ok(())
+    }
+
+    async fn request_channels(&self) -> Result<(), Error> {
+        let groups_guard = self.groups.read().await;
+        let group = match groups_guard.get(&self.group_id) {
+            Some(group) => group,
+            None => return Ok(())
+        };
+
+        let read_states = db::read_states(self.pool.clone(), self.user_id, self.group_id).await?;
+
+        group.send_reply(self.conn_id, ServerMessage::ChannelList {
+            channels: &group.channels,
+            read_states,
+        });
+
+        Ok(())
+    }
+
+    async fn delete_channel(&self, channel_id: db::ChannelID) -> Result<(), Error> {
+        let mut groups_guard = self.groups.write().await;
+        let group = match groups_guard.get_mut(&self.group_id) {
+            Some(group) => group,
+            None => return Ok(())
+        };
+
+        if !self.check_permission(group, ChannelDelete, db::Permissions::MANAGE_CHANNELS).await? {
+            return Ok(());
+        }
+
+        if group.channels.len() == 1 {
+            group.send_reply_error(self.conn_id, ChannelDelete, LoneChannel);
+            return Ok(());
+        }
+
+        let channel_index = group.find_channel(channel_id);
+        if channel_index == usize::MAX {
+            group.send_reply_error(self.conn_id, Request, ChannelIdInvalid);
+            return Ok(());
+        }
+
+        if !db::delete_channel(self.pool.clone(), channel_id).await? {
+            // If the above checks pass then this cannot happen
+            group.send_reply_error(self.conn_id, Request, ChannelIdInvalid);
+            return Ok(());
+        }
+
+        group.channels.remove(channel_index);
+
+        group.send_all(ServerMessage::ChannelDeleted {
+            channel_id
+        });
+
+        Ok(())
+    }
+
+    async fn request_users(&self) -> Result<(), Error> {
+        let groups_guard = self.groups.read().await;
+        let group = match groups_guard.get(&self.group_id) {
+            Some(group) => group,
+            None => return Ok(())
+        };
+
+        let group_users = db::group_users(self.pool.clone(), self.group_id).await?;
+        let users_guard = self.users.read().await;
+        let mut users = Vec::new();
+
+        for user in group_users.iter() {
+            let online_user = if group.online_users.contains_key(&user.user_id) {
+                users_guard.get(&user.user_id)
+            } else {
+                None
+            };
+            let (status, status_text) = match online_user {
+                Some(online_user) => (
+                    UserStatus::from_presence(online_user.presence.presence),
+                    visible_status_text(&online_user.presence).cloned()
+                ),
+                None => (UserStatus::Offline, None)
+            };
+            let last_seen = match status {
+                UserStatus::Offline => user.last_seen,
+                _ => None
+            };
+            users.push(User {
+                user_id: user.user_id,
+                name: user.name.clone(),
+                picture: user.picture.clone(),
+                role: user.role,
+                status,
+                status_text,
+                last_seen,
+            });
+        }
+
+        group.send_reply(self.conn_id, ServerMessage::UserList {
+            users
+        });
+
+        Ok(())
+    }
+
+    async fn rename_channel(&self, channel_id: db::ChannelID, name: String) -> Result<(), Error> {
+        let mut groups_guard = self.groups.write().await;
+        let group = match groups_guard.get_mut(&self.group_id) {
+            Some(group) => group,
+            None => return Ok(())
+        };
+
+        if !self.check_permission(group, ChannelRename, db::Permissions::MANAGE_CHANNELS).await? {
+            return Ok(());
+        }
 
         if !db::valid_channel_name(&name) {
             // This shouldn't happen unless someone is bypassing the JavaScript
@@ -486,16 +1789,26 @@ impl<'a> MessageContext<'a> {
         Ok(())
     }
 
-    async fn rename_group(&self, name: String, picture: String) -> Result<(), PoolError> {
+    async fn rename_group(&self, name: String, picture: String) -> Result<(), Error> {
+        // The client sends back the proxied URL if the picture wasn't changed.
+        let picture = crate::proxy::original_url(picture);
+
         let groups_guard = self.groups.read().await;
-        let group = &groups_guard[&self.group_id];
+        let group = match groups_guard.get(&self.group_id) {
+            Some(group) => group,
+            None => return Ok(())
+        };
+
+        if !self.check_permission(group, GroupRename, db::Permissions::MANAGE_GROUP).await? {
+            return Ok(());
+        }
 
         if !db::valid_group_name(&name) {
             group.send_reply_error(self.conn_id, GroupRename, NameInvalid);
             return Ok(());
         }
 
-        if !db::valid_url(&picture) {
+        if !crate::avatar::valid_picture(&picture) {
             group.send_reply_error(self.conn_id, GroupRename, PictureInvalid);
             return Ok(());
         }
@@ -505,30 +1818,98 @@ impl<'a> MessageContext<'a> {
             return Ok(());
         }
 
-        let users = db::group_user_ids(self.pool.clone(), self.group_id).await?;
-
-        let message = serde_json::to_string(&ServerMessage::GroupRenamed {
-            group_id: self.group_id,
+        // Every member of the group is subscribed to it, even if they are
+        // currently looking at another group.
+        group.send_all(ServerMessage::GroupRenamed {
             name,
             picture
-        }).unwrap();
+        });
 
-        // Need to send this to all users that are members of the group.
-        // They may be logged into another group.
+        Ok(())
+    }
 
-        let user_groups_guard = self.user_groups.read().await;
+    async fn set_role(&self, user_id: db::UserID, role: db::Role) -> Result<(), Error> {
+        let groups_guard = self.groups.read().await;
+        let group = match groups_guard.get(&self.group_id) {
+            Some(group) => group,
+            None => return Ok(())
+        };
 
-        for user_id in users.iter() {
-            if let Some(groups) = user_groups_guard.get(&user_id) {
-                for group_id in groups.iter() {
-                    let group = &groups_guard[group_id];
-                    for conn_id in group.online_users[&user_id].iter() {
-                        send_message(&group.connections[conn_id], message.clone());
-                    }
-                }
+        if !self.check_permission(group, RoleChange, db::Permissions::MANAGE_ROLES).await? {
+            return Ok(());
+        }
+
+        // There can only be one owner and the owner cannot be demoted.
+        if role == db::Role::Owner || user_id == self.user_id {
+            group.send_reply_error(self.conn_id, RoleChange, RoleInvalid);
+            return Ok(());
+        }
+
+        match db::group_role(self.pool.clone(), user_id, self.group_id).await? {
+            Some(db::Role::Owner) => {
+                group.send_reply_error(self.conn_id, RoleChange, RoleInvalid);
+                return Ok(());
+            },
+            Some(_) => {},
+            None => {
+                group.send_reply_error(self.conn_id, RoleChange, UserIdInvalid);
+                return Ok(());
+            }
+        }
+
+        if !db::set_role(self.pool.clone(), user_id, self.group_id, role).await? {
+            // The user left the group after the above check
+            group.send_reply_error(self.conn_id, RoleChange, UserIdInvalid);
+            return Ok(());
+        }
+
+        group.send_all(ServerMessage::UserRoleChanged {
+            user_id,
+            role,
+        });
+
+        Ok(())
+    }
+
+    async fn kick_user(&self, user_id: db::UserID, ban: bool) -> Result<(), Error> {
+        let groups_guard = self.groups.read().await;
+        let group = match groups_guard.get(&self.group_id) {
+            Some(group) => group,
+            None => return Ok(())
+        };
+
+        let permission = if ban {
+            db::Permissions::BAN_MEMBERS
+        } else {
+            db::Permissions::KICK_MEMBERS
+        };
+
+        match db::can_remove_member(self.pool.clone(), self.user_id, user_id, self.group_id, permission).await? {
+            Some(true) => {},
+            Some(false) => {
+                group.send_reply_error(self.conn_id, UserKick, PermissionDenied);
+                return Ok(());
+            },
+            None => {
+                group.send_reply_error(self.conn_id, UserKick, UserIdInvalid);
+                return Ok(());
             }
         }
 
+        // This is the same as the user leaving the group
+        let banned_by = if ban { Some(self.user_id) } else { None };
+        db::remove_member(self.pool.clone(), user_id, self.group_id, banned_by).await?;
+
+        // Need to release the read lock before acquiring the write lock.
+        drop(groups_guard);
+
+        let mut groups_guard = self.groups.write().await;
+        let mut users_guard = self.users.write().await;
+        leave_group(&mut groups_guard, &mut users_guard, user_id, self.group_id);
+        if let Some(group) = groups_guard.get(&self.group_id) {
+            group.send_delete_user(user_id);
+        }
+
         Ok(())
     }
 }
@@ -1,12 +1,14 @@
 use log::{debug, error};
 use crate::error::Error;
 use crate::database as db;
+use crate::unfurl::Unfurler;
 use deadpool_postgres::Pool;
 use tokio::sync::{RwLock, mpsc};
 use futures::{FutureExt, StreamExt};
 use warp::ws::{Ws, WebSocket, Message};
 use std::collections::hash_map::{HashMap, Entry};
-use std::sync::{Arc, atomic::{AtomicUsize, Ordering}};
+use std::sync::{Arc, Mutex, atomic::{AtomicUsize, Ordering}};
+use std::time::{Instant, SystemTime};
 
 pub type ConnID = usize;
 pub type AtomicConnID = AtomicUsize;
@@ -16,147 +18,242 @@ pub type Sender = mpsc::UnboundedSender<Result<Message, warp::Error>>;
 
 struct ConnectionContext {
     user_id: db::UserID,
-    group_id: db::GroupID,
     conn_id: ConnID,
 }
 
+/// The last time that a connection reported that its user was typing.
+pub struct Typing {
+    pub channel_id: db::ChannelID,
+    pub time: Instant,
+}
+
 pub struct Group {
+    pub group_id: db::GroupID,
     pub channels: Vec<db::Channel>,
     pub connections: HashMap<ConnID, Sender>,
     pub online_users: HashMap<db::UserID, Vec<ConnID>>,
+    /// Typing notifications are frequent so they're kept behind their own
+    /// lock. This means that they only need read access to the group map.
+    pub typing: Mutex<HashMap<ConnID, Typing>>,
+}
+
+/// A user that has at least one connection.
+///
+/// Each connection is subscribed to every group that the user is a member of
+/// so the list of groups is shared between the connections.
+pub struct OnlineUser {
+    pub connections: HashMap<ConnID, Sender>,
+    pub groups: Vec<db::GroupID>,
+    pub presence: db::UserPresence,
 }
 
 pub type GroupMap = HashMap<db::GroupID, Group>;
 pub type Groups = Arc<RwLock<GroupMap>>;
-pub type UserGroupMap = HashMap<db::UserID, Vec<db::GroupID>>;
-pub type UserGroups = Arc<RwLock<UserGroupMap>>;
+pub type UserMap = HashMap<db::UserID, OnlineUser>;
+pub type Users = Arc<RwLock<UserMap>>;
 
 impl Group {
-    /// Create a new group and insert a connection
-    async fn new(conn_ctx: &ConnectionContext, pool: Pool, ch_tx: Sender)
-        -> Result<Self, Error>
-    {
-        let channels = db::group_channels(pool, conn_ctx.group_id).await?;
-        let mut connections = HashMap::new();
-        connections.insert(conn_ctx.conn_id, ch_tx);
-        let mut online_users = HashMap::new();
-        online_users.insert(conn_ctx.user_id, vec![conn_ctx.conn_id]);
-        Ok(Self { channels, connections, online_users })
+    /// Create a new group without any connections.
+    async fn new(group_id: db::GroupID, pool: Pool) -> Result<Self, Error> {
+        let channels = db::group_channels(pool, group_id).await?;
+        Ok(Self {
+            group_id,
+            channels,
+            connections: HashMap::new(),
+            online_users: HashMap::new(),
+            typing: Mutex::new(HashMap::new()),
+        })
     }
 
     /// Insert a new connection into the group.
-    /// Returns true if the user has one connection to the group.
-    fn insert_connection(&mut self, conn_ctx: &ConnectionContext, ch_tx: Sender) -> bool {
-        let conn_ids = self.online_users.entry(conn_ctx.user_id).or_default();
-        conn_ids.push(conn_ctx.conn_id);
-        let mut joined_group = false;
-        if conn_ids.len() == 1 {
-            self.send_user_online(conn_ctx.user_id);
-            joined_group = true;
+    fn insert_connection(&mut self, user_id: db::UserID, conn_id: ConnID, ch_tx: Sender, presence: &db::UserPresence) {
+        let conn_ids = self.online_users.entry(user_id).or_default();
+        conn_ids.push(conn_id);
+        // Invisible users already appear to be offline.
+        if conn_ids.len() == 1 && presence.presence != db::Presence::Invisible {
+            self.send_user_presence(user_id, presence);
         }
-        self.connections.insert(conn_ctx.conn_id, ch_tx);
-        joined_group
+        self.connections.insert(conn_id, ch_tx);
     }
 
-    /// Remove the current connection from the group.
-    /// Returns true if the user has no connections to the group.
-    fn remove_connection(&mut self, conn_ctx: &ConnectionContext) -> bool {
-        self.connections.remove(&conn_ctx.conn_id);
-        if self.connections.is_empty() {
-            return true;
-        }
-        let mut user_entry = match self.online_users.entry(conn_ctx.user_id) {
+    /// Remove a connection from the group. If last_seen is Some and this is
+    /// the user's last connection, everyone is told that the user is offline.
+    fn remove_connection(&mut self, user_id: db::UserID, conn_id: ConnID, last_seen: Option<SystemTime>) {
+        self.connections.remove(&conn_id);
+        self.typing.get_mut().unwrap().remove(&conn_id);
+        let mut user_entry = match self.online_users.entry(user_id) {
             Entry::Occupied(entry) => entry,
-            Entry::Vacant(_) => panic!(),
+            Entry::Vacant(_) => return,
         };
         let conn_ids = user_entry.get_mut();
         if conn_ids.len() == 1 {
             user_entry.remove();
-            self.send_user_offline(conn_ctx.user_id);
-            true
-        } else {
-            let index = conn_ids.iter().position(|id| *id == conn_ctx.conn_id).unwrap();
+            if let Some(time) = last_seen {
+                self.send_user_offline(user_id, time);
+            }
+        } else if let Some(index) = conn_ids.iter().position(|id| *id == conn_id) {
             conn_ids.swap_remove(index);
-            false
         }
     }
+
+    /// Remove all of the connections of a user from the group.
+    fn remove_user(&mut self, user_id: db::UserID) {
+        if let Some(conn_ids) = self.online_users.remove(&user_id) {
+            for conn_id in conn_ids.iter() {
+                self.connections.remove(conn_id);
+                self.typing.get_mut().unwrap().remove(conn_id);
+            }
+        }
+    }
+}
+
+/// Load any groups in the list that aren't already in the map.
+///
+/// All of the groups are loaded before any are inserted so that the map is
+/// left unchanged if there's an error.
+async fn load_groups(groups: &mut GroupMap, pool: Pool, group_ids: &[db::GroupID])
+    -> Result<(), Error>
+{
+    let mut loaded = Vec::new();
+    for group_id in group_ids.iter() {
+        if !groups.contains_key(group_id) {
+            loaded.push(Group::new(*group_id, pool.clone()).await?);
+        }
+    }
+    for group in loaded.drain(..) {
+        groups.insert(group.group_id, group);
+    }
+    Ok(())
+}
+
+/// Remove a connection from a group. Also removes the group if the group
+/// becomes empty.
+fn unsubscribe(
+    groups: &mut GroupMap,
+    group_id: db::GroupID,
+    user_id: db::UserID,
+    conn_id: ConnID,
+    last_seen: Option<SystemTime>
+) {
+    if let Entry::Occupied(mut entry) = groups.entry(group_id) {
+        entry.get_mut().remove_connection(user_id, conn_id, last_seen);
+        if entry.get().connections.is_empty() {
+            entry.remove();
+        }
+    }
+}
+
+/// Unsubscribe all of a user's connections from a group after the user has
+/// been removed from the group. The connections are told that they have left
+/// the group.
+pub fn leave_group(groups: &mut GroupMap, users: &mut UserMap, user_id: db::UserID, group_id: db::GroupID) {
+    let user = match users.get_mut(&user_id) {
+        Some(user) => user,
+        None => return
+    };
+
+    if let Some(index) = user.groups.iter().position(|id| *id == group_id) {
+        user.groups.swap_remove(index);
+    }
+
+    if let Entry::Occupied(mut entry) = groups.entry(group_id) {
+        entry.get_mut().remove_user(user_id);
+        if entry.get().connections.is_empty() {
+            entry.remove();
+        }
+    }
+
+    user.send_group_left(group_id);
 }
 
 #[derive(Clone)]
 pub struct Context {
     pool: Pool,
     groups: Groups,
-    user_groups: UserGroups,
+    users: Users,
+    unfurler: Unfurler,
 }
 
 impl Context {
-    pub fn new(pool: Pool) -> Self {
+    pub fn new(pool: Pool, unfurler: Unfurler) -> Self {
         Self {
             pool,
             groups: Groups::default(),
-            user_groups: UserGroups::default(),
+            users: Users::default(),
+            unfurler,
         }
     }
 
-    /// Insert a connection into the group map. Creates a new group if
-    /// necessary, otherwise inserts into an existing group.
+    /// Insert a connection into the user map and subscribe it to each of the
+    /// user's groups. Groups are created in the group map as necessary.
     async fn insert_connection(&self, conn_ctx: &ConnectionContext, ch_tx: Sender)
         -> Result<(), Error>
     {
-        let joined_group;
-        match self.groups.write().await.entry(conn_ctx.group_id) {
-            Entry::Occupied(mut entry) => {
-                joined_group = entry.get_mut().insert_connection(&conn_ctx, ch_tx);
-            }
+        let mut groups_guard = self.groups.write().await;
+        let mut users_guard = self.users.write().await;
+
+        let user = match users_guard.entry(conn_ctx.user_id) {
+            Entry::Occupied(entry) => entry.into_mut(),
             Entry::Vacant(entry) => {
-                entry.insert(Group::new(&conn_ctx, self.pool.clone(), ch_tx).await?);
-                joined_group = true;
-            }
-        }
-        if joined_group {
-            match self.user_groups.write().await.entry(conn_ctx.user_id) {
-                Entry::Occupied(mut entry) => {
-                    entry.get_mut().push(conn_ctx.group_id);
-                },
-                Entry::Vacant(entry) => {
-                    entry.insert(vec![conn_ctx.group_id]);
-                }
+                let groups = db::user_group_ids(self.pool.clone(), conn_ctx.user_id).await?;
+                let presence = db::user_presence(self.pool.clone(), conn_ctx.user_id).await?;
+                entry.insert(OnlineUser { connections: HashMap::new(), groups, presence })
             }
+        };
+
+        load_groups(&mut groups_guard, self.pool.clone(), &user.groups).await?;
+
+        for group_id in user.groups.iter() {
+            groups_guard.get_mut(group_id).unwrap()
+                .insert_connection(conn_ctx.user_id, conn_ctx.conn_id, ch_tx.clone(), &user.presence);
         }
+        user.connections.insert(conn_ctx.conn_id, ch_tx);
+
         Ok(())
     }
 
-    /// Remove a connection from the group map. Also removes the group if the
-    /// group becomes empty.
-    async fn remove_connection(&self, conn_ctx: &ConnectionContext) {
-        let left_group;
-        match self.groups.write().await.entry(conn_ctx.group_id) {
-            Entry::Occupied(mut entry) => {
-                if entry.get_mut().connections.len() == 1 {
-                    entry.remove();
-                    left_group = true;
-                } else {
-                    left_group = entry.get_mut().remove_connection(&conn_ctx);
-                }
-            },
-            Entry::Vacant(_) => panic!()
-        }
-        if left_group {
-            match self.user_groups.write().await.entry(conn_ctx.user_id) {
-                Entry::Occupied(mut entry) => {
-                    if entry.get_mut().len() == 1 {
-                        entry.remove();
-                    } else {
-                        let pos = entry.get_mut().iter().position(|id| *id == conn_ctx.group_id).unwrap();
-                        entry.get_mut().swap_remove(pos);
-                    }
-                },
-                Entry::Vacant(_) => panic!()
+    /// Remove a connection from the user map and from each of the groups.
+    /// Groups that become empty are removed. If this was the user's last
+    /// connection, the time is recorded as the user's last_seen time.
+    async fn remove_connection(&self, conn_ctx: &ConnectionContext) -> Result<(), Error> {
+        let last_seen = {
+            let mut groups_guard = self.groups.write().await;
+            let mut users_guard = self.users.write().await;
+
+            let mut entry = match users_guard.entry(conn_ctx.user_id) {
+                Entry::Occupied(entry) => entry,
+                Entry::Vacant(_) => return Ok(())
+            };
+
+            let user = entry.get_mut();
+            user.connections.remove(&conn_ctx.conn_id);
+
+            // The last_seen time of an invisible user isn't updated because
+            // that would reveal that they were online.
+            let last_seen = if user.connections.is_empty() && user.presence.presence != db::Presence::Invisible {
+                Some(SystemTime::now())
+            } else {
+                None
+            };
+
+            for group_id in user.groups.iter() {
+                unsubscribe(&mut groups_guard, *group_id, conn_ctx.user_id, conn_ctx.conn_id, last_seen);
+            }
+            if user.connections.is_empty() {
+                entry.remove();
             }
+
+            last_seen
+        };
+
+        if let Some(time) = last_seen {
+            db::set_last_seen(self.pool.clone(), conn_ctx.user_id, time).await?;
         }
+
+        Ok(())
     }
 
-    pub async fn upgrade(group_id: db::GroupID, ws: Ws, session_id: db::SessionID, ctx: Self)
+    pub async fn upgrade(ws: Ws, session_id: db::SessionID, ctx: Self)
         -> Result<Box<dyn warp::Reply>, warp::Rejection>
     {
         // The JavaScript that invokes this is only loaded when the session cookie
@@ -169,16 +266,10 @@ impl Context {
             None => return Ok(Box::new(warp::http::StatusCode::INTERNAL_SERVER_ERROR))
         };
 
-        // Can only happen if someone is directly accessing the socket.
-        if !db::group_member(ctx.pool.clone(), user_id, group_id).await? {
-            return Ok(Box::new(warp::http::StatusCode::INTERNAL_SERVER_ERROR));
-        }
-
         // Upgrade the HTTP connection to a WebSocket connection
         Ok(Box::new(ws.on_upgrade(move |socket: WebSocket| {
             ctx.connected(socket, ConnectionContext {
                 user_id,
-                group_id,
                 conn_id: NEXT_CONNECTION_ID.fetch_add(1, Ordering::Relaxed)
             })
         })))
@@ -202,28 +293,29 @@ impl Context {
             }
         }));
 
-        // Add the connection to the hashmap, saving the sending end of the queue.
-        // Putting messages onto the queue will cause them to eventually be
-        // processed above and sent over the socket.
-        if let Err(e) = self.insert_connection(&conn_ctx, ch_tx).await {
+        // Add the connection to the hashmaps, saving the sending end of the
+        // queue. Putting messages onto the queue will cause them to eventually
+        // be processed above and sent over the socket.
+        if let Err(e) = self.insert_connection(&conn_ctx, ch_tx.clone()).await {
             error!("{}", e);
             return;
         }
 
-        let message_ctx = super::handler::MessageContext {
+        let socket_ctx = super::handler::SocketContext {
             user_id: conn_ctx.user_id,
-            group_id: conn_ctx.group_id,
             conn_id: conn_ctx.conn_id,
+            sender: ch_tx,
             groups: &self.groups,
-            user_groups: &self.user_groups,
+            users: &self.users,
             pool: &self.pool,
+            unfurler: &self.unfurler,
         };
 
         // Handle each message received from the socket.
         while let Some(result) = ws_rx.next().await {
             // result: Result<Message, warp::Error>
             match result {
-                Ok(message) => message_ctx.handle(message).await,
+                Ok(message) => socket_ctx.handle(message).await,
                 Err(e) => {
                     error!("Error receiving from socket ({}): {}", conn_ctx.conn_id, e);
                     break;
@@ -231,25 +323,49 @@ impl Context {
             }
         }
 
-        self.remove_connection(&conn_ctx).await;
+        if let Err(e) = self.remove_connection(&conn_ctx).await {
+            error!("{}", e);
+        }
         debug!("Socket disconnected: {}", conn_ctx.conn_id);
     }
 
+    /// Close all of a user's connections.
     pub async fn kick_user(&self, user_id: db::UserID) {
-        let groups_guard = self.groups.read().await;
-        let user_groups_guard = self.user_groups.read().await;
-        if let Some(groups) = user_groups_guard.get(&user_id) {
-            for group_id in groups.iter() {
-                groups_guard[group_id].kick_user(user_id);
-            }
+        if let Some(user) = self.users.read().await.get(&user_id) {
+            user.kick();
         }
     }
 
-    pub async fn kick_user_from_group(&self, user_id: db::UserID, group_id: db::GroupID) {
-        let groups_guard = self.groups.read().await;
-        if let Some(group) = groups_guard.get(&group_id) {
-            group.kick_user(user_id);
+    /// Subscribe all of a user's connections to a group that they just joined.
+    pub async fn join_group(&self, user_id: db::UserID, group_id: db::GroupID) -> Result<(), Error> {
+        let mut groups_guard = self.groups.write().await;
+        let mut users_guard = self.users.write().await;
+
+        let user = match users_guard.get_mut(&user_id) {
+            Some(user) => user,
+            None => return Ok(())
+        };
+
+        if user.groups.contains(&group_id) {
+            return Ok(());
         }
+
+        load_groups(&mut groups_guard, self.pool.clone(), &[group_id]).await?;
+
+        let group = groups_guard.get_mut(&group_id).unwrap();
+        for (conn_id, ch_tx) in user.connections.iter() {
+            group.insert_connection(user_id, *conn_id, ch_tx.clone(), &user.presence);
+        }
+        user.groups.push(group_id);
+
+        Ok(())
+    }
+
+    /// Unsubscribe a user from a group that they were removed from.
+    pub async fn kick_user_from_group(&self, user_id: db::UserID, group_id: db::GroupID) {
+        let mut groups_guard = self.groups.write().await;
+        let mut users_guard = self.users.write().await;
+        leave_group(&mut groups_guard, &mut users_guard, user_id, group_id);
     }
 
     pub async fn rename_user(&self, groups: Vec<db::GroupID>, user_id: db::UserID, name: &String, picture: &String) {
@@ -261,18 +377,23 @@ impl Context {
         }
     }
 
-    pub async fn delete_group(&self, users: Vec<db::UserID>, deleted_group_id: db::GroupID) {
-        let groups_guard = self.groups.read().await;
-        let user_groups_guard = self.user_groups.read().await;
-        for user_id in users.iter() {
-            if let Some(groups) = user_groups_guard.get(&user_id) {
-                for group_id in groups.iter() {
-                    let group = &groups_guard[group_id];
-                    if *group_id == deleted_group_id {
-                        group.kick_user(*user_id);
-                    } else {
-                        group.send_delete_group(*user_id, deleted_group_id);
-                    }
+    /// Tell everyone connected to a group that it has been deleted and then
+    /// unsubscribe them from it.
+    pub async fn delete_group(&self, group_id: db::GroupID) {
+        let mut groups_guard = self.groups.write().await;
+        let mut users_guard = self.users.write().await;
+
+        let group = match groups_guard.remove(&group_id) {
+            Some(group) => group,
+            None => return
+        };
+
+        group.send_delete_group();
+
+        for user_id in group.online_users.keys() {
+            if let Some(user) = users_guard.get_mut(user_id) {
+                if let Some(index) = user.groups.iter().position(|id| *id == group_id) {
+                    user.groups.swap_remove(index);
                 }
             }
         }
@@ -0,0 +1,54 @@
+use std::io::ErrorKind;
+use std::path::PathBuf;
+use futures::FutureExt;
+use futures::future::BoxFuture;
+use super::{Storage, StorageResult};
+
+/// Stores each file in a directory on the local filesystem. Keys may contain
+/// slashes to put files in subdirectories. Directories are created when the
+/// first file is stored in them.
+pub struct LocalStorage {
+    root: PathBuf,
+}
+
+impl LocalStorage {
+    pub fn new<P: Into<PathBuf>>(root: P) -> Self {
+        Self { root: root.into() }
+    }
+
+    fn path(&self, key: &str) -> PathBuf {
+        self.root.join(key)
+    }
+}
+
+impl Storage for LocalStorage {
+    fn put<'a>(&'a self, key: &'a str, data: Vec<u8>) -> BoxFuture<'a, StorageResult<()>> {
+        async move {
+            let path = self.path(key);
+            if let Some(parent) = path.parent() {
+                tokio::fs::create_dir_all(parent).await?;
+            }
+            tokio::fs::write(path, data).await
+        }.boxed()
+    }
+
+    fn get<'a>(&'a self, key: &'a str) -> BoxFuture<'a, StorageResult<Option<Vec<u8>>>> {
+        async move {
+            match tokio::fs::read(self.path(key)).await {
+                Ok(data) => Ok(Some(data)),
+                Err(e) if e.kind() == ErrorKind::NotFound => Ok(None),
+                Err(e) => Err(e),
+            }
+        }.boxed()
+    }
+
+    fn delete<'a>(&'a self, key: &'a str) -> BoxFuture<'a, StorageResult<()>> {
+        async move {
+            match tokio::fs::remove_file(self.path(key)).await {
+                Ok(()) => Ok(()),
+                Err(e) if e.kind() == ErrorKind::NotFound => Ok(()),
+                Err(e) => Err(e),
+            }
+        }.boxed()
+    }
+}
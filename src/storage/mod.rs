@@ -0,0 +1,30 @@
+mod local;
+
+pub use local::LocalStorage;
+
+use futures::future::BoxFuture;
+use std::sync::Arc;
+
+pub type StorageResult<T> = Result<T, std::io::Error>;
+
+/// A place to keep the contents of uploaded files.
+///
+/// Files are identified by a key which is chosen by the caller. Keys are made
+/// of one or more segments separated by slashes, like "avatar/abc-64.png".
+/// Each segment is non-empty and only contains base64url characters and dots,
+/// and is never "." or "..". This means that keys can safely be used as
+/// relative paths or object names by an implementation. An implementation that
+/// maps keys to paths must create any intermediate directories.
+pub trait Storage: Send + Sync {
+    /// Store a file, replacing any file with the same key.
+    fn put<'a>(&'a self, key: &'a str, data: Vec<u8>) -> BoxFuture<'a, StorageResult<()>>;
+
+    /// Get the contents of a file. Returns None if there is no file with the
+    /// key.
+    fn get<'a>(&'a self, key: &'a str) -> BoxFuture<'a, StorageResult<Option<Vec<u8>>>>;
+
+    /// Remove a file. Removing a file that doesn't exist is not an error.
+    fn delete<'a>(&'a self, key: &'a str) -> BoxFuture<'a, StorageResult<()>>;
+}
+
+pub type StorageRef = Arc<dyn Storage>;
@@ -0,0 +1,103 @@
+use log::error;
+use std::time::{Duration, SystemTime};
+use crate::avatar;
+use crate::error::Error;
+use crate::database as db;
+use deadpool_postgres::Pool;
+use crate::storage::StorageRef;
+use crate::proxy::PictureCacheRef;
+
+/// Remove the attachments that were never added to a message and then remove
+/// the files of any deleted attachments from storage.
+async fn sweep_attachments(pool: &Pool, storage: &StorageRef, unattached_expiry: Duration) -> Result<(), Error> {
+    db::delete_unattached(pool.clone(), SystemTime::now() - unattached_expiry).await?;
+
+    let mut after = String::new();
+
+    loop {
+        let storage_keys = db::deleted_files(pool.clone(), &after).await?;
+        let mut removed = Vec::with_capacity(storage_keys.len());
+        for key in storage_keys.iter() {
+            match storage.delete(key).await {
+                Ok(()) => removed.push(key.clone()),
+                Err(e) => error!("Failed to remove file {}: {}", key, e),
+            }
+        }
+        db::remove_deleted_files(pool.clone(), &removed).await?;
+
+        match storage_keys.last() {
+            Some(last) if storage_keys.len() == db::DELETED_FILE_BATCH => after = last.clone(),
+            _ => return Ok(()),
+        }
+    }
+}
+
+/// Remove the uploaded pictures that aren't used by any user or group. This
+/// includes pictures that were replaced.
+async fn sweep_avatars(pool: &Pool, storage: &StorageRef, expiry: Duration) -> Result<(), Error> {
+    // The uploads are counted for the quota so they must be kept for at least
+    // the quota period.
+    let before = SystemTime::now() - expiry.max(db::AVATAR_UPLOAD_PERIOD);
+    let mut after = String::new();
+
+    loop {
+        let avatar_keys = db::unused_avatars(pool.clone(), before, avatar::AVATAR_SIZES, &after).await?;
+        let mut removed = Vec::with_capacity(avatar_keys.len());
+        'keys: for key in avatar_keys.iter() {
+            for &size in avatar::AVATAR_SIZES.iter() {
+                if let Err(e) = storage.delete(&avatar::storage_key(key, size)).await {
+                    error!("Failed to remove picture {}: {}", key, e);
+                    continue 'keys;
+                }
+            }
+            removed.push(key.clone());
+        }
+        db::remove_avatars(pool.clone(), &removed).await?;
+
+        match avatar_keys.last() {
+            Some(last) if avatar_keys.len() == db::UNUSED_AVATAR_BATCH => after = last.clone(),
+            _ => return Ok(()),
+        }
+    }
+}
+
+/// Remove the cached pictures that have expired and are no longer used by a
+/// user, group or link preview. Pictures that are still in use are kept so that
+/// the proxy can fall back to them.
+async fn sweep_picture_cache(pool: &Pool, cache: &PictureCacheRef) -> Result<(), Error> {
+    for picture in cache.expired().await? {
+        let in_use = match &picture.url {
+            Some(url) => db::picture_in_use(pool.clone(), url).await?,
+            None => false,
+        };
+        if !in_use {
+            cache.remove(picture).await?;
+        }
+    }
+    Ok(())
+}
+
+/// Periodically remove files that are no longer needed. This runs for as long
+/// as the server does.
+pub async fn run(
+    pool: Pool,
+    storage: StorageRef,
+    picture_cache: PictureCacheRef,
+    unattached_expiry: Duration,
+    sweep_interval: Duration,
+) {
+    let mut interval = tokio::time::interval(sweep_interval);
+
+    loop {
+        interval.tick().await;
+        if let Err(e) = sweep_attachments(&pool, &storage, unattached_expiry).await {
+            error!("Sweep failed: {}", e);
+        }
+        if let Err(e) = sweep_avatars(&pool, &storage, unattached_expiry).await {
+            error!("Picture sweep failed: {}", e);
+        }
+        if let Err(e) = sweep_picture_cache(&pool, &picture_cache).await {
+            error!("Picture cache sweep failed: {}", e);
+        }
+    }
+}
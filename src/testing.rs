@@ -0,0 +1,65 @@
+//! Helpers for tests that fetch from a local server.
+
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use warp::Filter;
+use crate::config::FetchConfig;
+use crate::fetch::Fetcher;
+use crate::utils::generate_random_base64url;
+
+/// A fetcher that is allowed to connect to the local server.
+pub fn fetcher() -> Fetcher {
+    Fetcher::new(&FetchConfig { allow_private_addresses: true, timeout: 2 })
+}
+
+/// Serve the routes on a random port of 127.0.0.1.
+pub fn serve<F>(routes: F) -> SocketAddr
+    where F: Filter + Clone + Send + Sync + 'static, F::Extract: warp::Reply
+{
+    serve_at([127, 0, 0, 1], routes)
+}
+
+/// Serve the routes on a random port of a loopback address.
+pub fn serve_at<F>(ip: [u8; 4], routes: F) -> SocketAddr
+    where F: Filter + Clone + Send + Sync + 'static, F::Extract: warp::Reply
+{
+    let (addr, server) = warp::serve(routes).bind_ephemeral((ip, 0));
+    tokio::spawn(server);
+    addr
+}
+
+/// Counts the requests that reach a route.
+#[derive(Clone, Default)]
+pub struct Hits(Arc<AtomicUsize>);
+
+impl Hits {
+    pub fn filter(&self) -> impl Filter<Extract = (), Error = std::convert::Infallible> + Clone {
+        let hits = self.0.clone();
+        warp::any().map(move || { hits.fetch_add(1, Ordering::SeqCst); }).untuple_one()
+    }
+
+    pub fn count(&self) -> usize {
+        self.0.load(Ordering::SeqCst)
+    }
+}
+
+/// A directory that is removed when this is dropped. It isn't created.
+pub struct TempDir(PathBuf);
+
+impl TempDir {
+    pub fn new() -> Self {
+        Self(std::env::temp_dir().join(format!("chat-test-{}", generate_random_base64url(12))))
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.0
+    }
+}
+
+impl Drop for TempDir {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_dir_all(&self.0);
+    }
+}
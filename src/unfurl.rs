@@ -0,0 +1,411 @@
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::Semaphore;
+use crate::database as db;
+use crate::fetch::Fetcher;
+
+/// Only the start of a page is read. The meta tags are in the head so this is
+/// usually more than enough.
+const MAX_PAGE_SIZE: usize = 256 * 1024;
+
+const MAX_TITLE_LENGTH: usize = 256;
+const MAX_DESCRIPTION_LENGTH: usize = 1024;
+
+/// The maximum number of pages that are fetched at the same time. Any other
+/// messages wait for their turn.
+const MAX_CONCURRENT_UNFURLS: usize = 8;
+
+/// A preview of a URL that was fetched within this time is reused for other
+/// messages instead of fetching the page again.
+pub const PREVIEW_REUSE_TIME: Duration = Duration::from_secs(60 * 60);
+
+/// Find the first web link in the content of a message. Links that are too long
+/// to store are skipped.
+pub fn find_url(content: &str) -> Option<String> {
+    for (index, _) in content.match_indices("http") {
+        // The link must be at the start of a word or in parentheses (as in a
+        // Markdown link).
+        let before = content[..index].chars().next_back();
+        if before.is_some_and(|ch| !ch.is_whitespace() && ch != '(' && ch != '<') {
+            continue;
+        }
+
+        let rest = &content[index..];
+        let end = rest.find(|ch: char| ch.is_whitespace() || matches!(ch, ')' | '>' | '"' | '\'' | '`'))
+            .unwrap_or(rest.len());
+        let candidate = rest[..end].trim_end_matches(['.', ',', ';', ':', '!', '?']);
+
+        if let Ok(url) = reqwest::Url::parse(candidate) {
+            if (url.scheme() == "http" || url.scheme() == "https") && url.host_str().is_some() {
+                let url = url.into_string();
+                if db::valid_url(&url) {
+                    return Some(url);
+                }
+            }
+        }
+    }
+    None
+}
+
+fn decode_entities(text: &str) -> String {
+    let mut decoded = String::with_capacity(text.len());
+    let mut rest = text;
+    while let Some(start) = rest.find('&') {
+        decoded.push_str(&rest[..start]);
+        rest = &rest[start..];
+        let end = match rest.find(';') {
+            Some(end) if end <= 10 => end,
+            _ => {
+                decoded.push('&');
+                rest = &rest[1..];
+                continue;
+            }
+        };
+        let ch = match &rest[1..end] {
+            "amp" => Some('&'),
+            "lt" => Some('<'),
+            "gt" => Some('>'),
+            "quot" => Some('"'),
+            "apos" => Some('\''),
+            "nbsp" => Some(' '),
+            entity => match entity.strip_prefix('#') {
+                Some(number) => match number.strip_prefix('x').or_else(|| number.strip_prefix('X')) {
+                    Some(hex) => u32::from_str_radix(hex, 16).ok(),
+                    None => number.parse().ok(),
+                }.and_then(std::char::from_u32),
+                None => None,
+            }
+        };
+        match ch {
+            Some(ch) => {
+                decoded.push(ch);
+                rest = &rest[end + 1..];
+            },
+            None => {
+                decoded.push('&');
+                rest = &rest[1..];
+            }
+        }
+    }
+    decoded.push_str(rest);
+    decoded
+}
+
+/// Decode entities, collapse whitespace and limit the length of text from a
+/// page.
+fn clean_text(text: &str, max_chars: usize) -> Option<String> {
+    let text = decode_entities(text);
+    let text = text.split_whitespace().collect::<Vec<_>>().join(" ");
+    if text.is_empty() {
+        return None;
+    }
+    match text.char_indices().nth(max_chars) {
+        Some((index, _)) => Some(text[..index].to_owned()),
+        None => Some(text),
+    }
+}
+
+/// Parse the attributes of a tag. The names are converted to lowercase.
+fn parse_attributes(tag: &str) -> Vec<(String, String)> {
+    let mut attributes = Vec::new();
+    let mut rest = tag;
+    loop {
+        rest = rest.trim_start_matches(|ch: char| ch.is_whitespace() || ch == '/');
+        if rest.is_empty() {
+            return attributes;
+        }
+
+        let name_end = rest.find(|ch: char| ch.is_whitespace() || ch == '=' || ch == '/')
+            .unwrap_or(rest.len());
+        let name = rest[..name_end].to_ascii_lowercase();
+        rest = rest[name_end..].trim_start();
+
+        let value = match rest.strip_prefix('=') {
+            Some(after) => {
+                let after = after.trim_start();
+                match after.chars().next() {
+                    Some(quote) if quote == '"' || quote == '\'' => {
+                        let end = after[1..].find(quote).map_or(after.len(), |end| end + 1);
+                        rest = after.get(end + 1..).unwrap_or("");
+                        &after[1..end]
+                    },
+                    _ => {
+                        let end = after.find(char::is_whitespace).unwrap_or(after.len());
+                        rest = &after[end..];
+                        &after[..end]
+                    }
+                }
+            },
+            None => "",
+        };
+
+        if name.is_empty() {
+            // Skip a stray character so that the loop always makes progress.
+            rest = rest.get(1..).unwrap_or("");
+        } else {
+            attributes.push((name, value.to_owned()));
+        }
+    }
+}
+
+#[derive(Default)]
+struct PageInfo {
+    og_title: Option<String>,
+    og_description: Option<String>,
+    og_image: Option<String>,
+    title: Option<String>,
+    description: Option<String>,
+}
+
+/// Find the Open Graph meta tags and the title in the head of a page.
+fn parse_page(html: &str) -> PageInfo {
+    let mut info = PageInfo::default();
+    // Only ASCII characters are changed so the indices are the same.
+    let lower = html.to_ascii_lowercase();
+    let head_end = ["</head", "<body"].iter()
+        .filter_map(|tag| lower.find(tag))
+        .min()
+        .unwrap_or(html.len());
+
+    let mut index = 0;
+    while index < head_end {
+        let start = match lower[index..head_end].find('<') {
+            Some(start) => index + start,
+            None => break,
+        };
+        let tag_end = match lower[start..].find('>') {
+            Some(end) => start + end,
+            None => break,
+        };
+        let tag = &lower[start + 1..tag_end];
+
+        if tag.starts_with("title") && info.title.is_none() {
+            let text_start = tag_end + 1;
+            let text_end = lower[text_start..].find("</title").map_or(head_end, |end| text_start + end);
+            info.title = Some(html[text_start..text_end.max(text_start)].to_owned());
+            index = text_end.max(text_start);
+            continue;
+        }
+
+        if tag.starts_with("meta") && tag[4..].starts_with(|ch: char| ch.is_whitespace()) {
+            let attributes = parse_attributes(&html[start + 5..tag_end]);
+            let get = |name: &str| attributes.iter()
+                .find(|(key, _)| key == name)
+                .map(|(_, value)| value.as_str());
+            let key = get("property").or_else(|| get("name")).map(str::to_ascii_lowercase);
+            if let (Some(key), Some(content)) = (key, get("content")) {
+                let field = match key.as_str() {
+                    "og:title" => &mut info.og_title,
+                    "og:description" => &mut info.og_description,
+                    "og:image" | "og:image:url" | "og:image:secure_url" => &mut info.og_image,
+                    "description" => &mut info.description,
+                    _ => {
+                        index = tag_end + 1;
+                        continue;
+                    }
+                };
+                if field.is_none() {
+                    *field = Some(content.to_owned());
+                }
+            }
+        }
+
+        index = tag_end + 1;
+    }
+
+    info
+}
+
+/// Resolve the image URL relative to the page. Only web URLs are allowed.
+fn image_url(page_url: &str, image: &str) -> Option<String> {
+    let image = decode_entities(image.trim());
+    let url = reqwest::Url::parse(page_url).ok()?.join(&image).ok()?;
+    if url.scheme() != "http" && url.scheme() != "https" {
+        return None;
+    }
+    let url = url.into_string();
+    if db::valid_url(&url) {
+        Some(url)
+    } else {
+        None
+    }
+}
+
+/// Create a preview of a page from its Open Graph meta tags, falling back to
+/// the title and the description meta tag.
+fn create_preview(url: &str, html: &str) -> Option<db::LinkPreview> {
+    let info = parse_page(html);
+    let title = info.og_title.as_deref()
+        .and_then(|title| clean_text(title, MAX_TITLE_LENGTH))
+        .or_else(|| info.title.as_deref().and_then(|title| clean_text(title, MAX_TITLE_LENGTH)))?;
+    let description = info.og_description.as_deref()
+        .or(info.description.as_deref())
+        .and_then(|description| clean_text(description, MAX_DESCRIPTION_LENGTH));
+    let image = info.og_image.as_deref().and_then(|image| image_url(url, image));
+    Some(db::LinkPreview { url: url.to_owned(), title, description, image })
+}
+
+/// Fetches pages to create link previews, limiting the number of pages that are
+/// fetched at once.
+#[derive(Clone)]
+pub struct Unfurler {
+    fetcher: Fetcher,
+    permits: Arc<Semaphore>,
+}
+
+impl Unfurler {
+    pub fn new(fetcher: Fetcher) -> Self {
+        Self { fetcher, permits: Arc::new(Semaphore::new(MAX_CONCURRENT_UNFURLS)) }
+    }
+
+    /// Fetch a page and create a preview of it.
+    ///
+    /// Returns None if the page couldn't be fetched, isn't HTML or doesn't
+    /// have a title.
+    pub async fn unfurl(&self, url: &str) -> Option<db::LinkPreview> {
+        let _permit = self.permits.acquire().await;
+        let accept = |content_type: &str| content_type == "text/html" || content_type == "application/xhtml+xml";
+        let page = self.fetcher.get_prefix(url, accept, MAX_PAGE_SIZE).await.ok()?;
+        create_preview(url, &String::from_utf8_lossy(&page.data))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use warp::Filter;
+    use crate::config::FetchConfig;
+    use crate::testing::{self, Hits};
+
+    fn html(body: String) -> impl warp::Reply {
+        warp::reply::with_header(body, "Content-Type", "text/html; charset=utf-8")
+    }
+
+    #[test]
+    fn find_urls() {
+        assert_eq!(find_url("see https://example.com/a."), Some("https://example.com/a".to_owned()));
+        assert_eq!(find_url("[link](http://example.com/b) and https://c.com"), Some("http://example.com/b".to_owned()));
+        assert_eq!(find_url("<https://example.com/c>"), Some("https://example.com/c".to_owned()));
+        assert_eq!(find_url("nohttps://example.com"), None);
+        assert_eq!(find_url("http: not a link, https://"), None);
+        assert_eq!(find_url("ftp://example.com"), None);
+        let long = format!("https://example.com/{}", "a".repeat(db::MAX_URL_LENGTH));
+        assert_eq!(find_url(&long), None);
+    }
+
+    #[test]
+    fn decode() {
+        assert_eq!(decode_entities("a &amp; b &lt;c&gt; &quot;d&quot; &#39;e&#x27;"), "a & b <c> \"d\" 'e'");
+        assert_eq!(decode_entities("&#128512; &nbsp;"), "\u{1F600}  ");
+        assert_eq!(decode_entities("&unknown; & &; &#xZZ; &#99999999;"), "&unknown; & &; &#xZZ; &#99999999;");
+        assert_eq!(decode_entities("AT&T; &amp"), "AT&T; &amp");
+    }
+
+    #[test]
+    fn attributes() {
+        let pairs = parse_attributes;
+        let owned = |pairs: &[(&str, &str)]| pairs.iter()
+            .map(|(k, v)| (k.to_string(), v.to_string()))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            pairs(r#"Property="og:title" content='It&#39;s "quoted"' data-x=bare checked /"#),
+            owned(&[("property", "og:title"), ("content", "It&#39;s \"quoted\""), ("data-x", "bare"), ("checked", "")])
+        );
+        assert_eq!(pairs(r#"name = "description" content="unclosed"#), owned(&[("name", "description"), ("content", "unclosed")]));
+        assert_eq!(pairs("= ="), owned(&[]));
+    }
+
+    #[test]
+    fn parse() {
+        let info = parse_page(r#"
+            <html><head>
+            <TITLE> The &amp; title </TITLE>
+            <meta property="og:title" content="OG title">
+            <meta property="og:title" content="Second OG title">
+            <meta name="description" content="Plain description">
+            <meta property="og:description" content="OG description">
+            <meta property="og:image:secure_url" content="/image.png">
+            <meta charset="utf-8">
+            </head><body>
+            <meta property="og:image" content="/body.png">
+            </body></html>
+        "#);
+        assert_eq!(info.title.as_deref(), Some(" The &amp; title "));
+        assert_eq!(info.og_title.as_deref(), Some("OG title"));
+        assert_eq!(info.og_description.as_deref(), Some("OG description"));
+        assert_eq!(info.description.as_deref(), Some("Plain description"));
+        assert_eq!(info.og_image.as_deref(), Some("/image.png"));
+
+        let info = parse_page("<title>Unclosed");
+        assert_eq!(info.title.as_deref(), Some("Unclosed"));
+        let info = parse_page("<body><title>Not in the head</title></body>");
+        assert!(info.title.is_none());
+    }
+
+    #[test]
+    fn preview() {
+        let preview = create_preview("https://example.com/page", r#"
+            <title>Title</title>
+            <meta name="description" content="  Some
+                description  ">
+            <meta property="og:image" content="javascript:alert(1)">
+        "#).unwrap();
+        assert_eq!(preview.title, "Title");
+        assert_eq!(preview.description.as_deref(), Some("Some description"));
+        assert!(preview.image.is_none());
+
+        let preview = create_preview("https://example.com/a/page", r#"
+            <meta property="og:title" content="OG &amp; title">
+            <meta property="og:image" content="../image.png">
+        "#).unwrap();
+        assert_eq!(preview.title, "OG & title");
+        assert_eq!(preview.image.as_deref(), Some("https://example.com/image.png"));
+
+        assert!(create_preview("https://example.com", "<p>No title</p>").is_none());
+
+        let long = format!("<title>{}</title>", "x".repeat(MAX_TITLE_LENGTH + 10));
+        assert_eq!(create_preview("https://example.com", &long).unwrap().title.len(), MAX_TITLE_LENGTH);
+    }
+
+    #[tokio::test]
+    async fn unfurl_page() {
+        let page = warp::path("page").map(|| html(r#"
+            <head>
+            <meta property="og:title" content="Page title">
+            <meta property="og:description" content="Page description">
+            <meta property="og:image" content="/image.png">
+            </head>
+        "#.to_owned()));
+        // The head is past the part of the page that is read.
+        let late = warp::path("late").map(|| {
+            html(format!("<!-- {} --><title>Too late</title>", "x".repeat(MAX_PAGE_SIZE)))
+        });
+        // The rest of a large page is ignored.
+        let large = warp::path("large").map(|| {
+            html(format!("<title>Large</title>{}", "x".repeat(MAX_PAGE_SIZE * 2)))
+        });
+        let text = warp::path("text").map(|| "<title>Not HTML</title>");
+        let addr = testing::serve(page.or(late).or(large).or(text));
+        let unfurler = Unfurler::new(testing::fetcher());
+
+        let preview = unfurler.unfurl(&format!("http://{}/page", addr)).await.unwrap();
+        assert_eq!(preview.title, "Page title");
+        assert_eq!(preview.description.as_deref(), Some("Page description"));
+        assert_eq!(preview.image, Some(format!("http://{}/image.png", addr)));
+
+        assert!(unfurler.unfurl(&format!("http://{}/late", addr)).await.is_none());
+        assert_eq!(unfurler.unfurl(&format!("http://{}/large", addr)).await.unwrap().title, "Large");
+        assert!(unfurler.unfurl(&format!("http://{}/text", addr)).await.is_none());
+    }
+
+    #[tokio::test]
+    async fn refuses_private_addresses() {
+        let hits = Hits::default();
+        let addr = testing::serve(hits.filter().map(|| html("<title>Private</title>".to_owned())));
+        let unfurler = Unfurler::new(Fetcher::new(&FetchConfig::default()));
+
+        assert!(unfurler.unfurl(&format!("http://{}/", addr)).await.is_none());
+        assert!(unfurler.unfurl(&format!("http://localhost:{}/", addr.port())).await.is_none());
+        assert_eq!(hits.count(), 0);
+    }
+}
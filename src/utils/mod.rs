@@ -1,6 +1,8 @@
 mod warp;
 mod random;
+mod time;
 
 // Maybe I shouldn't name it warp...
 pub use crate::utils::warp::*;
 pub use random::*;
+pub use time::*;